@@ -1,17 +1,20 @@
 use std::error::Error;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use futures::future;
 use futures::stream::StreamExt;
 use futures_signals::signal::Mutable;
 
-use super::sleep;
-use crate::fabric_client::FabricConnState;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{self, KeyPair};
+
+use livecore_protocol as proto;
+use proto::{HANDSHAKE_CHALLENGE_WRAP, CHALLENGE_RESPONSE_LEN};
 
-use wasm_bindgen::convert::IntoWasmAbi;
-use wasm_bindgen::JsValue;
+use fabric_client::Backoff;
 
-use tokio::sync::mpsc::{Sender, channel};
+use super::sleep;
+use crate::fabric_client::FabricConnState;
 
 pub struct FabricClient {
     shared: Arc<FabricClientShared>,
@@ -23,6 +26,15 @@ struct FabricClientShared {
     //tmp_callback: Mutex<Option<js_sys::Function>>,
 }
 
+/// State kept across connections to the orchestrator, so that a new
+/// connection may resume the previous session.
+struct Session {
+    rand: SystemRandom,
+    keypair: signature::EcdsaKeyPair,
+    /// From the last `ServerHandshake`, sent in the next `ClientHandshake`.
+    resumption_token: Option<String>,
+}
+
 impl FabricClient {
     pub fn new(url: String) -> Self {
         let shared = Arc::new(FabricClientShared {
@@ -33,17 +45,34 @@ impl FabricClient {
 
         let shared_f = shared.clone();
         wasm_bindgen_futures::spawn_local(async move {
+            let rand = SystemRandom::new();
+            let keypair = {
+                let algo = &signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+                let pkcs8_bytes = signature::EcdsaKeyPair::generate_pkcs8(algo, &rand).unwrap();
+                signature::EcdsaKeyPair::from_pkcs8(algo, pkcs8_bytes.as_ref()).unwrap()
+            };
+            let mut session = Session {
+                rand,
+                keypair,
+                resumption_token: None,
+            };
+
+            let mut backoff = Backoff::new(
+                Duration::from_millis(500),
+                Duration::from_secs(30),
+            );
             loop {
                 log::info!("yay websocket loop");
-                // TODO backoff logic
-                match do_fabric_loop(&shared_f, &url).await {
+                match do_fabric_loop(&shared_f, &url, &mut session, &mut backoff).await {
                     Ok(()) => {
                         log::error!("do_fabric_loop should never exit successfully");
                         panic!();
                     }
                     Err(err) => {
-                        log::error!("fabric loop error: {}", err);
-                        sleep(1000).await;
+                        shared_f.state.set(FabricConnState::Reconnecting);
+                        let delay = backoff.next_delay();
+                        log::error!("fabric loop error: {}, retrying in {:?}", err, delay);
+                        sleep(delay.as_millis() as i32).await;
                     }
                 }
             }
@@ -57,12 +86,26 @@ impl FabricClient {
     //}
 }
 
-async fn do_fabric_loop(shared: &Arc<FabricClientShared>, url: &str) -> Result<(), Box<dyn Error>> {
+#[derive(Debug)]
+struct FabricError(&'static str);
+impl std::fmt::Display for FabricError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl Error for FabricError {}
+
+async fn do_fabric_loop(
+    shared: &Arc<FabricClientShared>,
+    url: &str,
+    session: &mut Session,
+    backoff: &mut Backoff,
+) -> Result<(), Box<dyn Error>> {
     // TODO Error handling
     let mut ws = super::websocket::WebSocket::connect(url.to_string()).unwrap();
     ws.wait_open().await?;
 
-    let messages = ws
+    let mut messages = ws
         .messages()
         .unwrap()
         .take_until(ws.wait_close())
@@ -72,42 +115,86 @@ async fn do_fabric_loop(shared: &Arc<FabricClientShared>, url: &str) -> Result<(
             // TODO: less copy, use blob?
             let vec = array.to_vec();
 
-            match livecore_protocol::deserialize(&vec) {
+            match proto::OrchServerMsg::deserialize(&vec) {
                 Ok(msg) => Some(msg),
                 Err(err) => {
                     log::error!("failed to deserialize message!! {}", err);
                     None
                 }
             }
-        });
-
-    messages
-        .for_each(|msg| {
-            match msg {
-                livecore_protocol::Message::StreamData { data } => {
-                    //let cb_guard = shared.tmp_callback.lock().unwrap();
-                    //let cb = cb_guard.as_ref().unwrap();
-
-                    let data_slice = &data[..];
-                    let value: js_sys::Uint8Array = data_slice.into();
-
-                    let this = JsValue::null();
-                    log::info!("before callback call");
-                    match cb.call1(&this, &value.into()) {
-                        Ok(val) => log::info!("ret: {:?}", val),
-                        Err(val) => log::info!("fail: {:?}", val),
-                    }
-                    log::info!("after callback call");
-                }
-            }
-            log::info!("yay msg");
-            future::ready(())
         })
-        .await;
-
-    ws.wait_close().await;
+        .boxed_local();
+
+    let mut challenge = [0; 32];
+    session.rand.fill(&mut challenge).unwrap();
+
+    // Stays on JSON, the only encoding the browser client supports.
+    let handshake: proto::OrchClientMsg = proto::ClientHandshake {
+        version: proto::ProtocolVersion::MIN_SUPPORTED,
+        max_version: Some(proto::ProtocolVersion::CURRENT),
+        encodings: vec![],
+        node_classes: vec![],
+        peer_connection_capabilities: vec![proto::PeerConnectionType::WebsocketClient],
+        token: None,
+        pubkey: session.keypair.public_key().as_ref().to_owned(),
+        challenge: proto::Challenge { challenge },
+        resumption_token: session.resumption_token.clone(),
+    }.into();
+    ws.send(&handshake.serialize()?)?;
+
+    let msg = match messages.next().await {
+        Some(proto::OrchServerMsg::ServerHandshake(msg)) => msg,
+        Some(proto::OrchServerMsg::HandshakeRejected(msg)) => {
+            log::error!("rejected by orchestrator: {:?}", msg);
+            return Err(FabricError("handshake rejected").into());
+        },
+        Some(_) => return Err(FabricError("unexpected message during handshake").into()),
+        None => return Err(FabricError("connection closed during handshake").into()),
+    };
+    if !msg.version.is_supported() {
+        return Err(FabricError("unsupported protocol version").into());
+    }
 
-    log::info!("yay");
+    // The orchestrator must prove its identity by signing our challenge.
+    let wrap = HANDSHAKE_CHALLENGE_WRAP.as_bytes();
+    let response = &msg.challenge_response;
+    let data = &response.challenge_response;
+    if data.len() != CHALLENGE_RESPONSE_LEN || !data.starts_with(wrap) || !data.ends_with(wrap)
+        || &data[wrap.len()..(wrap.len() + 32)] != &challenge[..]
+    {
+        return Err(FabricError("challenge response does not match challenge").into());
+    }
+    let orch_pubkey = signature::UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, &msg.pubkey);
+    orch_pubkey.verify(data, &response.signature)
+        .map_err(|_| FabricError("invalid challenge response signature"))?;
+
+    let challenge_response = {
+        let mut nonce = [0; 32];
+        session.rand.fill(&mut nonce).unwrap();
+
+        let mut challenge_response = Vec::new();
+        challenge_response.extend(wrap);
+        challenge_response.extend(&msg.challenge.challenge);
+        challenge_response.extend(&nonce);
+        challenge_response.extend(wrap);
+
+        let signature = session.keypair.sign(&session.rand, &challenge_response).unwrap();
+        proto::ChallengeResponse {
+            challenge_response,
+            signature: signature.as_ref().to_owned(),
+        }
+    };
+    let finish: proto::OrchClientMsg = proto::ClientHandshakeFinish { challenge_response }.into();
+    ws.send(&finish.serialize()?)?;
+
+    session.resumption_token = msg.resumption_token;
+    shared.state.set(FabricConnState::Connected);
+    backoff.reset();
+    log::info!("connected to fabric as {:?}", msg.client_uuid);
+
+    while let Some(msg) = messages.next().await {
+        log::info!("yay msg {:?}", msg);
+    }
 
-    Ok(())
+    Err(FabricError("connection closed").into())
 }
@@ -111,6 +111,10 @@ impl WebSocket {
         WebSocket::wrap(|| NWS::new(&url))
     }
 
+    pub fn send(&self, data: &[u8]) -> Result<(), WSError> {
+        self.inner.send_with_u8_array(data).map_err(|_| WSError {})
+    }
+
     pub fn messages(&mut self) -> Option<impl Stream<Item = js_sys::ArrayBuffer>> {
         self.messages.take()
     }
@@ -1,4 +1,5 @@
 use std::pin::Pin;
+use std::time::Duration;
 
 use clap::Clap;
 use futures::{Stream, StreamExt, Sink, SinkExt};
@@ -7,6 +8,8 @@ use tokio::net::{TcpListener, UnixListener};
 
 use livecore_protocol as proto;
 
+use fabric_client::{FabricEvent, HandshakeError};
+
 #[derive(Clap, Debug, PartialEq)]
 enum FabricProtocol {
     Websocket,
@@ -42,14 +45,52 @@ struct Opts {
 
     #[clap(long = "fabric-protocol", default_value = "websocket", arg_enum)]
     fabric_protocol: FabricProtocol,
+
+    /// Keep the orchestrator connection on JSON instead of offering a binary
+    /// encoding. Useful for debugging.
+    #[clap(long)]
+    json: bool,
+}
+
+/// Exit codes for handshake failures that retrying will not fix.
+const EXIT_INVALID_TOKEN: i32 = 2;
+const EXIT_UNSUPPORTED_NODE_CLASS: i32 = 3;
+const EXIT_VERSION_MISMATCH: i32 = 4;
+const EXIT_ORCHESTRATOR_VERIFICATION: i32 = 5;
+const EXIT_PROTOCOL_ERROR: i32 = 6;
+
+/// Decides what to do after a failed handshake.
+///
+/// Returns `Ok` with the delay requested by the orchestrator if we should
+/// reconnect, or `Err` with the exit code if we should give up.
+fn handshake_failure_action(error: &HandshakeError) -> Result<Option<Duration>, i32> {
+    use proto::HandshakeRejectReason as R;
+    match error {
+        HandshakeError::Rejected { retry_after: Some(delay), .. } => Ok(Some(*delay)),
+        HandshakeError::Rejected { reason: R::Unavailable, .. } => Ok(None),
+        HandshakeError::Rejected { reason: R::InvalidToken, .. } => Err(EXIT_INVALID_TOKEN),
+        HandshakeError::Rejected { reason: R::UnsupportedNodeClass, .. } => Err(EXIT_UNSUPPORTED_NODE_CLASS),
+        HandshakeError::Rejected { reason: R::VersionMismatch, .. } |
+        HandshakeError::UnsupportedVersion(_) => Err(EXIT_VERSION_MISMATCH),
+        // The orchestrator failed to prove its identity.
+        HandshakeError::InvalidResponseLength { .. } |
+        HandshakeError::ChallengeMismatch |
+        HandshakeError::InvalidSignature => Err(EXIT_ORCHESTRATOR_VERIFICATION),
+        // The orchestrator picked an encoding we did not offer, it will keep
+        // doing so.
+        HandshakeError::UnsupportedEncoding(_) => Err(EXIT_PROTOCOL_ERROR),
+        // A message out of order, a new connection may go better.
+        HandshakeError::Unexpected => Ok(None),
+    }
 }
 
 type PacketSink = Pin<Box<dyn Sink<Vec<u8>, Error = ()> + Send>>;
 type PacketSource = Pin<Box<dyn Stream<Item = Result<Vec<u8>, ()>> + Send>>;
 
-async fn connect_ws(url: String) -> (PacketSource, PacketSink) {
+async fn connect_ws(url: String) -> anyhow::Result<(PacketSource, PacketSink)> {
     log::info!("connecting to WS fabric {}", url);
-    let ws = fabric_client::platform::connect(url).await.unwrap();
+    let ws = fabric_client::platform::connect(url).await
+        .map_err(|()| anyhow::anyhow!("websocket connection failed"))?;
 
     let source = ws.source.map(|v| match v {
         Ok(msg) => Ok(msg.bytes().to_owned()),
@@ -60,16 +101,16 @@ async fn connect_ws(url: String) -> (PacketSource, PacketSink) {
         .sink_map_err(|_err| ())
         .with(|v: Vec<u8>| async { Ok(v.into()) });
 
-    (Box::pin(source), Box::pin(sink))
+    Ok((Box::pin(source), Box::pin(sink)))
 }
 
-async fn connect_ipc(path: String) -> (PacketSource, PacketSink) {
+async fn connect_ipc(path: String) -> anyhow::Result<(PacketSource, PacketSink)> {
     use tokio::net::UnixStream;
     use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
     log::info!("connecting to IPC fabric {}", path);
 
-    let stream = UnixStream::connect(path).await.unwrap();
+    let stream = UnixStream::connect(path).await?;
     let framed = Framed::new(stream, LengthDelimitedCodec::new());
 
     let (sink, source) = framed.split();
@@ -83,7 +124,14 @@ async fn connect_ipc(path: String) -> (PacketSource, PacketSink) {
         .sink_map_err(|_err| ())
         .with(|v: Vec<u8>| async { Ok(v.into()) });
 
-    (Box::pin(source), Box::pin(sink))
+    Ok((Box::pin(source), Box::pin(sink)))
+}
+
+async fn connect(opts: &Opts) -> anyhow::Result<(PacketSource, PacketSink)> {
+    match opts.fabric_protocol {
+        FabricProtocol::Websocket => connect_ws(opts.fabric_url.clone()).await,
+        FabricProtocol::IPC => connect_ipc(opts.fabric_url.clone()).await,
+    }
 }
 
 #[tokio::main]
@@ -92,21 +140,16 @@ async fn main() {
 
     let opts: Opts = Opts::parse();
 
-    let (mut transport_receiver, mut transport_sender) = match opts.fabric_protocol {
-        FabricProtocol::Websocket => connect_ws(opts.fabric_url.clone()).await,
-        FabricProtocol::IPC => connect_ipc(opts.fabric_url.clone()).await,
-    };
-
     use fabric_client::platform::peer_connection_manager_impl::NativePeerConnectionManagerBuilder;
     let mut peer_connector_builder = NativePeerConnectionManagerBuilder::new();
 
-    if let Some(addr) = opts.ws_peer_bind {
+    if let Some(addr) = &opts.ws_peer_bind {
         log::info!("binding to peer WS addr {}", addr);
-        let listener = TcpListener::bind(addr).await.unwrap();
+        let listener = TcpListener::bind(addr.as_str()).await.unwrap();
         peer_connector_builder = peer_connector_builder.with_ws_listener(listener);
     }
 
-    if let Some(addr) = opts.ipc_peer_bind {
+    if let Some(addr) = &opts.ipc_peer_bind {
         log::info!("binding to peer IPC addr {}", addr);
         let listener = UnixListener::bind(addr).unwrap();
         peer_connector_builder = peer_connector_builder.with_ipc_listener(listener);
@@ -114,38 +157,103 @@ async fn main() {
 
     let peer_connector = peer_connector_builder.build();
 
-    //let mut ws = fabric_client::platform::connect(opts.fabric_url.clone()).await.unwrap();
-    let (sender, mut receiver) = fabric_client::OrchPacketSender::new();
-
-    tokio::spawn(async move {
-        while let Some(msg) = receiver.recv().await {
-            transport_sender.send(msg).await.unwrap();
-        }
-    });
-
     let mut fabric_builder = fabric_client::FabricBuilder::new()
-        .with_node_classes(opts.classes);
+        .with_node_classes(opts.classes.clone());
 
-    if let Some(token) = opts.token {
+    if let Some(token) = opts.token.clone() {
         fabric_builder = fabric_builder.with_auth_token(token);
     }
 
-    let fabric = fabric_builder.start(
-        sender,
-        Box::new(peer_connector),
+    if opts.json {
+        fabric_builder = fabric_builder.with_encodings(vec![]);
+    }
+
+    let mut fabric_builder = Some(fabric_builder);
+    let mut peer_connector = Some(peer_connector);
+    let mut fabric: Option<fabric_client::Fabric> = None;
+    let mut events = None;
+
+    let mut backoff = fabric_client::Backoff::new(
+        Duration::from_millis(500),
+        Duration::from_secs(30),
     );
 
-    while let Some(resp) = transport_receiver.next().await {
-        match resp {
-            Ok(val) => {
-                let msg = proto::OrchServerMsg::deserialize(&val).unwrap();
-                fabric.handle_fabric_packet(msg).await;
-                //state.handle_fabric_packet(msg);
+    loop {
+        let (mut transport_receiver, mut transport_sender) = match connect(&opts).await {
+            Ok(transport) => transport,
+            Err(err) => {
+                let delay = backoff.next_delay();
+                log::error!("failed to connect to fabric: {:#}, retrying in {:?}", err, delay);
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+        };
+
+        let (sender, mut receiver) = fabric_client::OrchPacketSender::new();
+
+        tokio::spawn(async move {
+            while let Some(msg) = receiver.recv().await {
+                if transport_sender.send(msg).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        // The fabric is started on the first connection, and reconnected on
+        // the following ones so that it may resume its session.
+        match &fabric {
+            Some(fabric) => fabric.reconnect(sender).await,
+            None => {
+                let started = fabric_builder.take().unwrap().start(
+                    sender,
+                    Box::new(peer_connector.take().unwrap()),
+                );
+                events = Some(started.events());
+                fabric = Some(started);
             },
-            Err(()) => {
-                println!("disconnect");
+        }
+        let fabric = fabric.as_ref().unwrap();
+        let events = events.as_mut().unwrap();
+
+        let mut retry_after = None;
+        loop {
+            tokio::select! {
+                resp = transport_receiver.next() => match resp {
+                    Some(Ok(val)) => match proto::OrchServerMsg::deserialize(&val) {
+                        Ok(msg) => fabric.handle_fabric_packet(msg).await,
+                        Err(err) => {
+                            log::error!("invalid message from fabric: {}", err);
+                            break;
+                        },
+                    },
+                    Some(Err(())) | None => break,
+                },
+                Some(event) = events.next() => {
+                    log::info!("fabric event: {:?}", event);
+                    match event {
+                        FabricEvent::HandshakeComplete { .. } => backoff.reset(),
+                        FabricEvent::OrchestratorTimeout => break,
+                        FabricEvent::HandshakeFailed { error } => {
+                            match handshake_failure_action(&error) {
+                                Ok(delay) => {
+                                    retry_after = delay;
+                                    break;
+                                },
+                                Err(code) => {
+                                    log::error!("giving up: {}", error);
+                                    std::process::exit(code);
+                                },
+                            }
+                        },
+                        _ => (),
+                    }
+                },
             }
         }
+
+        let delay = retry_after.unwrap_or_else(|| backoff.next_delay());
+        log::warn!("disconnected from fabric, reconnecting in {:?}", delay);
+        tokio::time::sleep(delay).await;
     }
 
     //let keypair = {
@@ -1,3 +1,4 @@
+use std::ops::Range;
 use std::sync::{Arc, atomic::{AtomicU8, Ordering}};
 
 /// Specified fragment size for an object.
@@ -14,7 +15,16 @@ impl FragSize {
     /// Calculates of fragments needed to represent something of the given size.
     pub fn needed_fragments(&self, size: usize) -> usize {
         let frag_size = self.size();
-        (size + frag_size - 1) / frag_size
+        // Rounded up without `size + frag_size - 1`, which overflows for
+        // sizes near `usize::MAX`.
+        size / frag_size + if size % frag_size == 0 { 0 } else { 1 }
+    }
+
+    /// Length of fragment `idx` of something of the given size. Only the
+    /// last fragment may be shorter than the fragment size.
+    pub fn fragment_len(&self, size: usize, idx: usize) -> usize {
+        let frag_size = self.size();
+        std::cmp::min(frag_size, size - idx * frag_size)
     }
 }
 
@@ -84,6 +94,9 @@ impl RootBuffer {
         let layout = std::alloc::Layout::from_size_align(
             size, fragment_size.size()).unwrap();
         let backing = unsafe { std::alloc::alloc(layout) };
+        if backing.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
 
         let inner = RootBufferInner {
             fragment_size,
@@ -107,6 +120,9 @@ impl RootBuffer {
         let layout = std::alloc::Layout::from_size_align(
             size, fragment_size.size()).unwrap();
         let backing = unsafe { std::alloc::alloc_zeroed(layout) };
+        if backing.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
 
         let inner = RootBufferInner {
             fragment_size,
@@ -128,6 +144,11 @@ impl RootBuffer {
         self.0.fragment_size.needed_fragments(self.0.size)
     }
 
+    /// Size of the backing allocation.
+    pub fn size(&self) -> usize {
+        self.0.size
+    }
+
     /// Returns the number of sealed fragments.
     pub fn num_sealed(&self) -> usize {
         self.0.claims.iter().map(|v| {
@@ -151,6 +172,40 @@ impl RootBuffer {
         self.0.as_ref_full_unchecked()
     }
 
+    fn is_sealed(&self, fragment: usize) -> bool {
+        // Pairs with the release in `FragmentBuffer::seal`, so the data is
+        // visible to readers on other threads.
+        self.0.claims[fragment].load(Ordering::Acquire) == FRAG_SEALED
+    }
+
+    /// Returns the longest prefix of the buffer made up of sealed fragments.
+    pub fn sealed_prefix<'a>(&'a self) -> &'a [u8] {
+        let sealed = (0..self.num_fragments())
+            .take_while(|fragment| self.is_sealed(*fragment))
+            .count();
+        let len = std::cmp::min(sealed * self.0.fragment_size.size(), self.0.size);
+        // SAFETY: Every fragment covering `..len` was observed as sealed
+        // above, so it is initialized and will never be written again.
+        unsafe { self.0.sealed_slice(0..len) }
+    }
+
+    /// Returns the given byte range if every fragment covering it is sealed.
+    ///
+    /// Panics if the range is out of bounds.
+    pub fn range<'a>(&'a self, range: Range<usize>) -> Option<&'a [u8]> {
+        assert!(range.start <= range.end && range.end <= self.0.size);
+        if range.start < range.end {
+            let frag_size = self.0.fragment_size.size();
+            let mut fragments = (range.start / frag_size)..=((range.end - 1) / frag_size);
+            if !fragments.all(|fragment| self.is_sealed(fragment)) {
+                return None;
+            }
+        }
+        // SAFETY: Every fragment covering `range` was observed as sealed
+        // above, so it is initialized and will never be written again.
+        Some(unsafe { self.0.sealed_slice(range) })
+    }
+
     pub fn claim(&self, fragment: usize) -> Option<FragmentBuffer> {
         debug_assert!(fragment < self.num_fragments());
         let state = &self.0.claims[fragment];
@@ -221,21 +276,31 @@ impl RootBufferInner {
         let num_fragments = self.num_fragments();
         let base_fragment_size = self.fragment_size.size();
         let fragment_size = match fragment {
+            // The last fragment holds whatever remains, which is a full
+            // fragment if the size is a multiple of the fragment size.
             f if f == num_fragments - 1 =>
-                self.size % base_fragment_size,
+                self.size - (base_fragment_size * f),
             f if f < num_fragments =>
                 base_fragment_size,
             _ => panic!(),
         };
-        unsafe {
-            let ptr = self.backing.offset(
-                (fragment_size * fragment) as isize);
-            std::ptr::slice_from_raw_parts_mut(ptr, fragment_size)
-        }
+        std::ptr::slice_from_raw_parts_mut(
+            self.get_fragment_ptr(fragment), fragment_size)
     }
     unsafe fn as_ref_full_unchecked<'a>(&'a self) -> &'a [u8] {
         &*std::ptr::slice_from_raw_parts(self.backing, self.size)
     }
+    /// Builds a slice over only the given byte range.
+    ///
+    /// # Safety
+    /// Every fragment overlapping `range` must have been observed as sealed
+    /// with an acquire load. Other fragments may be uninitialized or written
+    /// through a `FragmentBuffer` concurrently, so no reference may cover
+    /// them.
+    unsafe fn sealed_slice<'a>(&'a self, range: Range<usize>) -> &'a [u8] {
+        debug_assert!(range.start <= range.end && range.end <= self.size);
+        std::slice::from_raw_parts(self.backing.add(range.start), range.end - range.start)
+    }
 }
 
 pub struct FragmentBuffer {
@@ -280,6 +345,11 @@ impl FragmentBuffer {
         RootBuffer(self.container.clone())
     }
 
+    /// Returns whether this is a fragment of the given root buffer.
+    pub fn belongs_to(&self, root: &RootBuffer) -> bool {
+        Arc::ptr_eq(&self.container, &root.0)
+    }
+
     pub fn state(&self) -> FragmentState {
         let state_num = unsafe {
             (*self.state).load(Ordering::Relaxed)
@@ -309,7 +379,8 @@ impl FragmentBuffer {
                 data.as_ptr(),
                 (&mut *self.buf).as_mut_ptr(),
                 self.len()
-            )
+            );
+            (*self.state).store(FRAG_CLAIMED_MUTABLE, Ordering::Relaxed);
         }
     }
 
@@ -334,7 +405,7 @@ impl FragmentBuffer {
     pub fn seal(&mut self) {
         assert!(self.state() == FragmentState::Mutable);
         unsafe {
-            (*self.state).store(FRAG_SEALED, Ordering::Relaxed);
+            (*self.state).store(FRAG_SEALED, Ordering::Release);
         }
     }
 
@@ -344,6 +415,15 @@ impl FragmentBuffer {
 mod tests {
     use super::*;
 
+    #[test]
+    fn fragment_sizes() {
+        assert_eq!(FragSize(8).needed_fragments(1000), 4);
+        assert_eq!(FragSize(8).needed_fragments(1024), 4);
+        assert_eq!(FragSize(8).needed_fragments(usize::MAX), usize::MAX / 256 + 1);
+        assert_eq!(FragSize(8).fragment_len(1000, 2), 256);
+        assert_eq!(FragSize(8).fragment_len(1000, 3), 232);
+    }
+
     #[test]
     fn basic_usage() {
         let root = RootBuffer::new(1000, FragSize(8));
@@ -384,4 +464,49 @@ mod tests {
         let frag4_2 = root.claim(3).unwrap();
     }
 
+    #[test]
+    fn fill_and_seal() {
+        let root = RootBuffer::new(1024, FragSize(8));
+        assert_eq!(root.num_fragments(), 4);
+
+        for idx in 0..4 {
+            let mut frag = root.claim(idx).unwrap();
+            assert_eq!(frag.len(), 256);
+            frag.fill(&[idx as u8; 256]);
+            assert_eq!(frag.state(), FragmentState::Mutable);
+            assert!(root.as_ref_full().is_none());
+            frag.seal();
+        }
+
+        let full = root.as_ref_full().unwrap();
+        assert_eq!(full.len(), 1024);
+        for idx in 0..4 {
+            assert!(full[idx * 256..(idx + 1) * 256].iter().all(|v| *v == idx as u8));
+        }
+    }
+
+    #[test]
+    fn partial_reads() {
+        let root = RootBuffer::new(1000, FragSize(8));
+        let mut frags: Vec<_> = (0..4).map(|idx| root.claim(idx).unwrap()).collect();
+        for (idx, frag) in frags.iter_mut().enumerate() {
+            frag.fill(&[idx as u8; 256]);
+        }
+
+        frags[1].seal();
+        assert!(root.sealed_prefix().is_empty());
+        assert_eq!(root.range(300..512), Some(&[1; 212][..]));
+        assert_eq!(root.range(255..256), None);
+        assert_eq!(root.range(300..513), None);
+        assert_eq!(root.range(100..100), Some(&[][..]));
+
+        frags[0].seal();
+        frags[3].seal();
+        assert_eq!(root.sealed_prefix().len(), 512);
+        assert_eq!(root.range(800..1000), Some(&[3; 200][..]));
+
+        frags[2].seal();
+        assert_eq!(root.sealed_prefix().len(), 1000);
+    }
+
 }
@@ -0,0 +1,70 @@
+//! Canonical content hashes.
+//!
+//! A fragment is identified by the SHA-256 digest of its data.
+//!
+//! An object is identified by a SHA-256 digest over its layout and the hashes
+//! of its fragments, in order. This makes it possible to verify an object
+//! manifest on its own, and the object data one fragment at a time.
+
+use std::convert::TryInto;
+
+use ring::digest;
+
+use livecore_protocol as proto;
+use proto::Hash;
+
+/// Prefix of the object hash input, keeps object hashes from ever colliding
+/// with fragment hashes.
+const OBJECT_HASH_PREFIX: &[u8] = b"__LIVECORE_OBJECT__";
+
+fn finish(digest: digest::Digest) -> Hash {
+    Hash(digest.as_ref().try_into().unwrap())
+}
+
+pub fn fragment_hash(data: &[u8]) -> Hash {
+    finish(digest::digest(&digest::SHA256, data))
+}
+
+pub fn object_hash(size: usize, fragment_size: u32, fragments: &[Hash]) -> Hash {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(OBJECT_HASH_PREFIX);
+    ctx.update(&(size as u64).to_le_bytes());
+    ctx.update(&fragment_size.to_le_bytes());
+    for fragment in fragments {
+        ctx.update(&fragment.0);
+    }
+    finish(ctx.finish())
+}
+
+/// Computes the object hash described by a manifest.
+pub fn manifest_hash(manifest: &proto::ObjectManifest) -> Hash {
+    let fragments: Vec<Hash> = manifest.fragments.iter().map(|f| f.hash).collect();
+    object_hash(manifest.size, manifest.fragment_size, &fragments)
+}
+
+#[cfg(test)]
+mod tests {
+    use livecore_protocol::Hash;
+
+    use super::{fragment_hash, object_hash};
+
+    #[test]
+    fn fragment_sha256() {
+        let hash = fragment_hash(b"abc");
+        let expected = Hash::parse_str(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad").unwrap();
+        assert_eq!(hash, expected);
+    }
+
+    #[test]
+    fn object_layout() {
+        let fragments = [fragment_hash(b"a"), fragment_hash(b"b")];
+
+        let hash = object_hash(2, 0, &fragments);
+        assert_eq!(hash, object_hash(2, 0, &fragments));
+
+        assert!(hash != object_hash(3, 0, &fragments));
+        assert!(hash != object_hash(2, 1, &fragments));
+        assert!(hash != object_hash(2, 0, &[fragments[1], fragments[0]]));
+    }
+}
@@ -6,20 +6,127 @@
 //! of data distribution. When a client has all the fragments of an object,
 //! it may assemble the full object.
 //!
-//! Both objects and fragments may be marked as live. A live entity is kept in
-//! memory, while a dead one may be garbage collected.
+//! Both objects and fragments may be marked as live by pinning them. A live
+//! entity is kept in memory, while a dead one may be garbage collected with
+//! `DataManager::collect_garbage`.
 //!
-//! If an object is marked as live, all its fragments are kept alive by it.
+//! Every object holds a reference to each of its fragments, which keeps the
+//! fragments alive for as long as the object exists. Objects that are still
+//! being fetched are kept alive until they complete.
+//!
+//! With a memory budget set, the least recently used objects that are not
+//! live are evicted whenever the data held exceeds it, see
+//! `DataManager::evict`. Objects that peers were recently told about or
+//! fetched from us are kept for a grace period, as more requests for them
+//! may be on the way.
+//!
+//! Present fragments may also be kept in a persistent `FragmentStore`, see
+//! `store`.
+//!
+//! Objects can be read before they complete with an `ObjectReader`, see
+//! `reader`.
 
 use std::collections::{HashMap, HashSet};
-use std::time::Instant;
-use std::sync::atomic::AtomicUsize;
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use tokio::sync::watch;
+use tokio::time::Instant;
 
 pub mod fragment_buffer;
 use fragment_buffer::{FragSize, FragmentBuffer, RootBuffer};
 
+pub mod hash;
+pub mod signing;
+pub mod store;
+use store::{FragmentStore, StoreThread, StoreDone};
+
+mod reader;
+pub use reader::ObjectReader;
+
+mod object_builder;
+pub use object_builder::{ObjectBuilder, LocalObject};
+
 use livecore_protocol as proto;
-use proto::Hash;
+use proto::{Hash, Uuid};
+
+use crate::peer::key::{KeyRing, KeyCapability};
+
+/// Largest fragment size accepted in an object manifest, `2^30`.
+const MAX_FRAG_SIZE: u32 = 30;
+
+fn valid_fragment_size(frag_size: FragSize) -> bool {
+    frag_size.0 != 0 && frag_size.0 <= MAX_FRAG_SIZE
+}
+
+/// Default for the largest object size accepted in an object manifest,
+/// 1 GiB.
+pub const DEFAULT_MAX_OBJECT_SIZE: usize = 1 << 30;
+
+/// Default for how long an object is kept from eviction after it was last
+/// announced or served to peers.
+pub const DEFAULT_SERVE_GRACE: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DataError {
+    /// An object manifest was received for an object that already exists.
+    DuplicateObject(Hash),
+    /// The object manifest is not internally consistent.
+    InvalidManifest(Hash, &'static str),
+    /// A local object could not be created from the given data.
+    InvalidObject(&'static str),
+    /// Fragment data was received for a fragment that is not part of any
+    /// known object.
+    UnknownFragment(Hash),
+    /// The object is not known.
+    UnknownObject(Hash),
+    /// A read of an object was outside of it.
+    InvalidRange {
+        hash: Hash,
+        start: usize,
+        end: usize,
+    },
+    /// Fragment data was received with the wrong size.
+    InvalidSize {
+        hash: Hash,
+        expected: usize,
+        actual: usize,
+    },
+    /// Fragment data was received whose digest does not match the hash it
+    /// was sent for.
+    HashMismatch {
+        hash: Hash,
+        actual: Hash,
+        peer: Uuid,
+    },
+    /// A manifest for a stream was signed by a key that may not originate
+    /// the stream.
+    Unauthorized(Uuid),
+    /// A manifest for a stream has a signature that does not match its
+    /// contents.
+    InvalidSignature(Uuid),
+}
+impl std::fmt::Display for DataError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::DuplicateObject(hash) => write!(f, "duplicate object {}", hash),
+            Self::InvalidManifest(hash, reason) => write!(f, "invalid manifest for {}: {}", hash, reason),
+            Self::InvalidObject(reason) => write!(f, "invalid object: {}", reason),
+            Self::UnknownFragment(hash) => write!(f, "unknown fragment {}", hash),
+            Self::UnknownObject(hash) => write!(f, "unknown object {}", hash),
+            Self::InvalidRange { hash, start, end } => write!(
+                f, "invalid range {}..{} for object {}", start, end, hash),
+            Self::InvalidSize { hash, expected, actual } => write!(
+                f, "invalid size for fragment {} (expected {}, got {})", hash, expected, actual),
+            Self::HashMismatch { hash, actual, peer } => write!(
+                f, "hash mismatch for fragment {} from peer {} (got {})", hash, peer, actual),
+            Self::Unauthorized(stream) => write!(f, "key may not originate stream {}", stream),
+            Self::InvalidSignature(stream) => write!(f, "invalid signature on manifest for stream {}", stream),
+        }
+    }
+}
+impl std::error::Error for DataError {}
 
 #[derive(Debug, Eq, PartialEq)]
 enum FragmentState {
@@ -32,21 +139,111 @@ struct Fragment {
     hash: Hash,
     state: FragmentState,
 
+    /// The peer the fragment has been requested from, if any.
+    requested_from: Option<Uuid>,
+
     tmp_data: Option<Vec<u8>>,
     buffers: Vec<FragmentBuffer>,
+
+    /// Objects the fragment is part of, each holding a reference to it.
+    objects: Vec<Hash>,
+    /// Number of times the fragment has been pinned.
+    pins: usize,
+}
+impl Fragment {
+    fn is_live(&self) -> bool {
+        self.pins > 0 || !self.objects.is_empty()
+    }
 }
 
 struct Object {
     hash: Hash,
+    tags: Vec<String>,
     buffer: RootBuffer,
 
+    /// Set once every fragment of the object is sealed.
+    complete: bool,
+
     fragment_hashes: Vec<Hash>,
     fragment_hash_to_idx: HashMap<Hash, usize>,
+
+    /// Number of times the object has been pinned.
+    pins: usize,
+    /// Value of `DataManager::clock` when the object was last used.
+    last_used: u64,
+    /// When fragments of the object were last announced or served to peers.
+    served_at: Option<Instant>,
+
+    /// Notifies `ObjectReader`s when fragments are sealed. The receiver is
+    /// kept to hand out clones of.
+    progress: watch::Sender<()>,
+    progress_receiver: watch::Receiver<()>,
+}
+impl Object {
+    fn new(manifest: &proto::ObjectManifest, buffer: RootBuffer) -> Self {
+        let (progress, progress_receiver) = watch::channel(());
+        Object {
+            hash: manifest.hash,
+            tags: manifest.tags.clone(),
+            buffer,
+            complete: false,
+
+            fragment_hashes: manifest.fragments.iter().map(|f| f.hash).collect(),
+            fragment_hash_to_idx: manifest.fragments.iter().enumerate().map(|(i, f)| (f.hash, i)).collect(),
+
+            pins: 0,
+            last_used: 0,
+            served_at: None,
+
+            progress,
+            progress_receiver,
+        }
+    }
+}
+impl Object {
+    fn is_live(&self) -> bool {
+        self.pins > 0 || !self.complete
+    }
+
+    /// Whether peers may still be about to request fragments of the object.
+    fn is_serving(&self, now: Instant, grace: Duration) -> bool {
+        self.served_at
+            .map(|served_at| now.saturating_duration_since(served_at) < grace)
+            .unwrap_or(false)
+    }
 }
 
 pub struct DataManager {
     objects: HashMap<Hash, Object>,
     fragments: HashMap<Hash, Fragment>,
+
+    /// Objects that have completed since the last call to `take_completed`.
+    completed: Vec<Hash>,
+
+    /// Latest manifest of every known stream.
+    streams: HashMap<Uuid, proto::SignedStreamManifest>,
+    /// Signed manifests of objects in streams, passed on to new peers.
+    signed_objects: HashMap<Hash, proto::SignedObjectManifest>,
+
+    /// Present fragments dropped since the last call to `take_dropped`.
+    dropped: Vec<Hash>,
+
+    /// Bytes of data held before objects are evicted, if limited.
+    memory_budget: Option<usize>,
+    /// How long objects served to peers are kept from eviction.
+    serve_grace: Duration,
+    /// Largest object accepted in a manifest, as its buffer is allocated
+    /// up front.
+    max_object_size: usize,
+    /// Counts object uses, for finding the least recently used one.
+    clock: u64,
+
+    store: Option<StoreThread>,
+    /// Fragments in `store`, or being written to it.
+    stored: HashSet<Hash>,
+    /// Fragments being read from `store`, with the peers waiting to be
+    /// served them.
+    reads: HashMap<Hash, Vec<Uuid>>,
 }
 
 impl DataManager {
@@ -55,43 +252,1362 @@ impl DataManager {
         Self {
             objects: HashMap::new(),
             fragments: HashMap::new(),
+            completed: Vec::new(),
+            streams: HashMap::new(),
+            signed_objects: HashMap::new(),
+            dropped: Vec::new(),
+            memory_budget: None,
+            serve_grace: DEFAULT_SERVE_GRACE,
+            max_object_size: DEFAULT_MAX_OBJECT_SIZE,
+            clock: 0,
+            store: None,
+            stored: HashSet::new(),
+            reads: HashMap::new(),
         }
     }
 
-    pub fn handle_object_manifest(&mut self, manifest: proto::ObjectManifest) {
-        let root = RootBuffer::new(manifest.size, FragSize(manifest.fragment_size));
+    /// Keeps a copy of every present fragment in `store`. The fragments
+    /// already in it are served to peers, and used by new objects.
+    pub fn with_store(mut self, store: Box<dyn FragmentStore>) -> Self {
+        match store.list() {
+            Ok(hashes) => {
+                log::info!("fragment store has {} fragments", hashes.len());
+                self.stored = hashes.into_iter().collect();
+            },
+            Err(error) => log::warn!("failed to list fragment store: {}", error),
+        }
+        self.store = Some(StoreThread::spawn(store));
+        self
+    }
+
+    /// Limits the bytes of data held, see `evict`.
+    pub fn with_memory_budget(mut self, memory_budget: Option<usize>) -> Self {
+        self.memory_budget = memory_budget;
+        self
+    }
+
+    /// Sets how long objects announced or served to peers are kept from
+    /// eviction, see `evict`.
+    pub fn with_serve_grace(mut self, serve_grace: Duration) -> Self {
+        self.serve_grace = serve_grace;
+        self
+    }
+
+    /// Sets the largest object size accepted in a manifest.
+    pub fn with_max_object_size(mut self, max_object_size: usize) -> Self {
+        self.max_object_size = max_object_size;
+        self
+    }
+
+    pub fn handle_object_manifest(&mut self, manifest: proto::ObjectManifest) -> Result<(), DataError> {
+        if self.objects.contains_key(&manifest.hash) {
+            return Err(DataError::DuplicateObject(manifest.hash));
+        }
+        let frag_size = FragSize(manifest.fragment_size);
+        if !valid_fragment_size(frag_size) {
+            return Err(DataError::InvalidManifest(manifest.hash, "invalid fragment size"));
+        }
+        if manifest.size > self.max_object_size {
+            return Err(DataError::InvalidManifest(manifest.hash, "object too large"));
+        }
+        if manifest.size == 0 || frag_size.needed_fragments(manifest.size) != manifest.fragments.len() {
+            return Err(DataError::InvalidManifest(manifest.hash, "fragments do not match size"));
+        }
+        if hash::manifest_hash(&manifest) != manifest.hash {
+            return Err(DataError::InvalidManifest(manifest.hash, "object hash does not match manifest"));
+        }
+
+        // A fragment has the same data wherever it is used, so every slot it
+        // is placed in must have the same length.
+        let mut lengths = HashMap::new();
+        for (idx, fragment) in manifest.fragments.iter().enumerate() {
+            let len = frag_size.fragment_len(manifest.size, idx);
+            let known = *lengths.entry(fragment.hash).or_insert(len);
+            if known != len || self.check_fragment_len(&fragment.hash, len).is_err() {
+                return Err(DataError::InvalidManifest(manifest.hash, "fragment sizes do not match"));
+            }
+        }
+
+        let root = RootBuffer::new(manifest.size, frag_size);
 
         for (idx, fragment) in manifest.fragments.iter().enumerate() {
             let mut frag_buf = root.claim(idx).unwrap();
 
-            let frag = self.fragments.entry(fragment.hash).or_insert(Fragment {
-                hash: fragment.hash,
-                state: FragmentState::Expecting,
-                tmp_data: None,
-                buffers: vec![],
-            });
+            let frag = self.fragment_entry(fragment.hash);
+            frag.objects.push(manifest.hash);
 
-            if let Some(data) = frag.tmp_data.take() {
-                frag_buf.fill(&data);
-            } else if frag.buffers.len() > 0 {
-                let prev_buf = &frag.buffers[0];
-                if frag.state == FragmentState::Present {
-                    frag_buf.fill(prev_buf.as_ref());
+            if frag.state == FragmentState::Present {
+                if let Some(data) = frag.tmp_data.take() {
+                    frag_buf.fill(&data);
+                } else {
+                    frag_buf.fill(frag.buffers[0].as_ref());
                 }
+                frag_buf.seal();
             }
             frag.buffers.push(frag_buf);
         }
 
-        let object = Object {
-            hash: manifest.hash,
-            buffer: root.clone(),
+        let object = Object::new(&manifest, root.clone());
 
-            fragment_hashes: manifest.fragments.iter().map(|f| f.hash).collect(),
-            fragment_hash_to_idx: manifest.fragments.iter().enumerate().map(|(i, f)| (f.hash, i)).collect(),
+        self.objects.insert(manifest.hash, object);
+        self.check_complete(&[manifest.hash]);
+
+        // Stored fragments fill the object once they are read, see
+        // `handle_store_done`.
+        for hash in lengths.keys() {
+            if self.stored.contains(hash) && !self.has_fragment(hash) {
+                self.read_stored(*hash, None);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Inserts an object created on this node.
+    ///
+    /// Returns the fragments that became present, which other nodes may now
+    /// be told about.
+    pub fn insert_local_object(&mut self, object: LocalObject) -> Result<Vec<Hash>, DataError> {
+        let LocalObject { manifest, buffer, fragments, .. } = object;
+
+        if self.objects.contains_key(&manifest.hash) {
+            return Err(DataError::DuplicateObject(manifest.hash));
+        }
+
+        for (fragment, frag_buf) in manifest.fragments.iter().zip(fragments.iter()) {
+            self.check_fragment_len(&fragment.hash, frag_buf.len())?;
+        }
+
+        let mut new_present = Vec::new();
+
+        for (fragment, frag_buf) in manifest.fragments.iter().zip(fragments) {
+            self.persist(&fragment.hash, frag_buf.as_ref());
+
+            let frag = self.fragment_entry(fragment.hash);
+            frag.objects.push(manifest.hash);
+
+            if frag.state == FragmentState::Expecting {
+                // Other objects may already be waiting for this fragment.
+                for buf in frag.buffers.iter_mut() {
+                    buf.fill(frag_buf.as_ref());
+                    buf.seal();
+                }
+                frag.state = FragmentState::Present;
+                frag.requested_from = None;
+                new_present.push(fragment.hash);
+            }
+
+            frag.tmp_data = None;
+            frag.buffers.push(frag_buf);
+        }
+
+        self.objects.insert(manifest.hash, Object::new(&manifest, buffer));
+
+        // Waiting objects sharing fragments with this one may be complete now.
+        let mut check = vec![manifest.hash];
+        for hash in new_present.iter() {
+            check.extend(self.fragments[hash].objects.iter().cloned());
+        }
+        self.check_complete(&check);
+
+        Ok(new_present)
+    }
+
+    /// Handles an object manifest for a stream received from a peer.
+    ///
+    /// The manifest is only accepted if it is signed by a key that may
+    /// originate the stream.
+    pub fn handle_signed_object_manifest(
+        &mut self,
+        keys: &KeyRing,
+        signed: proto::SignedObjectManifest,
+    ) -> Result<(), DataError> {
+        let payload = signing::object_manifest_payload(&signed.stream_uuid, &signed.manifest);
+        verify_signature(keys, signed.stream_uuid, &signed.pubkey, &payload, &signed.signature)?;
+
+        self.handle_object_manifest(signed.manifest.clone())?;
+        self.signed_objects.insert(signed.manifest.hash, signed);
+
+        Ok(())
+    }
+
+    /// Handles a stream manifest received from a peer.
+    ///
+    /// The manifest is only accepted if it is signed by a key that may
+    /// originate the stream. Returns `true` if it is newer than the one we
+    /// had.
+    pub fn handle_signed_stream_manifest(
+        &mut self,
+        keys: &KeyRing,
+        signed: proto::SignedStreamManifest,
+    ) -> Result<bool, DataError> {
+        let stream_uuid = signed.manifest.stream_uuid;
+        let payload = signing::stream_manifest_payload(&signed.manifest);
+        verify_signature(keys, stream_uuid, &signed.pubkey, &payload, &signed.signature)?;
+
+        Ok(self.insert_stream_manifest(signed))
+    }
+
+    /// Stores the signed manifest of an object created on this node.
+    pub fn insert_signed_object_manifest(&mut self, signed: proto::SignedObjectManifest) {
+        self.signed_objects.insert(signed.manifest.hash, signed);
+    }
+
+    /// Stores a stream manifest without verifying it, returns `true` if it is
+    /// newer than the one we had.
+    pub fn insert_stream_manifest(&mut self, signed: proto::SignedStreamManifest) -> bool {
+        let stream_uuid = signed.manifest.stream_uuid;
+        if let Some(current) = self.streams.get(&stream_uuid) {
+            if current.manifest.sequence >= signed.manifest.sequence {
+                return false;
+            }
+        }
+        self.streams.insert(stream_uuid, signed);
+        true
+    }
+
+    /// Returns the next manifest of a stream originated by this node, with
+    /// the given object added to it.
+    pub fn next_stream_manifest(&self, stream_uuid: Uuid, object: Hash) -> proto::StreamManifest {
+        match self.streams.get(&stream_uuid) {
+            Some(current) => {
+                let mut objects = current.manifest.objects.clone();
+                objects.push(object);
+                proto::StreamManifest {
+                    stream_uuid,
+                    sequence: current.manifest.sequence + 1,
+                    objects,
+                }
+            },
+            None => proto::StreamManifest {
+                stream_uuid,
+                sequence: 0,
+                objects: vec![object],
+            },
+        }
+    }
+
+    pub fn stream_manifests(&self) -> impl Iterator<Item = &proto::SignedStreamManifest> {
+        self.streams.values()
+    }
+
+    pub fn signed_object_manifests(&self) -> impl Iterator<Item = &proto::SignedObjectManifest> {
+        self.signed_objects.values()
+    }
+
+    fn fragment_entry(&mut self, hash: Hash) -> &mut Fragment {
+        self.fragments.entry(hash).or_insert(Fragment {
+            hash,
+            state: FragmentState::Expecting,
+            requested_from: None,
+            tmp_data: None,
+            buffers: vec![],
+            objects: vec![],
+            pins: 0,
+        })
+    }
+
+    /// Marks any of the given objects that have all fragments sealed as
+    /// complete, and wakes their readers.
+    fn check_complete(&mut self, objects: &[Hash]) {
+        for hash in objects {
+            if let Some(object) = self.objects.get_mut(hash) {
+                // The object holds a receiver itself, so this can not fail.
+                let _ = object.progress.send(());
+                if !object.complete && object.buffer.as_ref_full().is_some() {
+                    object.complete = true;
+                    self.completed.push(*hash);
+                    self.clock += 1;
+                    object.last_used = self.clock;
+                }
+            }
+        }
+    }
+
+    /// Handles data received from a peer for a fragment.
+    ///
+    /// Returns `true` if the fragment became present, `false` if it already
+    /// was.
+    pub fn handle_fragment_data(&mut self, peer: Uuid, hash: Hash, data: &[u8]) -> Result<bool, DataError> {
+        let frag = self.fragments.get(&hash)
+            .ok_or(DataError::UnknownFragment(hash))?;
+
+        if frag.state == FragmentState::Present {
+            return Ok(false);
+        }
+
+        self.check_fragment_len(&hash, data.len())?;
+
+        let actual = hash::fragment_hash(data);
+        if actual != hash {
+            return Err(DataError::HashMismatch {
+                hash,
+                actual,
+                peer,
+            });
+        }
+
+        self.fill_fragment(&hash, data);
+        self.persist(&hash, data);
+
+        Ok(true)
+    }
+
+    /// Checks that data of the given length fits every slot a fragment is
+    /// placed in, and matches any data already held for it.
+    fn check_fragment_len(&self, hash: &Hash, len: usize) -> Result<(), DataError> {
+        let frag = match self.fragments.get(hash) {
+            Some(frag) => frag,
+            None => return Ok(()),
         };
+        let known = frag.tmp_data.iter().map(|data| data.len())
+            .chain(frag.buffers.iter().map(|buf| buf.len()));
+        for expected in known {
+            if expected != len {
+                return Err(DataError::InvalidSize { hash: *hash, expected, actual: len });
+            }
+        }
+        Ok(())
+    }
 
-        assert!(!self.objects.contains_key(&manifest.hash));
-        self.objects.insert(manifest.hash, object);
+    /// Makes an expecting fragment present with data that has been
+    /// verified, and completes the objects waiting for it.
+    ///
+    /// The length of the data must have been checked with
+    /// `check_fragment_len`.
+    fn fill_fragment(&mut self, hash: &Hash, data: &[u8]) {
+        debug_assert!(self.check_fragment_len(hash, data.len()).is_ok());
+        let frag = self.fragments.get_mut(hash).unwrap();
+        for buf in frag.buffers.iter_mut() {
+            buf.fill(data);
+            buf.seal();
+        }
+        if frag.buffers.is_empty() {
+            frag.tmp_data = Some(data.to_owned());
+        }
+
+        frag.state = FragmentState::Present;
+        frag.requested_from = None;
+
+        let objects = frag.objects.clone();
+        self.check_complete(&objects);
+    }
+
+    /// Writes a present fragment to the store, if it is not there already.
+    ///
+    /// The fragment counts as stored right away, as reads queued after the
+    /// write see its data.
+    fn persist(&mut self, hash: &Hash, data: &[u8]) {
+        let store = match self.store.as_mut() {
+            Some(store) if !self.stored.contains(hash) => store,
+            _ => return,
+        };
+        store.put(*hash, data.to_owned());
+        self.stored.insert(*hash);
+    }
+
+    /// Starts reading a fragment from the store, for the objects expecting
+    /// it and the given peer. A read already in progress is shared.
+    fn read_stored(&mut self, hash: Hash, peer: Option<Uuid>) {
+        let store = match self.store.as_mut() {
+            Some(store) => store,
+            None => return,
+        };
+        let waiting = self.reads.entry(hash).or_insert_with(|| {
+            store.get(hash);
+            Vec::new()
+        });
+        waiting.extend(peer);
+    }
+
+    /// Removes a fragment from the store.
+    fn unstore(&mut self, hash: &Hash) {
+        if !self.stored.remove(hash) {
+            return;
+        }
+        self.store.as_mut().unwrap().remove(*hash);
+    }
+
+    /// Waits for the next operation finished by the store thread, to be
+    /// passed to `handle_store_done`. Never resolves without a store.
+    pub(crate) async fn store_done(&mut self) -> Option<StoreDone> {
+        match self.store.as_mut() {
+            Some(store) => store.recv().await,
+            None => futures::future::pending().await,
+        }
+    }
+
+    /// Handles an operation finished by the store thread.
+    ///
+    /// A fragment read from the store fills the objects expecting it, and
+    /// is returned along with the peers waiting to be served it.
+    pub(crate) fn handle_store_done(&mut self, done: StoreDone) -> Option<(Hash, Bytes, Vec<Uuid>)> {
+        match done {
+            StoreDone::Get(hash, res) => return self.handle_stored_read(hash, res),
+            StoreDone::Put(hash, Err(error)) => {
+                log::warn!("failed to store fragment {}: {}", hash, error);
+                // Peers were told we have it, which is no longer true if it
+                // has left memory since.
+                if self.stored.remove(&hash) && !self.has_fragment(&hash) {
+                    self.dropped.push(hash);
+                }
+            },
+            StoreDone::Remove(hash, Err(error)) => {
+                log::warn!("failed to remove stored fragment {}: {}", hash, error);
+            },
+            StoreDone::Put(_, Ok(())) | StoreDone::Remove(_, Ok(())) => (),
+        }
+        None
+    }
+
+    fn handle_stored_read(
+        &mut self,
+        hash: Hash,
+        res: std::io::Result<Option<Vec<u8>>>,
+    ) -> Option<(Hash, Bytes, Vec<Uuid>)> {
+        let peers = self.reads.remove(&hash).unwrap_or_default();
+        let data = match res {
+            Ok(Some(data)) => Bytes::from(data),
+            Ok(None) => {
+                // Fetched from peers instead.
+                log::warn!("stored fragment {} is missing or corrupt", hash);
+                self.unstore(&hash);
+                return None;
+            },
+            Err(error) => {
+                log::warn!("failed to read stored fragment {}: {}", hash, error);
+                return None;
+            },
+        };
+
+        let expecting = matches!(
+            self.fragments.get(&hash),
+            Some(frag) if frag.state == FragmentState::Expecting
+        );
+        if expecting {
+            match self.check_fragment_len(&hash, data.len()) {
+                Ok(()) => self.fill_fragment(&hash, &data),
+                // The data matches its hash, so it is the objects that are
+                // wrong about it. They can not complete.
+                Err(error) => log::warn!("stored fragment does not fit its objects: {}", error),
+            }
+        }
+
+        if peers.is_empty() {
+            return None;
+        }
+        self.mark_served(&[hash]);
+        Some((hash, data, peers))
+    }
+
+    /// Forgets an object, releasing its buffer, whether it is live or not.
+    ///
+    /// Fragments shared with other objects or pinned are kept, the rest are
+    /// dropped whether present or not, and removed from the store.
+    pub fn expire_object(&mut self, hash: &Hash) -> Result<(), DataError> {
+        let fragments = self.objects.get(hash)
+            .ok_or(DataError::UnknownObject(*hash))?
+            .fragment_hashes.clone();
+        self.release_object(hash);
+
+        for frag_hash in fragments {
+            if !self.fragments.contains_key(&frag_hash) && self.stored.contains(&frag_hash) {
+                self.unstore(&frag_hash);
+                self.dropped.push(frag_hash);
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks an object as live, keeping it from being garbage collected.
+    ///
+    /// Pins are counted, the object stays live until it has been unpinned as
+    /// many times as it was pinned.
+    pub fn pin_object(&mut self, hash: &Hash) -> Result<(), DataError> {
+        let object = self.objects.get_mut(hash)
+            .ok_or(DataError::UnknownObject(*hash))?;
+        object.pins += 1;
+        Ok(())
+    }
+
+    /// Removes a pin added with `pin_object`. Unpinning an object that is
+    /// not pinned does nothing.
+    pub fn unpin_object(&mut self, hash: &Hash) -> Result<(), DataError> {
+        let object = self.objects.get_mut(hash)
+            .ok_or(DataError::UnknownObject(*hash))?;
+        object.pins = object.pins.saturating_sub(1);
+        Ok(())
+    }
+
+    /// Marks a fragment as live, keeping it from being garbage collected
+    /// along with the objects it is part of.
+    ///
+    /// Pins are counted, like object pins.
+    pub fn pin_fragment(&mut self, hash: &Hash) -> Result<(), DataError> {
+        let frag = self.fragments.get_mut(hash)
+            .ok_or(DataError::UnknownFragment(*hash))?;
+        frag.pins += 1;
+        Ok(())
+    }
+
+    /// Removes a pin added with `pin_fragment`. Unpinning a fragment that is
+    /// not pinned does nothing.
+    pub fn unpin_fragment(&mut self, hash: &Hash) -> Result<(), DataError> {
+        let frag = self.fragments.get_mut(hash)
+            .ok_or(DataError::UnknownFragment(*hash))?;
+        frag.pins = frag.pins.saturating_sub(1);
+        Ok(())
+    }
+
+    /// Frees every object that is not live, along with the fragments no
+    /// longer referenced by any object unless they are pinned.
+    ///
+    /// Returns the objects that were freed.
+    pub fn collect_garbage(&mut self) -> Vec<Hash> {
+        let dead: Vec<Hash> = self.objects.values()
+            .filter(|object| !object.is_live())
+            .map(|object| object.hash)
+            .collect();
+        for hash in dead.iter() {
+            self.release_object(hash);
+        }
+
+        // Fragments that were unpinned after their last object was freed.
+        let dropped = &mut self.dropped;
+        let stored = &self.stored;
+        self.fragments.retain(|hash, frag| {
+            if !frag.is_live() && frag.state == FragmentState::Present && !stored.contains(hash) {
+                dropped.push(*hash);
+            }
+            frag.is_live()
+        });
+
+        dead
+    }
+
+    /// Frees the least recently used objects that are not live, until the
+    /// data held is within the memory budget. Live objects are never
+    /// evicted, so the budget may still be exceeded. Neither are objects
+    /// announced or served to peers within the serve grace period, which
+    /// may have requests for them on the way.
+    ///
+    /// Returns the objects that were evicted.
+    pub fn evict(&mut self) -> Vec<Hash> {
+        let budget = match self.memory_budget {
+            Some(budget) => budget,
+            None => return vec![],
+        };
+        let (now, grace) = (Instant::now(), self.serve_grace);
+
+        let mut evicted = Vec::new();
+        while self.memory_usage() > budget {
+            let lru = self.objects.values()
+                .filter(|object| !object.is_live() && !object.is_serving(now, grace))
+                .min_by_key(|object| object.last_used)
+                .map(|object| object.hash);
+            match lru {
+                Some(hash) => {
+                    self.release_object(&hash);
+                    evicted.push(hash);
+                },
+                None => break,
+            }
+        }
+        evicted
+    }
+
+    /// Returns the present fragments that have been dropped since the last
+    /// call, which peers should no longer expect us to have. Fragments that
+    /// are still in the store are not dropped.
+    pub fn take_dropped(&mut self) -> Vec<Hash> {
+        std::mem::take(&mut self.dropped)
+    }
+
+    /// Removes an object, and drops its references to its fragments.
+    /// Fragments that are no longer live are dropped.
+    ///
+    /// Returns `false` if the object is not known.
+    fn release_object(&mut self, hash: &Hash) -> bool {
+        let object = match self.objects.remove(hash) {
+            Some(object) => object,
+            None => return false,
+        };
+
+        for frag_hash in object.fragment_hashes.iter() {
+            let frag = match self.fragments.get_mut(frag_hash) {
+                Some(frag) => frag,
+                // Already dropped, the object has the fragment more than once.
+                None => continue,
+            };
+            frag.objects.retain(|other| other != hash);
+            if !frag.is_live() {
+                if frag.state == FragmentState::Present && !self.stored.contains(frag_hash) {
+                    self.dropped.push(*frag_hash);
+                }
+                self.fragments.remove(frag_hash);
+                continue;
+            }
+
+            // A pinned fragment outlives the buffers of its objects, so keep
+            // its data on its own.
+            if frag.objects.is_empty() && frag.state == FragmentState::Present && frag.tmp_data.is_none() {
+                frag.tmp_data = frag.buffers.first().map(|buf| buf.as_ref().to_owned());
+            }
+            frag.buffers.retain(|buf| !buf.belongs_to(&object.buffer));
+        }
+
+        self.completed.retain(|other| other != hash);
+        self.signed_objects.remove(hash);
+
+        true
+    }
+
+    pub fn has_fragment(&self, hash: &Hash) -> bool {
+        self.fragments.get(hash)
+            .map(|frag| frag.state == FragmentState::Present)
+            .unwrap_or(false)
+    }
+
+    /// Returns the data of a present fragment.
+    pub fn fragment_data(&self, hash: &Hash) -> Option<&[u8]> {
+        let frag = self.fragments.get(hash)?;
+        if frag.state != FragmentState::Present {
+            return None;
+        }
+        if let Some(data) = &frag.tmp_data {
+            Some(data)
+        } else {
+            frag.buffers.first().map(|buf| buf.as_ref())
+        }
+    }
+
+    /// Returns a copy of the data of a present fragment to send to a peer,
+    /// and marks the objects it is part of as used.
+    ///
+    /// The data is copied, so the fragment may be evicted while the copy is
+    /// still being sent. Fragments that are only in the store are read from
+    /// it instead, and handed back for the peer by `handle_store_done`.
+    pub fn serve_fragment(&mut self, peer: Uuid, hash: &Hash) -> Option<Bytes> {
+        let data = match self.fragment_data(hash) {
+            Some(data) => Bytes::copy_from_slice(data),
+            None => {
+                if self.stored.contains(hash) {
+                    self.read_stored(*hash, Some(peer));
+                }
+                return None;
+            },
+        };
+        for object in self.fragments[hash].objects.clone() {
+            self.touch(&object);
+        }
+        self.mark_served(&[*hash]);
+        Some(data)
+    }
+
+    /// Records that fragments were announced or served to peers, which keeps
+    /// the objects they are part of from eviction for a while.
+    pub fn mark_served(&mut self, hashes: &[Hash]) {
+        let now = Instant::now();
+        for hash in hashes {
+            let frag = match self.fragments.get(hash) {
+                Some(frag) => frag,
+                None => continue,
+            };
+            for object in frag.objects.iter() {
+                if let Some(object) = self.objects.get_mut(object) {
+                    object.served_at = Some(now);
+                }
+            }
+        }
+    }
+
+    fn touch(&mut self, hash: &Hash) {
+        if let Some(object) = self.objects.get_mut(hash) {
+            self.clock += 1;
+            object.last_used = self.clock;
+        }
+    }
+
+    /// Returns the objects that have completed since the last call.
+    pub fn take_completed(&mut self) -> Vec<Hash> {
+        std::mem::take(&mut self.completed)
+    }
+
+    #[cfg(test)]
+    pub fn is_complete(&self, hash: &Hash) -> bool {
+        self.objects.get(hash)
+            .map(|object| object.complete)
+            .unwrap_or(false)
+    }
+
+    /// Opens a reader over an object, which may still be incomplete.
+    pub fn open_object(&mut self, hash: &Hash) -> Result<ObjectReader, DataError> {
+        let object = self.objects.get(hash)
+            .ok_or(DataError::UnknownObject(*hash))?;
+        let reader = ObjectReader::new(*hash, object.buffer.clone(), object.progress_receiver.clone());
+        self.touch(hash);
+        Ok(reader)
+    }
+
+    pub fn object_tags(&self, hash: &Hash) -> Option<&[String]> {
+        self.objects.get(hash).map(|object| &object.tags[..])
+    }
+
+    /// Returns a copy of the data of a complete object, and marks it as
+    /// used.
+    pub fn object_data(&mut self, hash: &Hash) -> Option<Bytes> {
+        let data = self.objects.get(hash)?.buffer.as_ref_full().map(Bytes::copy_from_slice)?;
+        self.touch(hash);
+        Some(data)
+    }
+
+    /// Bytes of fragment data held, in object buffers and in fragments that
+    /// arrived before any object claimed them.
+    pub fn memory_usage(&self) -> usize {
+        let objects: usize = self.objects.values()
+            .map(|object| object.buffer.size())
+            .sum();
+        let unclaimed: usize = self.fragments.values()
+            .filter_map(|frag| frag.tmp_data.as_ref())
+            .map(|data| data.len())
+            .sum();
+        objects + unclaimed
+    }
+
+    /// Returns the number of complete and partial objects.
+    pub fn object_counts(&self) -> (usize, usize) {
+        let complete = self.objects.values().filter(|object| object.complete).count();
+        (complete, self.objects.len() - complete)
+    }
+
+    /// Returns every fragment that can be served to peers, in memory or in
+    /// the store.
+    pub fn present_fragments(&self) -> Vec<Hash> {
+        let in_memory = self.fragments.values()
+            .filter(|frag| frag.state == FragmentState::Present)
+            .map(|frag| frag.hash)
+            .filter(|hash| !self.stored.contains(hash));
+        in_memory.chain(self.stored.iter().cloned()).collect()
+    }
+
+    pub fn stored_fragments(&self) -> Vec<Hash> {
+        self.stored.iter().cloned().collect()
+    }
+
+    /// Returns the fragments that are expected, but not yet requested from
+    /// any peer or being read from the store.
+    pub fn missing_fragments(&self) -> Vec<Hash> {
+        self.fragments.values()
+            .filter(|frag| frag.state == FragmentState::Expecting)
+            .filter(|frag| frag.requested_from.is_none())
+            .filter(|frag| !self.reads.contains_key(&frag.hash))
+            .map(|frag| frag.hash)
+            .collect()
+    }
+
+    pub fn mark_requested(&mut self, hash: &Hash, peer: Uuid) {
+        if let Some(frag) = self.fragments.get_mut(hash) {
+            frag.requested_from = Some(peer);
+        }
+    }
+
+    pub fn requested_from(&self, hash: &Hash) -> Option<Uuid> {
+        self.fragments.get(hash)?.requested_from
+    }
+
+    pub fn clear_requested(&mut self, hash: &Hash) {
+        if let Some(frag) = self.fragments.get_mut(hash) {
+            frag.requested_from = None;
+        }
+    }
+
+    /// Forgets all outstanding requests to the given peer, so that the
+    /// fragments can be requested from somewhere else.
+    pub fn clear_requested_from(&mut self, peer: &Uuid) {
+        for frag in self.fragments.values_mut() {
+            if frag.requested_from.as_ref() == Some(peer) {
+                frag.requested_from = None;
+            }
+        }
+    }
+
+}
+
+fn verify_signature(
+    keys: &KeyRing,
+    stream_uuid: Uuid,
+    pubkey: &[u8],
+    payload: &[u8],
+    signature: &[u8],
+) -> Result<(), DataError> {
+    let key = keys.get(pubkey)
+        .filter(|key| key.has_capability(&KeyCapability::OriginateStream(stream_uuid)))
+        .ok_or(DataError::Unauthorized(stream_uuid))?;
+    if !key.verify(payload, signature) {
+        return Err(DataError::InvalidSignature(stream_uuid));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use bytes::Bytes;
+
+    use livecore_protocol as proto;
+    use proto::{Hash, Uuid};
+
+    use super::{DataManager, DataError, ObjectBuilder};
+    use super::store::FragmentStore;
+    use super::fragment_buffer::FragSize;
+    use super::hash::{fragment_hash, manifest_hash};
+
+    const PEER: Uuid = Uuid::from_u128(1);
+
+    fn fragment(n: u8, len: usize) -> (Hash, Vec<u8>) {
+        let data = vec![n; len];
+        (fragment_hash(&data), data)
+    }
+
+    fn manifest(fragments: &[(Hash, Vec<u8>)]) -> proto::ObjectManifest {
+        let mut manifest = proto::ObjectManifest {
+            hash: Hash([0; 32]),
+            tags: vec![],
+            size: fragments.iter().map(|(_, data)| data.len()).sum(),
+            fragment_size: 8,
+            fragments: fragments.iter()
+                .map(|(hash, _)| proto::FragmentManifest { hash: *hash })
+                .collect(),
+        };
+        manifest.hash = manifest_hash(&manifest);
+        manifest
+    }
+
+    #[test]
+    fn fill_fragments() {
+        let frags = [fragment(1, 256), fragment(2, 256), fragment(3, 88)];
+        let mut manager = DataManager::new();
+        manager.handle_object_manifest(manifest(&frags)).unwrap();
+
+        assert_eq!(manager.missing_fragments().len(), 3);
+        assert!(manager.present_fragments().is_empty());
+
+        let (h1, d1) = &frags[0];
+        assert_eq!(manager.handle_fragment_data(PEER, *h1, d1), Ok(true));
+        assert_eq!(manager.handle_fragment_data(PEER, *h1, d1), Ok(false));
+        assert_eq!(manager.fragment_data(h1), Some(&d1[..]));
+
+        let (h3, d3) = &frags[2];
+        assert_eq!(
+            manager.handle_fragment_data(PEER, *h3, &[3; 256]),
+            Err(DataError::InvalidSize { hash: *h3, expected: 88, actual: 256 }),
+        );
+        assert_eq!(manager.handle_fragment_data(PEER, *h3, d3), Ok(true));
+
+        let (h4, d4) = fragment(4, 256);
+        assert_eq!(
+            manager.handle_fragment_data(PEER, h4, &d4),
+            Err(DataError::UnknownFragment(h4)),
+        );
+
+        assert_eq!(manager.missing_fragments(), vec![frags[1].0]);
+    }
+
+    #[test]
+    fn complete_object() {
+        let frags = [fragment(1, 256), fragment(2, 16)];
+        let first = manifest(&frags);
+        let mut manager = DataManager::new();
+        manager.handle_object_manifest(first.clone()).unwrap();
+        assert_eq!(manager.object_counts(), (0, 1));
+        assert_eq!(manager.memory_usage(), 256 + 16);
+
+        manager.handle_fragment_data(PEER, frags[0].0, &frags[0].1).unwrap();
+        assert!(manager.take_completed().is_empty());
+        assert!(manager.object_data(&first.hash).is_none());
+
+        manager.handle_fragment_data(PEER, frags[1].0, &frags[1].1).unwrap();
+        assert_eq!(manager.take_completed(), vec![first.hash]);
+        assert!(manager.take_completed().is_empty());
+        assert!(manager.is_complete(&first.hash));
+        assert_eq!(manager.object_counts(), (1, 0));
+
+        let data = manager.object_data(&first.hash).unwrap();
+        assert_eq!(&data[..], &[frags[0].1.clone(), frags[1].1.clone()].concat()[..]);
+
+        // An object made of fragments that are all present completes
+        // immediately.
+        let second = manifest(&frags[..1]);
+        manager.handle_object_manifest(second.clone()).unwrap();
+        assert_eq!(manager.take_completed(), vec![second.hash]);
+    }
+
+    #[test]
+    fn reject_corrupt_fragment() {
+        let frags = [fragment(1, 256), fragment(2, 16)];
+        let mut manager = DataManager::new();
+        manager.handle_object_manifest(manifest(&frags)).unwrap();
+
+        let (hash, _) = &frags[1];
+        assert_eq!(
+            manager.handle_fragment_data(PEER, *hash, &[3; 16]),
+            Err(DataError::HashMismatch { hash: *hash, actual: fragment_hash(&[3; 16]), peer: PEER }),
+        );
+        assert!(!manager.has_fragment(hash));
+    }
+
+    #[test]
+    fn reject_invalid_object_hash() {
+        let mut manifest = manifest(&[fragment(1, 16)]);
+        manifest.hash = Hash([1; 32]);
+
+        let mut manager = DataManager::new();
+        assert!(matches!(
+            manager.handle_object_manifest(manifest),
+            Err(DataError::InvalidManifest(_, _)),
+        ));
+    }
+
+    /// A manifest claiming the given size, which may not match the
+    /// fragments.
+    fn with_size(size: usize, fragments: &[Hash]) -> proto::ObjectManifest {
+        let mut manifest = proto::ObjectManifest {
+            hash: Hash([0; 32]),
+            tags: vec![],
+            size,
+            fragment_size: 8,
+            fragments: fragments.iter()
+                .map(|hash| proto::FragmentManifest { hash: *hash })
+                .collect(),
+        };
+        manifest.hash = manifest_hash(&manifest);
+        manifest
+    }
+
+    #[test]
+    fn reject_malformed_manifests() {
+        let invalid = |manager: &mut DataManager, manifest| matches!(
+            manager.handle_object_manifest(manifest),
+            Err(DataError::InvalidManifest(_, _)),
+        );
+
+        // Sizes that would need more fragments than fit in a `usize`.
+        let mut manager = DataManager::new();
+        assert!(invalid(&mut manager, with_size(usize::MAX, &[])));
+        let mut manager = DataManager::new().with_max_object_size(usize::MAX);
+        assert!(invalid(&mut manager, with_size(usize::MAX, &[])));
+
+        // A fragment placed in slots of different lengths.
+        let (frag1, frag2) = (fragment(1, 256), fragment(2, 256));
+        assert!(invalid(&mut manager, with_size(256 + 16, &[frag1.0, frag1.0])));
+
+        // A present fragment placed in a slot of another length.
+        manager.handle_object_manifest(manifest(&[frag1.clone()])).unwrap();
+        manager.handle_fragment_data(PEER, frag1.0, &frag1.1).unwrap();
+        assert!(invalid(&mut manager, with_size(256 + 16, &[frag2.0, frag1.0])));
+
+        // An expecting fragment only accepts data fitting its slots.
+        manager.handle_object_manifest(manifest(&[frag2.clone()])).unwrap();
+        assert_eq!(
+            manager.handle_fragment_data(PEER, frag2.0, &frag2.1[..16]),
+            Err(DataError::InvalidSize { hash: frag2.0, expected: 256, actual: 16 }),
+        );
+        assert_eq!(manager.object_counts(), (1, 1));
+    }
+
+    #[test]
+    fn shared_fragments() {
+        let frags = [fragment(1, 256), fragment(2, 256), fragment(3, 88)];
+        let mut manager = DataManager::new();
+        let first = manifest(&frags);
+        manager.handle_object_manifest(first.clone()).unwrap();
+        manager.handle_fragment_data(PEER, frags[0].0, &frags[0].1).unwrap();
+
+        assert_eq!(
+            manager.handle_object_manifest(first.clone()),
+            Err(DataError::DuplicateObject(first.hash)),
+        );
+
+        // A second object sharing a present fragment gets it filled
+        // immediately.
+        let second = manifest(&frags[..2]);
+        manager.handle_object_manifest(second.clone()).unwrap();
+
+        let buffer = &manager.objects[&second.hash].buffer;
+        assert_eq!(buffer.num_sealed(), 1);
+    }
+
+    #[test]
+    fn expire_object() {
+        let frags = [fragment(1, 256), fragment(2, 256), fragment(3, 88)];
+        let mut manager = DataManager::new();
+        let first = manifest(&frags);
+        let second = manifest(&frags[..2]);
+        manager.handle_object_manifest(first.clone()).unwrap();
+        manager.handle_object_manifest(second.clone()).unwrap();
+        manager.handle_fragment_data(PEER, frags[0].0, &frags[0].1).unwrap();
+
+        manager.expire_object(&first.hash).unwrap();
+        assert_eq!(
+            manager.expire_object(&first.hash),
+            Err(DataError::UnknownObject(first.hash)),
+        );
+        assert_eq!(manager.object_counts(), (0, 1));
+        assert_eq!(manager.memory_usage(), 512);
+
+        // Shared fragments are kept for the second object, the rest dropped.
+        assert_eq!(manager.fragment_data(&frags[0].0), Some(&frags[0].1[..]));
+        assert_eq!(manager.missing_fragments(), vec![frags[1].0]);
+        assert_eq!(
+            manager.handle_fragment_data(PEER, frags[2].0, &frags[2].1),
+            Err(DataError::UnknownFragment(frags[2].0)),
+        );
+
+        manager.expire_object(&second.hash).unwrap();
+        assert_eq!(manager.memory_usage(), 0);
+        assert!(manager.fragments.is_empty());
+    }
+
+    #[test]
+    fn collect_garbage() {
+        let frags = [fragment(1, 256), fragment(2, 256), fragment(3, 256), fragment(4, 256)];
+        let first = manifest(&frags[0..2]);
+        let second = manifest(&frags[1..3]);
+        let third = manifest(&frags[3..4]);
+        let mut manager = DataManager::new();
+        for manifest in [&first, &second, &third].iter() {
+            manager.handle_object_manifest((*manifest).clone()).unwrap();
+        }
+        for (hash, data) in frags[..3].iter() {
+            manager.handle_fragment_data(PEER, *hash, data).unwrap();
+        }
+
+        // The third object is still being fetched, and kept.
+        manager.pin_object(&second.hash).unwrap();
+        assert_eq!(manager.collect_garbage(), vec![first.hash]);
+        assert_eq!(manager.object_counts(), (1, 1));
+        assert!(!manager.has_fragment(&frags[0].0));
+        assert!(manager.has_fragment(&frags[1].0));
+
+        // A pinned fragment outlives its objects.
+        manager.pin_fragment(&frags[2].0).unwrap();
+        manager.unpin_object(&second.hash).unwrap();
+        assert_eq!(manager.collect_garbage(), vec![second.hash]);
+        assert!(!manager.has_fragment(&frags[1].0));
+        assert_eq!(manager.fragment_data(&frags[2].0), Some(&frags[2].1[..]));
+        assert_eq!(manager.memory_usage(), 256 + 256);
+
+        // It can be claimed again by a new object.
+        let fourth = manifest(&frags[2..3]);
+        manager.handle_object_manifest(fourth.clone()).unwrap();
+        assert!(manager.take_completed().contains(&fourth.hash));
+        assert_eq!(manager.memory_usage(), 256 + 256);
+
+        manager.unpin_fragment(&frags[2].0).unwrap();
+        assert_eq!(manager.collect_garbage(), vec![fourth.hash]);
+        assert!(manager.collect_garbage().is_empty());
+        assert_eq!(manager.fragments.len(), 1);
+        assert_eq!(manager.memory_usage(), 256);
+
+        assert_eq!(manager.pin_object(&first.hash), Err(DataError::UnknownObject(first.hash)));
+    }
+
+    #[test]
+    fn evict() {
+        let frags = [fragment(1, 256), fragment(2, 256), fragment(3, 256), fragment(4, 256)];
+        let objects: Vec<_> = frags.iter().map(|frag| manifest(&[frag.clone()])).collect();
+        let mut manager = DataManager::new()
+            .with_memory_budget(Some(512))
+            .with_serve_grace(Duration::from_secs(0));
+
+        for i in 0..2 {
+            manager.handle_object_manifest(objects[i].clone()).unwrap();
+            manager.handle_fragment_data(PEER, frags[i].0, &frags[i].1).unwrap();
+        }
+        assert!(manager.evict().is_empty());
+        manager.object_data(&objects[0].hash).unwrap();
+
+        // The object being fetched is kept, the least recently used one is
+        // evicted.
+        manager.handle_object_manifest(objects[2].clone()).unwrap();
+        assert_eq!(manager.memory_usage(), 768);
+        assert_eq!(manager.evict(), vec![objects[1].hash]);
+        assert_eq!(manager.take_dropped(), vec![frags[1].0]);
+        assert_eq!(manager.memory_usage(), 512);
+
+        // Pinned objects are kept.
+        manager.pin_object(&objects[0].hash).unwrap();
+        manager.handle_fragment_data(PEER, frags[2].0, &frags[2].1).unwrap();
+        manager.handle_object_manifest(objects[3].clone()).unwrap();
+        manager.handle_fragment_data(PEER, frags[3].0, &frags[3].1).unwrap();
+        assert_eq!(manager.evict(), vec![objects[2].hash]);
+
+        // Serving a fragment to a peer counts as a use.
+        manager.unpin_object(&objects[0].hash).unwrap();
+        manager.serve_fragment(PEER, &frags[0].0).unwrap();
+        manager.handle_object_manifest(objects[1].clone()).unwrap();
+        manager.handle_fragment_data(PEER, frags[1].0, &frags[1].1).unwrap();
+        assert_eq!(manager.evict(), vec![objects[3].hash]);
+    }
+
+    #[test]
+    fn evict_after_serve_grace() {
+        let grace = Duration::from_millis(100);
+        let frags = [fragment(1, 256), fragment(2, 256)];
+        let objects: Vec<_> = frags.iter().map(|frag| manifest(&[frag.clone()])).collect();
+        let mut manager = DataManager::new()
+            .with_memory_budget(Some(256))
+            .with_serve_grace(grace);
+
+        for i in 0..2 {
+            manager.handle_object_manifest(objects[i].clone()).unwrap();
+            manager.handle_fragment_data(PEER, frags[i].0, &frags[i].1).unwrap();
+        }
+
+        // Peers were told about the first object, and fetched from the
+        // second, so more requests may be on the way for both.
+        manager.mark_served(&[frags[0].0]);
+        manager.serve_fragment(PEER, &frags[1].0).unwrap();
+        assert!(manager.evict().is_empty());
+
+        std::thread::sleep(grace);
+        assert_eq!(manager.evict(), vec![objects[0].hash]);
+    }
+
+    /// A store shared between every clone, standing in for a directory that
+    /// outlives the `DataManager`.
+    #[derive(Clone, Default)]
+    struct MemStore(Arc<Mutex<HashMap<Hash, Vec<u8>>>>);
+    impl FragmentStore for MemStore {
+        fn put(&mut self, hash: &Hash, data: &[u8]) -> io::Result<()> {
+            self.0.lock().unwrap().insert(*hash, data.to_owned());
+            Ok(())
+        }
+        fn get(&self, hash: &Hash) -> io::Result<Option<Vec<u8>>> {
+            Ok(self.0.lock().unwrap().get(hash).cloned())
+        }
+        fn remove(&mut self, hash: &Hash) -> io::Result<()> {
+            self.0.lock().unwrap().remove(hash);
+            Ok(())
+        }
+        fn list(&self) -> io::Result<Vec<Hash>> {
+            Ok(self.0.lock().unwrap().keys().cloned().collect())
+        }
+    }
+
+    /// Handles every pending store operation, as the fabric would. Returns
+    /// the fragments read for peers.
+    fn settle(manager: &mut DataManager) -> Vec<(Hash, Bytes, Vec<Uuid>)> {
+        let mut served = Vec::new();
+        while let Some(done) = manager.store.as_mut().unwrap().recv_blocking() {
+            served.extend(manager.handle_store_done(done));
+        }
+        served
+    }
+
+    #[test]
+    fn fragment_store() {
+        let frags = [fragment(1, 256), fragment(2, 256), fragment(3, 256)];
+        let first = manifest(&frags[..2]);
+        let store = MemStore::default();
+
+        let mut manager = DataManager::new().with_store(Box::new(store.clone()));
+        manager.handle_object_manifest(first.clone()).unwrap();
+        for (hash, data) in frags[..2].iter() {
+            manager.handle_fragment_data(PEER, *hash, data).unwrap();
+        }
+        settle(&mut manager);
+        assert_eq!(store.0.lock().unwrap().len(), 2);
+
+        // Evicted fragments are still served from the store, once read.
+        manager.collect_garbage();
+        assert!(manager.take_dropped().is_empty());
+        assert_eq!(manager.present_fragments().len(), 2);
+        assert_eq!(manager.serve_fragment(PEER, &frags[0].0), None);
+        assert_eq!(manager.serve_fragment(Uuid::from_u128(2), &frags[0].0), None);
+        assert_eq!(
+            settle(&mut manager),
+            vec![(frags[0].0, Bytes::from(frags[0].1.clone()), vec![PEER, Uuid::from_u128(2)])],
+        );
+
+        // After a restart, objects made of stored fragments complete
+        // without fetching anything.
+        store.0.lock().unwrap().insert(frags[2].0, vec![0; 256]);
+        let mut manager = DataManager::new().with_store(Box::new(store.clone()));
+        let mut stored = manager.stored_fragments();
+        stored.sort();
+        let mut expected = vec![frags[0].0, frags[1].0, frags[2].0];
+        expected.sort();
+        assert_eq!(stored, expected);
+
+        manager.handle_object_manifest(first.clone()).unwrap();
+        assert!(manager.missing_fragments().is_empty());
+        assert!(manager.take_completed().is_empty());
+        assert!(settle(&mut manager).is_empty());
+        assert_eq!(manager.take_completed(), vec![first.hash]);
+
+        // Corrupt fragments are dropped from the store and fetched instead.
+        let second = manifest(&frags[1..]);
+        manager.handle_object_manifest(second.clone()).unwrap();
+        settle(&mut manager);
+        assert_eq!(manager.missing_fragments(), vec![frags[2].0]);
+        assert!(!store.0.lock().unwrap().contains_key(&frags[2].0));
+        manager.handle_fragment_data(PEER, frags[2].0, &frags[2].1).unwrap();
+        settle(&mut manager);
+        assert_eq!(store.0.lock().unwrap()[&frags[2].0], frags[2].1);
+
+        // Expired objects are removed from the store.
+        manager.expire_object(&second.hash).unwrap();
+        settle(&mut manager);
+        assert_eq!(manager.take_dropped(), vec![frags[2].0]);
+        assert_eq!(store.0.lock().unwrap().len(), 2);
+
+        // A stored fragment is not loaded into a slot of another length.
+        let mut manager = DataManager::new().with_store(Box::new(store.clone()));
+        let short = with_size(256 + 16, &[frags[1].0, frags[0].0]);
+        manager.handle_object_manifest(short.clone()).unwrap();
+        settle(&mut manager);
+        assert!(!manager.has_fragment(&frags[0].0));
+        assert_eq!(manager.missing_fragments(), vec![frags[0].0]);
+        assert!(manager.take_completed().is_empty());
+    }
+
+    #[test]
+    fn local_object() {
+        let data = [[1; 256], [2; 256]].concat();
+        let object = ObjectBuilder::new(FragSize(8)).build(&data).unwrap();
+        let hash = object.hash();
+
+        // Another object is already waiting for the second fragment.
+        let frags = [fragment(3, 256), fragment(2, 256)];
+        let waiting = manifest(&frags);
+        let mut manager = DataManager::new();
+        manager.handle_object_manifest(waiting.clone()).unwrap();
+
+        let new_present = manager.insert_local_object(object).unwrap();
+        assert_eq!(new_present, vec![fragment_hash(&[1; 256]), frags[1].0]);
+        assert_eq!(manager.take_completed(), vec![hash]);
+        assert!(manager.has_fragment(&frags[1].0));
+        assert_eq!(manager.objects[&waiting.hash].buffer.num_sealed(), 1);
+        assert_eq!(manager.objects[&hash].buffer.as_ref_full(), Some(&data[..]));
+
+        let object = ObjectBuilder::new(FragSize(8)).build(&data).unwrap();
+        assert_eq!(
+            manager.insert_local_object(object).err(),
+            Some(DataError::DuplicateObject(hash)),
+        );
+    }
+
+    #[test]
+    fn signed_manifests() {
+        use ring::rand::SystemRandom;
+        use ring::signature::{self, KeyPair};
+
+        use crate::peer::key::{KeyRing, KeyCapability};
+        use super::signing::{sign_object_manifest, sign_stream_manifest};
+
+        let rand = SystemRandom::new();
+        let algo = &signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+        let pkcs8 = signature::EcdsaKeyPair::generate_pkcs8(algo, &rand).unwrap();
+        let keypair = signature::EcdsaKeyPair::from_pkcs8(algo, pkcs8.as_ref()).unwrap();
+
+        let stream = Uuid::from_u128(10);
+        let other_stream = Uuid::from_u128(11);
+        let mut keys = KeyRing::new();
+        keys.grant(keypair.public_key().as_ref().to_owned(), KeyCapability::OriginateStream(stream));
+
+        let mut manager = DataManager::new();
+        let first = manifest(&[fragment(1, 256)]);
+
+        // Signed for a stream the key may not originate.
+        let signed = sign_object_manifest(&keypair, &rand, other_stream, first.clone());
+        assert_eq!(
+            manager.handle_signed_object_manifest(&keys, signed),
+            Err(DataError::Unauthorized(other_stream)),
+        );
+
+        // Tampered with after signing.
+        let mut signed = sign_object_manifest(&keypair, &rand, stream, first.clone());
+        signed.manifest.tags.push("injected".to_owned());
+        assert_eq!(
+            manager.handle_signed_object_manifest(&keys, signed),
+            Err(DataError::InvalidSignature(stream)),
+        );
+
+        // Unsigned.
+        let mut signed = sign_object_manifest(&keypair, &rand, stream, first.clone());
+        signed.signature = vec![];
+        assert_eq!(
+            manager.handle_signed_object_manifest(&keys, signed),
+            Err(DataError::InvalidSignature(stream)),
+        );
+        assert!(manager.objects.is_empty());
+
+        let signed = sign_object_manifest(&keypair, &rand, stream, first.clone());
+        manager.handle_signed_object_manifest(&keys, signed).unwrap();
+        assert!(manager.objects.contains_key(&first.hash));
+        assert_eq!(manager.signed_object_manifests().count(), 1);
+
+        // Stream manifests are only accepted if newer.
+        let next = manager.next_stream_manifest(stream, first.hash);
+        assert_eq!(next.sequence, 0);
+        let signed = sign_stream_manifest(&keypair, &rand, next);
+        assert_eq!(manager.handle_signed_stream_manifest(&keys, signed.clone()), Ok(true));
+        assert_eq!(manager.handle_signed_stream_manifest(&keys, signed), Ok(false));
+
+        let next = manager.next_stream_manifest(stream, Hash([1; 32]));
+        assert_eq!(next.sequence, 1);
+        assert_eq!(next.objects, vec![first.hash, Hash([1; 32])]);
+    }
+
+    #[tokio::test]
+    async fn read_partial_object() {
+        use futures::{FutureExt, StreamExt};
+
+        let frags = [fragment(1, 256), fragment(2, 256), fragment(3, 16)];
+        let first = manifest(&frags);
+        let second = manifest(&[frags[1].clone(), fragment(4, 16)]);
+        let mut manager = DataManager::new();
+        manager.handle_object_manifest(first.clone()).unwrap();
+        manager.handle_object_manifest(second.clone()).unwrap();
+        assert!(manager.open_object(&Hash([1; 32])).is_err());
+
+        let mut reader = manager.open_object(&first.hash).unwrap();
+        let mut stream = manager.open_object(&first.hash).unwrap().into_stream();
+        assert_eq!(reader.size(), 528);
+        assert_eq!(
+            reader.read_range(500..600).await,
+            Err(DataError::InvalidRange { hash: first.hash, start: 500, end: 600 }),
+        );
+        assert!(reader.read_range(300..400).now_or_never().is_none());
+        assert!(stream.next().now_or_never().is_none());
+
+        // A range resolves once its fragments are sealed, even though the
+        // prefix before it is missing.
+        manager.handle_fragment_data(PEER, frags[1].0, &frags[1].1).unwrap();
+        assert_eq!(&reader.read_range(300..400).await.unwrap()[..], &[2; 100][..]);
+        assert!(stream.next().now_or_never().is_none());
+
+        manager.handle_fragment_data(PEER, frags[0].0, &frags[0].1).unwrap();
+        assert_eq!(stream.next().await.unwrap().len(), 512);
+        manager.handle_fragment_data(PEER, frags[2].0, &frags[2].1).unwrap();
+        assert_eq!(&stream.next().await.unwrap()[..], &frags[2].1[..]);
+        assert!(stream.next().await.is_none());
+
+        // Waiting reads fail when the object is expired.
+        let mut reader = manager.open_object(&second.hash).unwrap();
+        let mut stream = manager.open_object(&second.hash).unwrap().into_stream();
+        assert_eq!(stream.next().await.unwrap().len(), 256);
+        manager.expire_object(&second.hash).unwrap();
+        assert_eq!(
+            reader.read_range(256..272).await,
+            Err(DataError::UnknownObject(second.hash)),
+        );
+        assert!(stream.next().await.is_none());
     }
 
 }
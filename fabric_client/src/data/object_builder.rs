@@ -0,0 +1,152 @@
+//! Creation of objects from local data.
+//!
+//! An `ObjectBuilder` splits data into fragments of a `RootBuffer`, seals them
+//! and computes the fragment and object hashes. The result is a `LocalObject`,
+//! which is ready to be inserted into a `DataManager` and carries the
+//! `ObjectManifest` other nodes need to fetch it.
+
+use livecore_protocol as proto;
+use proto::{Hash, Uuid};
+
+use super::DataError;
+use super::fragment_buffer::{FragSize, FragmentBuffer, RootBuffer};
+use super::hash;
+
+pub struct ObjectBuilder {
+    fragment_size: FragSize,
+    tags: Vec<String>,
+    stream: Option<Uuid>,
+}
+
+impl ObjectBuilder {
+
+    pub fn new(fragment_size: FragSize) -> Self {
+        Self {
+            fragment_size,
+            tags: Vec::new(),
+            stream: None,
+        }
+    }
+
+    pub fn with_tag(mut self, tag: String) -> Self {
+        self.tags.push(tag);
+        self
+    }
+    pub fn with_tags(mut self, tags: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        for tag in tags {
+            self.tags.push(tag.into());
+        }
+        self
+    }
+
+    /// Adds the object to a stream originated by this node. Its manifest is
+    /// signed and sent to peers when it is published.
+    pub fn with_stream(mut self, stream_uuid: Uuid) -> Self {
+        self.stream = Some(stream_uuid);
+        self
+    }
+
+    pub fn build(self, data: &[u8]) -> Result<LocalObject, DataError> {
+        if !super::valid_fragment_size(self.fragment_size) {
+            return Err(DataError::InvalidObject("invalid fragment size"));
+        }
+        if data.is_empty() {
+            return Err(DataError::InvalidObject("object is empty"));
+        }
+
+        let root = RootBuffer::new(data.len(), self.fragment_size);
+        let frag_size = self.fragment_size.size();
+
+        let fragments: Vec<FragmentBuffer> = data.chunks(frag_size)
+            .enumerate()
+            .map(|(idx, chunk)| {
+                let mut frag_buf = root.claim(idx).unwrap();
+                frag_buf.fill(chunk);
+                frag_buf.seal();
+                frag_buf
+            })
+            .collect();
+
+        let fragment_hashes: Vec<Hash> = fragments.iter()
+            .map(|frag_buf| hash::fragment_hash(frag_buf.as_ref()))
+            .collect();
+
+        let manifest = proto::ObjectManifest {
+            hash: hash::object_hash(data.len(), self.fragment_size.0, &fragment_hashes),
+            tags: self.tags,
+            size: data.len(),
+            fragment_size: self.fragment_size.0,
+            fragments: fragment_hashes.iter()
+                .map(|hash| proto::FragmentManifest { hash: *hash })
+                .collect(),
+        };
+
+        Ok(LocalObject {
+            manifest,
+            buffer: root,
+            fragments,
+            stream: self.stream,
+        })
+    }
+
+}
+
+/// An object created on this node, with all fragments present and sealed.
+pub struct LocalObject {
+    pub(crate) manifest: proto::ObjectManifest,
+    pub(crate) buffer: RootBuffer,
+    pub(crate) fragments: Vec<FragmentBuffer>,
+    pub(crate) stream: Option<Uuid>,
+}
+
+impl LocalObject {
+
+    pub fn hash(&self) -> Hash {
+        self.manifest.hash
+    }
+
+    pub fn manifest(&self) -> &proto::ObjectManifest {
+        &self.manifest
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ObjectBuilder;
+    use super::super::DataError;
+    use super::super::fragment_buffer::FragSize;
+    use super::super::hash::{fragment_hash, manifest_hash};
+
+    #[test]
+    fn build_object() {
+        let data: Vec<u8> = (0..1000).map(|n| n as u8).collect();
+
+        let object = ObjectBuilder::new(FragSize(8))
+            .with_tag("segment".to_owned())
+            .build(&data)
+            .unwrap();
+
+        let manifest = object.manifest();
+        assert_eq!(manifest.size, 1000);
+        assert_eq!(manifest.tags, vec!["segment".to_owned()]);
+        assert_eq!(manifest.fragments.len(), 4);
+        assert_eq!(manifest.fragments[3].hash, fragment_hash(&data[768..]));
+        assert_eq!(manifest_hash(manifest), object.hash());
+
+        assert_eq!(object.buffer.as_ref_full(), Some(&data[..]));
+    }
+
+    #[test]
+    fn reject_invalid() {
+        assert_eq!(
+            ObjectBuilder::new(FragSize(8)).build(&[]).err(),
+            Some(DataError::InvalidObject("object is empty")),
+        );
+        assert_eq!(
+            ObjectBuilder::new(FragSize(0)).build(&[1]).err(),
+            Some(DataError::InvalidObject("invalid fragment size")),
+        );
+    }
+
+}
@@ -0,0 +1,91 @@
+//! Reads of objects that are still being received.
+//!
+//! An `ObjectReader` shares the buffer of an object, and is woken through a
+//! watch channel whenever fragments of the object are sealed. Sealed
+//! fragments never change, so they are read without going through the
+//! fabric task.
+
+use std::ops::Range;
+
+use bytes::Bytes;
+use futures::Stream;
+use tokio::sync::watch;
+
+use livecore_protocol::Hash;
+
+use super::DataError;
+use super::fragment_buffer::RootBuffer;
+
+/// Reads the data of an object as its fragments arrive.
+///
+/// The reader keeps the object buffer alive, even if the object is evicted.
+/// If the object is expired before it completes, waiting reads fail with
+/// `DataError::UnknownObject`.
+pub struct ObjectReader {
+    hash: Hash,
+    buffer: RootBuffer,
+    progress: watch::Receiver<()>,
+}
+impl ObjectReader {
+    pub(crate) fn new(hash: Hash, buffer: RootBuffer, progress: watch::Receiver<()>) -> Self {
+        ObjectReader {
+            hash,
+            buffer,
+            progress,
+        }
+    }
+
+    pub fn hash(&self) -> Hash {
+        self.hash
+    }
+
+    /// Size of the object in bytes.
+    pub fn size(&self) -> usize {
+        self.buffer.size()
+    }
+
+    /// Waits until fragments of the object may have been sealed. Fails once
+    /// the object is gone, and can not make any more progress.
+    async fn changed(&mut self) -> Result<(), DataError> {
+        self.progress.changed().await
+            .map_err(|_| DataError::UnknownObject(self.hash))
+    }
+
+    /// Returns the given byte range of the object, once every fragment
+    /// covering it is sealed.
+    pub async fn read_range(&mut self, range: Range<usize>) -> Result<Bytes, DataError> {
+        if range.start > range.end || range.end > self.size() {
+            return Err(DataError::InvalidRange {
+                hash: self.hash,
+                start: range.start,
+                end: range.end,
+            });
+        }
+        loop {
+            if let Some(data) = self.buffer.range(range.clone()) {
+                return Ok(Bytes::copy_from_slice(data));
+            }
+            self.changed().await?;
+        }
+    }
+
+    /// Yields the data of the object from the start, as a new chunk whenever
+    /// the sealed prefix of the object grows. Ends once the whole object has
+    /// been yielded, or early if the object is expired.
+    pub fn into_stream(self) -> impl Stream<Item = Bytes> + Unpin {
+        Box::pin(futures::stream::unfold((self, 0), |(mut reader, offset)| async move {
+            loop {
+                if offset == reader.size() {
+                    return None;
+                }
+                let prefix = reader.buffer.sealed_prefix();
+                if prefix.len() > offset {
+                    let chunk = Bytes::copy_from_slice(&prefix[offset..]);
+                    let end = prefix.len();
+                    return Some((chunk, (reader, end)));
+                }
+                reader.changed().await.ok()?;
+            }
+        }))
+    }
+}
@@ -0,0 +1,73 @@
+//! Signatures over manifests sent between peers.
+//!
+//! Stream and object manifests received over peer links are only trusted if
+//! they are signed by a key allowed to originate the stream. The signatures
+//! are `ECDSA_P256_SHA256_FIXED`, made with the node keypair, over:
+//!
+//! * Stream manifests: `STREAM_MANIFEST_PREFIX`, the stream UUID, the
+//!   sequence as u64 LE, then the object hashes in order.
+//! * Object manifests: `OBJECT_MANIFEST_PREFIX`, the stream UUID, the object
+//!   hash, then every tag as its length as u32 LE followed by its bytes.
+//!
+//! The object hash covers the layout and fragments of the object, so only
+//! the tags need to be signed separately.
+
+use ring::rand::SecureRandom;
+use ring::signature::{self, KeyPair};
+
+use livecore_protocol as proto;
+use proto::Uuid;
+
+const STREAM_MANIFEST_PREFIX: &[u8] = b"__LIVECORE_STREAM_MANIFEST__";
+const OBJECT_MANIFEST_PREFIX: &[u8] = b"__LIVECORE_OBJECT_MANIFEST__";
+
+pub fn stream_manifest_payload(manifest: &proto::StreamManifest) -> Vec<u8> {
+    let mut payload = Vec::new();
+    payload.extend(STREAM_MANIFEST_PREFIX);
+    payload.extend(manifest.stream_uuid.as_bytes());
+    payload.extend(&manifest.sequence.to_le_bytes());
+    for hash in manifest.objects.iter() {
+        payload.extend(&hash.0);
+    }
+    payload
+}
+
+pub fn object_manifest_payload(stream_uuid: &Uuid, manifest: &proto::ObjectManifest) -> Vec<u8> {
+    let mut payload = Vec::new();
+    payload.extend(OBJECT_MANIFEST_PREFIX);
+    payload.extend(stream_uuid.as_bytes());
+    payload.extend(&manifest.hash.0);
+    for tag in manifest.tags.iter() {
+        payload.extend(&(tag.len() as u32).to_le_bytes());
+        payload.extend(tag.as_bytes());
+    }
+    payload
+}
+
+pub fn sign_stream_manifest(
+    keypair: &signature::EcdsaKeyPair,
+    rand: &dyn SecureRandom,
+    manifest: proto::StreamManifest,
+) -> proto::SignedStreamManifest {
+    let signature = keypair.sign(rand, &stream_manifest_payload(&manifest)).unwrap();
+    proto::SignedStreamManifest {
+        manifest,
+        pubkey: keypair.public_key().as_ref().to_owned(),
+        signature: signature.as_ref().to_owned(),
+    }
+}
+
+pub fn sign_object_manifest(
+    keypair: &signature::EcdsaKeyPair,
+    rand: &dyn SecureRandom,
+    stream_uuid: Uuid,
+    manifest: proto::ObjectManifest,
+) -> proto::SignedObjectManifest {
+    let signature = keypair.sign(rand, &object_manifest_payload(&stream_uuid, &manifest)).unwrap();
+    proto::SignedObjectManifest {
+        stream_uuid,
+        manifest,
+        pubkey: keypair.public_key().as_ref().to_owned(),
+        signature: signature.as_ref().to_owned(),
+    }
+}
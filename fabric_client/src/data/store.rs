@@ -0,0 +1,225 @@
+//! Persistent storage for fragment data.
+//!
+//! Fragments are held in memory while objects use them. A `FragmentStore`
+//! additionally keeps a copy of every present fragment, which outlives
+//! eviction and restarts. When a manifest is received for an object with
+//! stored fragments, they are loaded from the store instead of fetched from
+//! peers, and stored fragments are served to peers directly.
+//!
+//! Store operations block, and fragments may be large, so the store is run
+//! on its own thread by a `StoreThread`. Results are sent back as `StoreDone`
+//! and handled by `DataManager::handle_store_done`.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc as std_mpsc;
+
+use tokio::sync::mpsc;
+
+use livecore_protocol::Hash;
+
+use super::hash;
+
+/// Storage backend for fragments, keyed by their hash.
+pub trait FragmentStore: Send {
+    /// Stores the data of a sealed fragment, replacing any stored data.
+    fn put(&mut self, hash: &Hash, data: &[u8]) -> io::Result<()>;
+    /// Reads a stored fragment, `None` if it is not stored.
+    fn get(&self, hash: &Hash) -> io::Result<Option<Vec<u8>>>;
+    /// Removes a stored fragment. Removing a fragment that is not stored
+    /// does nothing.
+    fn remove(&mut self, hash: &Hash) -> io::Result<()>;
+    /// Lists every stored fragment, used to rebuild the index on startup.
+    fn list(&self) -> io::Result<Vec<Hash>>;
+}
+
+/// Stores each fragment in its own file in a directory, named by the hex
+/// encoded hash.
+pub struct FileStore {
+    dir: PathBuf,
+}
+impl FileStore {
+    /// Opens the store in `dir`, creating the directory if needed.
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(FileStore { dir })
+    }
+
+    fn path(&self, hash: &Hash) -> PathBuf {
+        self.dir.join(hash.to_string())
+    }
+}
+impl FragmentStore for FileStore {
+    fn put(&mut self, hash: &Hash, data: &[u8]) -> io::Result<()> {
+        // Written under a temporary name first, so that a crash never leaves
+        // a partial fragment under its hash.
+        let tmp_path = self.path(hash).with_extension("tmp");
+        fs::write(&tmp_path, data)?;
+        fs::rename(&tmp_path, self.path(hash))
+    }
+
+    fn get(&self, hash: &Hash) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.path(hash)) {
+            Ok(data) => Ok(Some(data)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn remove(&mut self, hash: &Hash) -> io::Result<()> {
+        match fs::remove_file(self.path(hash)) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+            _ => Ok(()),
+        }
+    }
+
+    fn list(&self) -> io::Result<Vec<Hash>> {
+        let mut hashes = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            // Leftover temporary files and anything else are skipped.
+            if let Some(hash) = name.to_str().and_then(Hash::parse_str) {
+                hashes.push(hash);
+            }
+        }
+        Ok(hashes)
+    }
+}
+
+enum StoreOp {
+    Put(Hash, Vec<u8>),
+    Get(Hash),
+    Remove(Hash),
+}
+
+/// Result of an operation run on the store thread.
+#[derive(Debug)]
+pub(crate) enum StoreDone {
+    Put(Hash, io::Result<()>),
+    /// Stored data that does not match its hash is read as `None`.
+    Get(Hash, io::Result<Option<Vec<u8>>>),
+    Remove(Hash, io::Result<()>),
+}
+
+/// Runs a `FragmentStore` on a dedicated thread. Operations are run in the
+/// order they are sent, so a read after a write sees the written data.
+///
+/// The thread exits once the `StoreThread` is dropped.
+pub(crate) struct StoreThread {
+    ops: std_mpsc::Sender<StoreOp>,
+    done: mpsc::UnboundedReceiver<StoreDone>,
+    /// Operations sent that have not been received back yet.
+    pending: usize,
+}
+impl StoreThread {
+    pub fn spawn(mut store: Box<dyn FragmentStore>) -> Self {
+        let (ops, op_receiver) = std_mpsc::channel();
+        let (done_sender, done) = mpsc::unbounded_channel();
+
+        std::thread::spawn(move || {
+            for op in op_receiver {
+                let done = match op {
+                    StoreOp::Put(hash, data) => StoreDone::Put(hash, store.put(&hash, &data)),
+                    StoreOp::Get(hash) => {
+                        let res = store.get(&hash).map(|data| {
+                            data.filter(|data| {
+                                let valid = hash::fragment_hash(data) == hash;
+                                if !valid {
+                                    log::warn!("stored fragment {} is corrupt", hash);
+                                }
+                                valid
+                            })
+                        });
+                        StoreDone::Get(hash, res)
+                    },
+                    StoreOp::Remove(hash) => StoreDone::Remove(hash, store.remove(&hash)),
+                };
+                if done_sender.send(done).is_err() {
+                    break;
+                }
+            }
+        });
+
+        StoreThread {
+            ops,
+            done,
+            pending: 0,
+        }
+    }
+
+    fn send(&mut self, op: StoreOp) {
+        // The thread only exits once we are dropped.
+        self.ops.send(op).unwrap();
+        self.pending += 1;
+    }
+
+    pub fn put(&mut self, hash: Hash, data: Vec<u8>) {
+        self.send(StoreOp::Put(hash, data));
+    }
+
+    pub fn get(&mut self, hash: Hash) {
+        self.send(StoreOp::Get(hash));
+    }
+
+    pub fn remove(&mut self, hash: Hash) {
+        self.send(StoreOp::Remove(hash));
+    }
+
+    /// Waits for the next finished operation.
+    pub async fn recv(&mut self) -> Option<StoreDone> {
+        let done = self.done.recv().await?;
+        self.pending -= 1;
+        Some(done)
+    }
+
+    /// Blocks until the next finished operation, `None` if nothing is
+    /// pending.
+    #[cfg(test)]
+    pub fn recv_blocking(&mut self) -> Option<StoreDone> {
+        if self.pending == 0 {
+            return None;
+        }
+        let done = self.done.blocking_recv()?;
+        self.pending -= 1;
+        Some(done)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use livecore_protocol::Hash;
+
+    use super::{FileStore, FragmentStore};
+
+    #[test]
+    fn file_store() {
+        let dir = std::env::temp_dir().join(format!("livecore_file_store_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut store = FileStore::open(&dir).unwrap();
+        let (h1, h2) = (Hash([1; 32]), Hash([2; 32]));
+        store.put(&h1, &[1, 2, 3]).unwrap();
+        store.put(&h2, &[4, 5]).unwrap();
+        fs::write(dir.join("partial.tmp"), &[6]).unwrap();
+
+        assert_eq!(store.get(&h1).unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(store.get(&Hash([3; 32])).unwrap(), None);
+
+        // A reopened store finds the same fragments.
+        let mut store = FileStore::open(&dir).unwrap();
+        let mut hashes = store.list().unwrap();
+        hashes.sort();
+        assert_eq!(hashes, vec![h1, h2]);
+
+        store.remove(&h1).unwrap();
+        store.remove(&h1).unwrap();
+        assert_eq!(store.get(&h1).unwrap(), None);
+        assert_eq!(store.list().unwrap(), vec![h2]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
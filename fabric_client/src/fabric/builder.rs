@@ -1,19 +1,34 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
 use ring::rand::SecureRandom;
 use ring::signature;
 
 use tokio::sync::mpsc;
+use tokio::time::Instant;
 
-use crate::data::DataManager;
+use livecore_protocol as proto;
+
+use crate::data::{DataManager, DEFAULT_MAX_OBJECT_SIZE, DEFAULT_SERVE_GRACE};
+use crate::data::store::FragmentStore;
 use crate::platform::PeerConnectionManager;
-use super::{Fabric, FabricProtoState};
+use crate::peer::key::KeyRing;
+use super::{Fabric, FabricProtoState, EventSubscribers};
+use super::heartbeat::Heartbeat;
 use super::packet_sender::OrchPacketSender;
 use super::state::FabricState;
 
 pub struct FabricBuilder {
     auth_token: Option<String>,
     node_classes: Vec<String>,
+    encodings: Vec<proto::Encoding>,
+    encrypted_links: bool,
+    orch_heartbeat: Heartbeat,
+    peer_heartbeat: Heartbeat,
+    memory_budget: Option<usize>,
+    serve_grace: Duration,
+    max_object_size: usize,
+    fragment_store: Option<Box<dyn FragmentStore>>,
     rand: Option<Box<dyn SecureRandom + Send>>,
     keypair: Option<signature::EcdsaKeyPair>,
 }
@@ -22,6 +37,14 @@ impl FabricBuilder {
         Self {
             auth_token: None,
             node_classes: Vec::new(),
+            encodings: vec![proto::Encoding::Cbor],
+            encrypted_links: true,
+            orch_heartbeat: Heartbeat::ORCHESTRATOR,
+            peer_heartbeat: Heartbeat::PEER,
+            memory_budget: None,
+            serve_grace: DEFAULT_SERVE_GRACE,
+            max_object_size: DEFAULT_MAX_OBJECT_SIZE,
+            fragment_store: None,
             rand: None,
             keypair: None,
         }
@@ -43,6 +66,69 @@ impl FabricBuilder {
         self
     }
 
+    /// Sets the encodings offered to the orchestrator in addition to JSON.
+    /// Defaults to CBOR, an empty list keeps the connection on JSON.
+    pub fn with_encodings(mut self, encodings: Vec<proto::Encoding>) -> Self {
+        self.encodings = encodings;
+        self
+    }
+
+    /// Sets whether the node offers encrypted peer links to the
+    /// orchestrator. Defaults to true. Links are still encrypted if the
+    /// orchestrator asks for it anyway.
+    pub fn with_encrypted_links(mut self, encrypted_links: bool) -> Self {
+        self.encrypted_links = encrypted_links;
+        self
+    }
+
+    /// Sets how often the orchestrator is pinged, and how long it may stay
+    /// silent before `FabricEvent::OrchestratorTimeout` is emitted.
+    /// Defaults to 10 and 30 seconds.
+    pub fn with_orchestrator_heartbeat(mut self, interval: Duration, idle_timeout: Duration) -> Self {
+        self.orch_heartbeat = Heartbeat { interval, idle_timeout };
+        self
+    }
+
+    /// Sets how often peers are pinged, and how long they may stay silent
+    /// before the link is closed. Defaults to 5 and 15 seconds.
+    pub fn with_peer_heartbeat(mut self, interval: Duration, idle_timeout: Duration) -> Self {
+        self.peer_heartbeat = Heartbeat { interval, idle_timeout };
+        self
+    }
+
+    /// Limits the bytes of object data held. When exceeded, the least
+    /// recently used objects are evicted, unless they are pinned, still
+    /// being fetched, or were announced or served to peers within the
+    /// serve grace period. Unlimited by default.
+    pub fn with_memory_budget(mut self, bytes: usize) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Sets how long objects announced or served to peers are kept from
+    /// eviction, as more requests for them may be on the way. Defaults to
+    /// 10 seconds.
+    pub fn with_serve_grace(mut self, serve_grace: Duration) -> Self {
+        self.serve_grace = serve_grace;
+        self
+    }
+
+    /// Sets the largest object accepted from the orchestrator or peers, as
+    /// memory for the whole object is allocated when its manifest is
+    /// received. Defaults to 1 GiB.
+    pub fn with_max_object_size(mut self, bytes: usize) -> Self {
+        self.max_object_size = bytes;
+        self
+    }
+
+    /// Keeps a copy of every fragment in a persistent store. Fragments
+    /// already in the store when the fabric starts are advertised to the
+    /// orchestrator after the handshake.
+    pub fn with_fragment_store(mut self, store: Box<dyn FragmentStore>) -> Self {
+        self.fragment_store = Some(store);
+        self
+    }
+
     pub fn with_random(mut self, rand: Box<dyn SecureRandom + Send>) -> Self {
         self.rand = Some(rand);
         self
@@ -59,6 +145,7 @@ impl FabricBuilder {
         peer_connector: Box<dyn PeerConnectionManager + Send>
     ) -> Fabric {
         let (recv_sender, recv_receiver) = mpsc::channel(3);
+        let (cmd_sender, cmd_receiver) = mpsc::channel(3);
 
         let rand = self.rand.unwrap_or_else(|| {
             Box::new(ring::rand::SystemRandom::new())
@@ -74,28 +161,56 @@ impl FabricBuilder {
 
         let (peer_receiver_sender, peer_receiver) = mpsc::channel(3);
 
+        let mut data_manager = DataManager::new()
+            .with_memory_budget(self.memory_budget)
+            .with_serve_grace(self.serve_grace)
+            .with_max_object_size(self.max_object_size);
+        if let Some(store) = self.fragment_store {
+            data_manager = data_manager.with_store(store);
+        }
+
+        let events = EventSubscribers::new();
+
         let mut fabric_state = FabricState {
             proto_state: FabricProtoState::Handshake1,
 
             sender,
             receiver: recv_receiver,
+            cmd_receiver,
 
             peer_connector,
             peer_receiver,
             peer_receiver_sender,
 
             peers: HashMap::new(),
-            data_manager: DataManager::new(),
+            connecting: HashSet::new(),
+            keys: KeyRing::new(),
+            data_manager,
+            object_waiters: HashMap::new(),
+            subscriptions: HashMap::new(),
+
+            events: events.clone(),
 
             node_classes: self.node_classes,
+            encodings: self.encodings,
+            encrypted_links: self.encrypted_links,
             auth_token: self.auth_token,
 
             uuid: None,
+            protocol_version: None,
+            resumption_token: None,
             keypair,
 
             orch_challenge: None,
             orch_pubkey: None,
 
+            orch_heartbeat: self.orch_heartbeat,
+            peer_heartbeat: self.peer_heartbeat,
+            epoch: Instant::now(),
+            last_orch_packet: Instant::now(),
+            orch_rtt: None,
+            stats_interval: None,
+
             rand,
         };
 
@@ -106,6 +221,8 @@ impl FabricBuilder {
 
         Fabric {
             fabric_packet_in: recv_sender,
+            cmd_in: cmd_sender,
+            events,
         }
     }
 }
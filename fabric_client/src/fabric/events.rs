@@ -0,0 +1,100 @@
+//! Lifecycle events for applications embedding a `Fabric`.
+
+use std::sync::{Arc, Mutex};
+
+use futures::channel::mpsc;
+
+use livecore_protocol as proto;
+use proto::{Hash, Uuid};
+
+use super::HandshakeError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FabricEvent {
+    /// The handshake with the orchestrator completed.
+    /// `resumed` is set if this was a reconnect, and the orchestrator
+    /// resumed the previous session.
+    HandshakeComplete {
+        uuid: Uuid,
+        orch_pubkey: Vec<u8>,
+        /// The protocol version picked by the orchestrator.
+        version: proto::ProtocolVersion,
+        resumed: bool,
+    },
+    /// The handshake with the orchestrator failed. The fabric does nothing
+    /// until it is given a new connection with `Fabric::reconnect`.
+    HandshakeFailed {
+        error: HandshakeError,
+    },
+    /// Nothing was received from the orchestrator within the idle timeout.
+    /// The connection should be replaced with `Fabric::reconnect`.
+    OrchestratorTimeout,
+
+    PeerConnected {
+        peer_uuid: Uuid,
+    },
+    PeerConnectFailed {
+        peer_uuid: Uuid,
+        reason: String,
+    },
+    PeerDisconnected {
+        peer_uuid: Uuid,
+        reason: String,
+    },
+
+    /// An object manifest was received from the orchestrator or a peer, and
+    /// its fragments will be fetched.
+    ObjectManifest {
+        hash: Hash,
+        tags: Vec<String>,
+    },
+    /// All the data of an object is present.
+    ObjectComplete {
+        hash: Hash,
+        tags: Vec<String>,
+    },
+    /// An object was expired by the orchestrator, and its data released.
+    ObjectExpired {
+        hash: Hash,
+    },
+    /// An object that was not pinned was freed, to stay within the memory
+    /// budget or by `Fabric::collect_garbage`.
+    ObjectEvicted {
+        hash: Hash,
+    },
+    /// A newer manifest for a stream was published by this node, or accepted
+    /// from a peer.
+    StreamUpdated {
+        stream_uuid: Uuid,
+        objects: Vec<Hash>,
+    },
+}
+
+/// Set of event streams handed out by `Fabric::events`.
+///
+/// Shared between the `Fabric` handle and `FabricState`, so that subscribing
+/// does not need to go through the state task.
+#[derive(Clone, Default)]
+pub(crate) struct EventSubscribers(Arc<Mutex<Vec<mpsc::UnboundedSender<FabricEvent>>>>);
+
+impl EventSubscribers {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<FabricEvent> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.0.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Sends an event to every subscriber, dropping those that have gone
+    /// away.
+    pub fn emit(&self, event: FabricEvent) {
+        log::trace!("fabric event: {:?}", event);
+        let mut subscribers = self.0.lock().unwrap();
+        subscribers.retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+    }
+
+}
@@ -0,0 +1,341 @@
+//! Handshake with the orchestrator for `FabricState`.
+//!
+//! 1. The client sends a `ClientHandshake` with its pubkey and a challenge.
+//! 2. The server responds with a `ServerHandshake`, containing the signed
+//!    challenge response and a challenge for the client.
+//! 3. The client validates the response, and answers with a
+//!    `ClientHandshakeFinish` containing its own signed challenge response.
+//!
+//! The server may instead refuse the node with a `HandshakeRejected`.
+//!
+//! If the orchestrator rejects the node or fails to prove its identity, the
+//! fabric enters the `Failed` state until it is given a new connection.
+
+use std::time::Duration;
+
+use ring::signature::{self, KeyPair};
+
+use livecore_protocol as proto;
+use proto::{HANDSHAKE_CHALLENGE_WRAP, CHALLENGE_RESPONSE_LEN};
+
+use super::{FabricEvent, FabricProtoState};
+use super::packet_sender::OrchPacketSender;
+use super::state::FabricState;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeError {
+    /// A `ServerHandshake` was received while no handshake was in progress.
+    Unexpected,
+    /// The orchestrator picked a protocol version we don't support.
+    UnsupportedVersion(proto::ProtocolVersion),
+    /// The orchestrator picked an encoding we did not offer.
+    UnsupportedEncoding(proto::Encoding),
+    /// The challenge response from the orchestrator has the wrong length.
+    InvalidResponseLength {
+        expected: usize,
+        actual: usize,
+    },
+    /// The challenge response from the orchestrator is not in the expected
+    /// format, or does not contain the challenge we sent.
+    ChallengeMismatch,
+    /// The challenge response is not signed by the orchestrator pubkey.
+    InvalidSignature,
+    /// The orchestrator refused the node.
+    Rejected {
+        reason: proto::HandshakeRejectReason,
+        message: Option<String>,
+        /// How long to wait before trying again, if at all.
+        retry_after: Option<Duration>,
+    },
+}
+impl std::fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Unexpected => write!(f, "unexpected server handshake"),
+            Self::UnsupportedVersion(version) => write!(f, "unsupported protocol version {}", version),
+            Self::UnsupportedEncoding(encoding) => write!(f, "unsupported encoding {:?}", encoding),
+            Self::InvalidResponseLength { expected, actual } => write!(
+                f, "invalid challenge response length (expected {}, got {})", expected, actual),
+            Self::ChallengeMismatch => write!(f, "challenge response does not match challenge"),
+            Self::InvalidSignature => write!(f, "invalid challenge response signature"),
+            Self::Rejected { reason, message, .. } => {
+                write!(f, "rejected by orchestrator: {:?}", reason)?;
+                if let Some(message) = message {
+                    write!(f, " ({})", message)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+impl std::error::Error for HandshakeError {}
+
+/// Validates a challenge response signed by `pubkey`.
+fn verify_challenge_response(
+    pubkey: &signature::UnparsedPublicKey<Vec<u8>>,
+    challenge: &[u8; 32],
+    response: &proto::ChallengeResponse,
+) -> Result<(), HandshakeError> {
+    let data = &response.challenge_response;
+
+    if data.len() != CHALLENGE_RESPONSE_LEN {
+        return Err(HandshakeError::InvalidResponseLength {
+            expected: CHALLENGE_RESPONSE_LEN,
+            actual: data.len(),
+        });
+    }
+
+    let wrap = HANDSHAKE_CHALLENGE_WRAP.as_bytes();
+    let challenge_range = wrap.len()..(wrap.len() + 32);
+    if !data.starts_with(wrap) || !data.ends_with(wrap) || &data[challenge_range] != &challenge[..] {
+        return Err(HandshakeError::ChallengeMismatch);
+    }
+
+    pubkey.verify(data, &response.signature)
+        .map_err(|_| HandshakeError::InvalidSignature)
+}
+
+impl FabricState {
+
+    pub fn start_handshake(&mut self) {
+        assert_eq!(self.proto_state, FabricProtoState::Handshake1);
+
+        log::info!("fabric: starting handshake...");
+
+        let challenge = {
+            let mut data = [0; 32];
+            self.rand.fill(&mut data).unwrap();
+            data
+        };
+
+        self.orch_challenge = Some(challenge);
+
+        self.sender.send(proto::ClientHandshake {
+            version: proto::ProtocolVersion::MIN_SUPPORTED,
+            max_version: Some(proto::ProtocolVersion::CURRENT),
+            encodings: self.encodings.clone(),
+            node_classes: self.node_classes.clone(),
+            peer_connection_capabilities: self.peer_connection_capabilities(),
+            token: self.auth_token.clone(),
+            pubkey: self.keypair.public_key().as_ref().to_owned(),
+            challenge: proto::Challenge {
+                challenge,
+            },
+            resumption_token: self.resumption_token.clone(),
+        });
+        self.transition(FabricProtoState::Handshake2)
+    }
+
+    fn peer_connection_capabilities(&self) -> Vec<proto::PeerConnectionType> {
+        let mut capabilities = vec![
+            proto::PeerConnectionType::WebsocketClient,
+            proto::PeerConnectionType::WebsocketServer,
+        ];
+        if self.encrypted_links {
+            capabilities.push(proto::PeerConnectionType::Encrypted);
+        }
+        capabilities
+    }
+
+    /// Starts over with a new connection to the orchestrator.
+    ///
+    /// Peer links and data are kept until the handshake completes. If the
+    /// orchestrator does not resume the session, the peer links are dropped.
+    pub(crate) fn handle_reconnect(&mut self, sender: OrchPacketSender) {
+        log::info!("fabric: reconnecting to orchestrator");
+        self.sender = sender;
+        self.orch_challenge = None;
+        self.protocol_version = None;
+        self.last_orch_packet = tokio::time::Instant::now();
+        self.orch_rtt = None;
+        self.stats_interval = None;
+        self.transition(FabricProtoState::Handshake1);
+        self.start_handshake();
+    }
+
+    pub(crate) fn handle_server_handshake(&mut self, msg: proto::ServerHandshake) {
+        if let Err(error) = self.do_server_handshake(msg) {
+            self.sender.send(proto::ClientError {
+                fatal: true,
+                reason: format!("handshake failed: {}", error),
+            });
+            self.fail_handshake(error);
+        }
+    }
+
+    pub(crate) fn handle_handshake_rejected(&mut self, msg: proto::HandshakeRejected) {
+        if self.proto_state != FabricProtoState::Handshake2 {
+            log::warn!("fabric: ignoring unexpected handshake rejection: {:?}", msg);
+            return;
+        }
+
+        self.orch_challenge = None;
+        self.fail_handshake(HandshakeError::Rejected {
+            reason: msg.reason,
+            message: msg.message,
+            retry_after: msg.retry_after.map(|secs| Duration::from_secs(secs.into())),
+        });
+    }
+
+    fn fail_handshake(&mut self, error: HandshakeError) {
+        log::error!("fabric: handshake failed: {}", error);
+        self.transition(FabricProtoState::Failed);
+        self.events.emit(FabricEvent::HandshakeFailed { error });
+    }
+
+    fn do_server_handshake(&mut self, msg: proto::ServerHandshake) -> Result<(), HandshakeError> {
+        if self.proto_state != FabricProtoState::Handshake2 {
+            return Err(HandshakeError::Unexpected);
+        }
+
+        if !msg.version.is_supported() {
+            return Err(HandshakeError::UnsupportedVersion(msg.version));
+        }
+        if msg.encoding != proto::Encoding::Json && !self.encodings.contains(&msg.encoding) {
+            return Err(HandshakeError::UnsupportedEncoding(msg.encoding));
+        }
+
+        let algo = &signature::ECDSA_P256_SHA256_FIXED;
+        let orch_pubkey = signature::UnparsedPublicKey::new(algo, msg.pubkey.clone());
+
+        // Validate challenge response from the orchestrator.
+        let challenge = self.orch_challenge.take().ok_or(HandshakeError::Unexpected)?;
+        verify_challenge_response(&orch_pubkey, &challenge, &msg.challenge_response)?;
+
+        self.orch_pubkey = Some(orch_pubkey);
+
+        let resumed = self.uuid == Some(msg.client_uuid);
+        if self.uuid.is_some() && !resumed {
+            log::info!("fabric: session was not resumed, dropping {} peers", self.peers.len());
+            for (peer_uuid, _peer) in self.peers.drain() {
+                self.data_manager.clear_requested_from(&peer_uuid);
+                self.events.emit(FabricEvent::PeerDisconnected {
+                    peer_uuid,
+                    reason: "session not resumed".to_owned(),
+                });
+            }
+            self.connecting.clear();
+        }
+
+        self.uuid = Some(msg.client_uuid.clone());
+        self.resumption_token = msg.resumption_token.clone();
+
+        self.protocol_version = Some(msg.version);
+        self.sender.set_version(msg.version);
+        self.sender.set_encoding(msg.encoding);
+
+        // Generate challenge response for the orchestrator.
+        let challenge_response = {
+            let mut ret_challenge = vec![0; 32];
+            self.rand.fill(&mut ret_challenge[..]).unwrap();
+
+            let mut challenge_response = Vec::new();
+            challenge_response.extend(HANDSHAKE_CHALLENGE_WRAP.as_bytes());
+            challenge_response.extend(&msg.challenge.challenge);
+            challenge_response.extend(&ret_challenge);
+            challenge_response.extend(HANDSHAKE_CHALLENGE_WRAP.as_bytes());
+
+            let signature = self.keypair.sign(
+                &*self.rand,
+                &challenge_response,
+            ).unwrap();
+
+            proto::ChallengeResponse {
+                challenge_response,
+                signature: signature.as_ref().to_owned(),
+            }
+        };
+
+        log::info!(
+            "fabric: connecting to orchestrator with pubkey {:x?}, protocol version {}, encoding {:?}",
+            msg.pubkey,
+            msg.version,
+            msg.encoding,
+        );
+
+        self.sender.send(proto::ClientHandshakeFinish {
+            challenge_response,
+        });
+
+        self.transition(FabricProtoState::Normal);
+        log::info!("fabric: connected!");
+
+        let stored = self.data_manager.stored_fragments();
+        if stored.len() > 0 {
+            self.sender.send(proto::StoredFragments {
+                hashes: stored,
+            });
+        }
+
+        self.events.emit(FabricEvent::HandshakeComplete {
+            uuid: msg.client_uuid,
+            orch_pubkey: msg.pubkey,
+            version: msg.version,
+            resumed,
+        });
+
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::rand::SystemRandom;
+    use ring::signature::{self, KeyPair};
+
+    use livecore_protocol as proto;
+    use proto::{HANDSHAKE_CHALLENGE_WRAP, CHALLENGE_RESPONSE_LEN};
+
+    use super::{verify_challenge_response, HandshakeError};
+
+    fn sign(keypair: &signature::EcdsaKeyPair, data: Vec<u8>) -> proto::ChallengeResponse {
+        let signature = keypair.sign(&SystemRandom::new(), &data).unwrap();
+        proto::ChallengeResponse {
+            challenge_response: data,
+            signature: signature.as_ref().to_owned(),
+        }
+    }
+
+    fn wrap(challenge: &[u8; 32]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(HANDSHAKE_CHALLENGE_WRAP.as_bytes());
+        data.extend(challenge);
+        data.extend(&[7; 32]);
+        data.extend(HANDSHAKE_CHALLENGE_WRAP.as_bytes());
+        data
+    }
+
+    #[test]
+    fn challenge_response() {
+        let rand = SystemRandom::new();
+        let algo = &signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+        let pkcs8 = signature::EcdsaKeyPair::generate_pkcs8(algo, &rand).unwrap();
+        let keypair = signature::EcdsaKeyPair::from_pkcs8(algo, pkcs8.as_ref()).unwrap();
+        let pubkey = signature::UnparsedPublicKey::new(
+            &signature::ECDSA_P256_SHA256_FIXED, keypair.public_key().as_ref().to_owned());
+
+        let challenge = [1; 32];
+
+        let valid = sign(&keypair, wrap(&challenge));
+        assert_eq!(verify_challenge_response(&pubkey, &challenge, &valid), Ok(()));
+
+        assert_eq!(
+            verify_challenge_response(&pubkey, &[2; 32], &valid),
+            Err(HandshakeError::ChallengeMismatch),
+        );
+
+        let short = sign(&keypair, vec![0; 10]);
+        assert_eq!(
+            verify_challenge_response(&pubkey, &challenge, &short),
+            Err(HandshakeError::InvalidResponseLength { expected: CHALLENGE_RESPONSE_LEN, actual: 10 }),
+        );
+
+        let mut forged = valid.clone();
+        forged.signature[0] ^= 1;
+        assert_eq!(
+            verify_challenge_response(&pubkey, &challenge, &forged),
+            Err(HandshakeError::InvalidSignature),
+        );
+    }
+}
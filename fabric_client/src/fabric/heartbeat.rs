@@ -0,0 +1,101 @@
+//! Keepalive for the orchestrator connection and peer links.
+//!
+//! Both sides of a connection send a `Ping` every `interval`, which is
+//! answered with a `Pong` echoing its timestamp. The timestamp is the time
+//! since an epoch picked by the sender, so the round trip time is known as
+//! soon as the `Pong` arrives. A connection that has received nothing at all
+//! for `idle_timeout` is considered dead.
+//!
+//! Peer links are handled in their connection task, see `peer::connection`.
+//! The orchestrator connection is owned by the embedding application, so the
+//! fabric only emits `FabricEvent::OrchestratorTimeout` for it.
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use livecore_protocol as proto;
+
+use super::{FabricEvent, FabricProtoState};
+use super::state::FabricState;
+
+/// The first protocol version with `Ping` and `Pong`. Older orchestrators
+/// are never pinged, or considered idle.
+const MIN_VERSION: proto::ProtocolVersion = proto::ProtocolVersion(4);
+
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Heartbeat {
+    pub interval: Duration,
+    pub idle_timeout: Duration,
+}
+impl Heartbeat {
+    pub const ORCHESTRATOR: Heartbeat = Heartbeat {
+        interval: Duration::from_secs(10),
+        idle_timeout: Duration::from_secs(30),
+    };
+    pub const PEER: Heartbeat = Heartbeat {
+        interval: Duration::from_secs(5),
+        idle_timeout: Duration::from_secs(15),
+    };
+
+    /// Ticks every `interval`, starting one `interval` from now.
+    pub fn ticker(&self) -> tokio::time::Interval {
+        tokio::time::interval_at(Instant::now() + self.interval, self.interval)
+    }
+}
+
+/// The timestamp to send in a `Ping` at the current time.
+pub(crate) fn timestamp(epoch: Instant) -> u64 {
+    epoch.elapsed().as_micros() as u64
+}
+
+/// The round trip time of a `Ping` sent with the given timestamp, once the
+/// `Pong` is received.
+pub(crate) fn round_trip(epoch: Instant, timestamp: u64) -> Duration {
+    let now = epoch.elapsed().as_micros() as u64;
+    Duration::from_micros(now.saturating_sub(timestamp))
+}
+
+impl FabricState {
+
+    pub(crate) fn handle_heartbeat_tick(&mut self) {
+        if self.proto_state != FabricProtoState::Normal || self.protocol_version < Some(MIN_VERSION) {
+            return;
+        }
+
+        if self.last_orch_packet.elapsed() > self.orch_heartbeat.idle_timeout {
+            log::warn!("fabric: nothing received from orchestrator in {:?}", self.last_orch_packet.elapsed());
+            // Only reported once per timeout, until something is received.
+            self.last_orch_packet = Instant::now();
+            self.events.emit(FabricEvent::OrchestratorTimeout);
+            return;
+        }
+
+        self.sender.send(proto::Ping {
+            timestamp: timestamp(self.epoch),
+        });
+    }
+
+    pub(crate) fn handle_orch_ping(&mut self, msg: proto::Ping) {
+        self.sender.send(proto::Pong {
+            timestamp: msg.timestamp,
+        });
+    }
+
+    pub(crate) fn handle_orch_pong(&mut self, msg: proto::Pong) {
+        let rtt = round_trip(self.epoch, msg.timestamp);
+        log::debug!("fabric: orchestrator round trip time {:?}", rtt);
+        self.orch_rtt = Some(rtt);
+    }
+
+    pub(crate) fn handle_peer_rtt(&mut self, peer_uuid: proto::Uuid, rtt: Duration) {
+        if let Some(peer) = self.peers.get_mut(&peer_uuid) {
+            peer.set_rtt(rtt);
+            self.sender.send(proto::PeerRtt {
+                peer_uuid,
+                rtt_us: rtt.as_micros() as u64,
+            });
+        }
+    }
+
+}
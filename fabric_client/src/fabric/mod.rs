@@ -1,21 +1,179 @@
-use tokio::sync::mpsc;
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::sync::{mpsc, oneshot};
+use futures::Stream;
+
+use bytes::Bytes;
 
 use livecore_protocol as proto;
+use proto::{Hash, Uuid};
+
+use crate::data::{DataError, ObjectBuilder, LocalObject, ObjectReader};
+use crate::data::fragment_buffer::FragSize;
 
 mod builder;
 mod packet_sender;
 mod state;
+mod peers;
+mod objects;
+mod events;
+mod handshake;
+mod streams;
+pub(crate) mod heartbeat;
+mod stats;
 
-pub(crate) use state::{FabricState, FabricProtoState};
+pub(crate) use state::{FabricState, FabricProtoState, FabricCmd, PeerConnMsg, PeerConnMsgKind};
 
 pub use builder::FabricBuilder;
+pub use events::FabricEvent;
+pub use handshake::HandshakeError;
+pub(crate) use events::EventSubscribers;
 pub use packet_sender::OrchPacketSender;
 
+/// An object with all of its data present.
+#[derive(Debug, Clone)]
+pub struct CompleteObject {
+    pub hash: Hash,
+    pub tags: Vec<String>,
+    pub data: Bytes,
+}
+
+/// Latest round trip times measured with the heartbeat.
+#[derive(Debug, Clone, Default)]
+pub struct RoundTripTimes {
+    pub orchestrator: Option<Duration>,
+    /// Connected peers with at least one measurement.
+    pub peers: HashMap<Uuid, Duration>,
+}
+
 pub struct Fabric {
     fabric_packet_in: mpsc::Sender<proto::OrchServerMsg>,
+    cmd_in: mpsc::Sender<FabricCmd>,
+    events: EventSubscribers,
 }
 impl Fabric {
     pub async fn handle_fabric_packet(&self, msg: proto::OrchServerMsg) {
         self.fabric_packet_in.send(msg).await.unwrap()
     }
+
+    /// Switches the fabric over to a new connection to the orchestrator, and
+    /// runs the handshake again.
+    ///
+    /// Messages sent to the orchestrator while disconnected are lost. If the
+    /// orchestrator resumes the session, peer links and data are kept.
+    pub async fn reconnect(&self, sender: OrchPacketSender) {
+        self.cmd_in.send(FabricCmd::Reconnect { sender }).await.unwrap();
+    }
+
+    /// Returns a stream of lifecycle events of the fabric.
+    ///
+    /// Only events that happen after the call are yielded.
+    pub fn events(&self) -> impl Stream<Item = FabricEvent> + Unpin {
+        self.events.subscribe()
+    }
+
+    /// Publishes data as a new object.
+    ///
+    /// The data is split into fragments of the given size, which are
+    /// announced to all peers. The orchestrator is sent the manifest of the
+    /// object, which is also returned.
+    pub async fn publish(
+        &self,
+        data: &[u8],
+        tags: Vec<String>,
+        fragment_size: FragSize,
+    ) -> Result<proto::ObjectManifest, DataError> {
+        let object = ObjectBuilder::new(fragment_size)
+            .with_tags(tags)
+            .build(data)?;
+        self.publish_object(object).await
+    }
+
+    /// Publishes an object built with an `ObjectBuilder`.
+    pub async fn publish_object(&self, object: LocalObject) -> Result<proto::ObjectManifest, DataError> {
+        let (reply, reply_receiver) = oneshot::channel();
+        self.cmd_in.send(FabricCmd::Publish { object, reply }).await.unwrap();
+        reply_receiver.await.unwrap()
+    }
+
+    /// Returns the data of an object once it is complete.
+    ///
+    /// The object does not need to be known yet, the future resolves whenever
+    /// a manifest for it is received and all its fragments are fetched.
+    pub fn get_object(&self, hash: Hash) -> impl Future<Output = Bytes> {
+        let cmd_in = self.cmd_in.clone();
+        async move {
+            let (reply, reply_receiver) = oneshot::channel();
+            cmd_in.send(FabricCmd::GetObject { hash, reply }).await.unwrap();
+            reply_receiver.await.unwrap()
+        }
+    }
+
+    /// Opens a reader over an object, which can read its data while the
+    /// fragments are still being fetched.
+    ///
+    /// Unlike `get_object`, the manifest of the object must already have been
+    /// received, otherwise `DataError::UnknownObject` is returned.
+    pub async fn open_object(&self, hash: Hash) -> Result<ObjectReader, DataError> {
+        let (reply, reply_receiver) = oneshot::channel();
+        self.cmd_in.send(FabricCmd::OpenObject { hash, reply }).await.unwrap();
+        reply_receiver.await.unwrap()
+    }
+
+    /// Subscribes to objects with the given tag.
+    ///
+    /// Every object with the tag that completes after the subscription is
+    /// made is yielded by the stream.
+    pub async fn subscribe(&self, tag: String) -> impl Stream<Item = CompleteObject> + Unpin {
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        self.cmd_in.send(FabricCmd::Subscribe { tag, sender }).await.unwrap();
+        receiver
+    }
+
+    /// Pins an object, keeping it in memory until it is unpinned as many
+    /// times as it was pinned.
+    pub async fn pin_object(&self, hash: Hash) -> Result<(), DataError> {
+        let (reply, reply_receiver) = oneshot::channel();
+        self.cmd_in.send(FabricCmd::PinObject { hash, pinned: true, reply }).await.unwrap();
+        reply_receiver.await.unwrap()
+    }
+
+    /// Removes a pin added with `pin_object`.
+    pub async fn unpin_object(&self, hash: Hash) -> Result<(), DataError> {
+        let (reply, reply_receiver) = oneshot::channel();
+        self.cmd_in.send(FabricCmd::PinObject { hash, pinned: false, reply }).await.unwrap();
+        reply_receiver.await.unwrap()
+    }
+
+    /// Pins a fragment, keeping it in memory after the objects it is part of
+    /// are freed, until it is unpinned as many times as it was pinned.
+    pub async fn pin_fragment(&self, hash: Hash) -> Result<(), DataError> {
+        let (reply, reply_receiver) = oneshot::channel();
+        self.cmd_in.send(FabricCmd::PinFragment { hash, pinned: true, reply }).await.unwrap();
+        reply_receiver.await.unwrap()
+    }
+
+    /// Removes a pin added with `pin_fragment`.
+    pub async fn unpin_fragment(&self, hash: Hash) -> Result<(), DataError> {
+        let (reply, reply_receiver) = oneshot::channel();
+        self.cmd_in.send(FabricCmd::PinFragment { hash, pinned: false, reply }).await.unwrap();
+        reply_receiver.await.unwrap()
+    }
+
+    /// Frees every complete object that is not pinned, and returns their
+    /// hashes. Objects still being fetched are kept.
+    pub async fn collect_garbage(&self) -> Vec<Hash> {
+        let (reply, reply_receiver) = oneshot::channel();
+        self.cmd_in.send(FabricCmd::CollectGarbage { reply }).await.unwrap();
+        reply_receiver.await.unwrap()
+    }
+
+    /// Returns the latest round trip times to the orchestrator and peers.
+    pub async fn round_trip_times(&self) -> RoundTripTimes {
+        let (reply, reply_receiver) = oneshot::channel();
+        self.cmd_in.send(FabricCmd::RoundTripTimes { reply }).await.unwrap();
+        reply_receiver.await.unwrap()
+    }
 }
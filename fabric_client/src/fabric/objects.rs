@@ -0,0 +1,158 @@
+//! Object handling for `FabricState`.
+
+use tokio::sync::oneshot;
+
+use bytes::Bytes;
+
+use livecore_protocol as proto;
+use proto::Hash;
+
+use crate::data::{DataError, LocalObject};
+use super::{CompleteObject, FabricEvent};
+use super::state::FabricState;
+
+impl FabricState {
+
+    pub(crate) fn handle_object_manifest(&mut self, msg: proto::ObjectManifest) {
+        let hash = msg.hash;
+        let tags = msg.tags.clone();
+
+        match self.data_manager.handle_object_manifest(msg) {
+            Ok(()) => {
+                self.events.emit(FabricEvent::ObjectManifest { hash, tags });
+                self.request_missing();
+            },
+            Err(error) => log::warn!("rejected object manifest: {}", error),
+        }
+    }
+
+    pub(crate) fn handle_expire_object(&mut self, msg: proto::ExpireObject) {
+        match self.data_manager.expire_object(&msg.hash) {
+            Ok(()) => {
+                log::info!("object {} expired", msg.hash);
+                self.events.emit(FabricEvent::ObjectExpired { hash: msg.hash });
+            },
+            Err(error) => log::warn!("failed to expire object: {}", error),
+        }
+    }
+
+    pub(crate) fn handle_publish(&mut self, object: LocalObject) -> Result<proto::ObjectManifest, DataError> {
+        let manifest = object.manifest().clone();
+        let stream = object.stream;
+
+        let new_present = self.data_manager.insert_local_object(object)?;
+        log::info!("published object {} ({} bytes)", manifest.hash, manifest.size);
+
+        if new_present.len() > 0 {
+            self.announce(&new_present, None);
+        }
+
+        if let Some(stream_uuid) = stream {
+            self.publish_to_stream(stream_uuid, &manifest);
+        }
+
+        self.sender.send(proto::ObjectPublished {
+            manifest: manifest.clone(),
+        });
+
+        Ok(manifest)
+    }
+
+    pub(crate) fn handle_get_object(&mut self, hash: Hash, reply: oneshot::Sender<Bytes>) {
+        if let Some(data) = self.data_manager.object_data(&hash) {
+            let _ = reply.send(data);
+            return;
+        }
+
+        let waiters = self.object_waiters.entry(hash).or_insert_with(Vec::new);
+        waiters.retain(|waiter| !waiter.is_closed());
+        waiters.push(reply);
+    }
+
+    pub(crate) fn handle_pin_object(&mut self, hash: Hash, pinned: bool) -> Result<(), DataError> {
+        if pinned {
+            self.data_manager.pin_object(&hash)
+        } else {
+            self.data_manager.unpin_object(&hash)
+        }
+    }
+
+    pub(crate) fn handle_pin_fragment(&mut self, hash: Hash, pinned: bool) -> Result<(), DataError> {
+        if pinned {
+            self.data_manager.pin_fragment(&hash)
+        } else {
+            self.data_manager.unpin_fragment(&hash)
+        }
+    }
+
+    pub(crate) fn handle_collect_garbage(&mut self) -> Vec<Hash> {
+        let collected = self.data_manager.collect_garbage();
+        if !collected.is_empty() {
+            log::info!("collected {} objects, {} bytes still held",
+                collected.len(), self.data_manager.memory_usage());
+            self.report_evicted(collected.clone());
+        }
+        collected
+    }
+
+    /// Evicts objects until the data held is within the memory budget.
+    pub(crate) fn enforce_memory_budget(&mut self) {
+        let evicted = self.data_manager.evict();
+        if !evicted.is_empty() {
+            log::info!("evicted {} objects, {} bytes still held",
+                evicted.len(), self.data_manager.memory_usage());
+            self.report_evicted(evicted);
+        }
+    }
+
+    /// Tells the orchestrator that objects were freed, so that it stops
+    /// sending requests for them our way.
+    fn report_evicted(&mut self, hashes: Vec<Hash>) {
+        for hash in hashes.iter() {
+            self.events.emit(FabricEvent::ObjectEvicted { hash: *hash });
+        }
+        self.sender.send(proto::ObjectsEvicted { hashes });
+    }
+
+    /// Hands objects that have completed to anyone waiting for them.
+    pub(crate) fn dispatch_completed(&mut self) {
+        for hash in self.data_manager.take_completed() {
+            log::debug!("object {} complete", hash);
+
+            let waiters = self.object_waiters.remove(&hash).unwrap_or_default();
+            let tags = self.data_manager.object_tags(&hash).unwrap().to_owned();
+
+            self.events.emit(FabricEvent::ObjectComplete {
+                hash,
+                tags: tags.clone(),
+            });
+
+            let subscribed = tags.iter().any(|tag| self.subscriptions.contains_key(tag));
+            if waiters.is_empty() && !subscribed {
+                continue;
+            }
+
+            let data = self.data_manager.object_data(&hash).unwrap();
+
+            for waiter in waiters {
+                let _ = waiter.send(data.clone());
+            }
+
+            for tag in tags.iter() {
+                if let Some(subscribers) = self.subscriptions.get_mut(tag) {
+                    subscribers.retain(|subscriber| {
+                        subscriber.unbounded_send(CompleteObject {
+                            hash,
+                            tags: tags.clone(),
+                            data: data.clone(),
+                        }).is_ok()
+                    });
+                    if subscribers.is_empty() {
+                        self.subscriptions.remove(tag);
+                    }
+                }
+            }
+        }
+    }
+
+}
@@ -5,18 +5,43 @@ use livecore_protocol as proto;
 #[derive(Clone)]
 pub struct OrchPacketSender {
     sender: mpsc::UnboundedSender<Vec<u8>>,
+    /// Negotiated protocol version, messages newer than it are dropped.
+    /// Before a version is negotiated, everything is sent.
+    version: Option<proto::ProtocolVersion>,
+    /// Encoding picked by the orchestrator, JSON until the handshake.
+    encoding: proto::Encoding,
 }
 impl OrchPacketSender {
     pub fn new() -> (Self, mpsc::UnboundedReceiver<Vec<u8>>) {
         let (sender, receiver) = mpsc::unbounded_channel();
         let sender = Self {
             sender,
+            version: None,
+            encoding: proto::Encoding::Json,
         };
         (sender, receiver)
     }
+    pub(crate) fn set_version(&mut self, version: proto::ProtocolVersion) {
+        self.version = Some(version);
+    }
+    pub(crate) fn set_encoding(&mut self, encoding: proto::Encoding) {
+        self.encoding = encoding;
+    }
+
+    /// Sends a message to the orchestrator.
+    /// If the transport has gone away, or the message is not supported by
+    /// the negotiated protocol version, the message is dropped.
     pub fn send<P: Into<proto::OrchClientMsg>>(&mut self, packet: P) {
         let msg: proto::OrchClientMsg = packet.into();
-        let serialized = msg.serialize().unwrap();
-        self.sender.send(serialized.into()).unwrap();
+        if let Some(version) = self.version {
+            if msg.min_version() > version {
+                log::debug!("protocol version {} does not support message, dropping {:?}", version, msg);
+                return;
+            }
+        }
+        let serialized = msg.serialize_with(self.encoding).unwrap();
+        if self.sender.send(serialized.into()).is_err() {
+            log::debug!("orchestrator transport closed, dropping message");
+        }
     }
 }
@@ -0,0 +1,354 @@
+//! Peer handling for `FabricState`.
+//!
+//! A peer link is authenticated against the pubkey given by the orchestrator
+//! before it is used, see `peer::auth`. If the orchestrator asks for it, the
+//! link is then encrypted, see `peer::crypto`.
+//!
+//! Fragments are exchanged between peers with a simple have/want protocol:
+//! * When a connection is established, both sides send a `Have` with every
+//!   fragment they have. Whenever a new fragment becomes present, a `Have` is
+//!   sent to all peers.
+//! * Missing fragments are requested with a `Want` from a single peer that has
+//!   announced them.
+//! * A `Want` is answered with a `FragmentData` for each requested fragment.
+//!   Fragments only in the fragment store are sent once they are read.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use ring::signature::KeyPair;
+
+use tokio::sync::mpsc;
+
+use livecore_protocol as proto;
+use proto::{Hash, Uuid};
+
+use crate::data::store::StoreDone;
+use crate::platform::PeerTunnel;
+use crate::peer::{self, PeerState};
+use super::FabricEvent;
+use super::state::{FabricState, PeerConnMsg, PeerConnMsgKind};
+
+impl FabricState {
+
+    pub(crate) fn handle_connect_peer(&mut self, msg: proto::ConnectPeer) {
+        let sender = self.peer_receiver_sender.clone();
+
+        let connect_fut = self.peer_connector.start_connect_peer(
+            PeerTunnel::new_dummy(),
+            msg.connector,
+            msg.self_nonce,
+            msg.peer_nonce,
+        );
+
+        let peer_uuid = msg.peer_uuid;
+        self.connecting.insert(peer_uuid);
+        let peer_pubkey = msg.peer_pubkey;
+        let peer_nonce = msg.peer_nonce;
+        let self_nonce = msg.self_nonce;
+
+        let ephemeral_key = if msg.encrypted {
+            Some(peer::crypto::EphemeralKey::generate(&*self.rand))
+        } else {
+            None
+        };
+        let ephemeral_pubkey = ephemeral_key.as_ref()
+            .map(|key| key.public_key().to_owned())
+            .unwrap_or_default();
+
+        // Our transcript is known up front, so we can sign it here instead
+        // of handing the keypair to the connection task.
+        let link = peer::auth::LinkInfo {
+            self_nonce,
+            peer_nonce,
+            self_pubkey: self.keypair.public_key().as_ref().to_owned(),
+            peer_pubkey: peer_pubkey.clone(),
+        };
+        let signature = self.keypair.sign(&*self.rand, &link.self_transcript(&ephemeral_pubkey))
+            .unwrap().as_ref().to_owned();
+        let auth = proto::PeerAuth {
+            signature,
+            ephemeral_pubkey,
+        };
+
+        let heartbeat = self.peer_heartbeat;
+
+        let fut = async move {
+            let connected = async {
+                let mut conn = connect_fut.await?;
+                let peer_auth = peer::auth::authenticate(&mut conn, &link, auth).await?;
+                if let Some(key) = ephemeral_key {
+                    conn = peer::crypto::encrypt(
+                        conn, key, &peer_auth.ephemeral_pubkey, &self_nonce, &peer_nonce)?;
+                }
+                anyhow::Result::<_>::Ok(conn)
+            };
+            let conn = match connected.await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    log::warn!("failed to connect to peer {}: {:#}", peer_uuid, err);
+                    let _ = sender.send(PeerConnMsg {
+                        uuid: peer_uuid,
+                        kind: PeerConnMsgKind::ConnectFailed {
+                            reason: format!("{:#}", err),
+                        },
+                    }).await;
+                    return;
+                },
+            };
+
+            let (out_sender, out_receiver) = mpsc::unbounded_channel();
+            let counters = Arc::new(peer::LinkCounters::default());
+
+            let res = sender.send(PeerConnMsg {
+                uuid: peer_uuid,
+                kind: PeerConnMsgKind::Connected {
+                    pubkey: peer_pubkey,
+                    sender: out_sender,
+                    counters: counters.clone(),
+                },
+            }).await;
+            if res.is_err() {
+                return;
+            }
+
+            let reason = peer::connection::run(
+                peer_uuid, conn, heartbeat, counters, out_receiver, sender.clone()).await;
+            log::info!("peer {} disconnected: {}", peer_uuid, reason);
+
+            let _ = sender.send(PeerConnMsg {
+                uuid: peer_uuid,
+                kind: PeerConnMsgKind::Disconnected {
+                    reason,
+                },
+            }).await;
+        };
+        tokio::spawn(fut);
+    }
+
+    pub(crate) fn handle_peer_conn_msg(&mut self, msg: PeerConnMsg) {
+        match msg.kind {
+            PeerConnMsgKind::Connected { pubkey, sender, counters } => {
+                // Dropping the sender closes the link.
+                if !self.connecting.remove(&msg.uuid) {
+                    log::info!("dropping link to peer {}, it is no longer wanted", msg.uuid);
+                    return;
+                }
+                let peer = PeerState::new(msg.uuid, pubkey, sender, counters);
+
+                self.send_manifests(&peer);
+
+                let hashes = self.data_manager.present_fragments();
+                if hashes.len() > 0 {
+                    peer.send(proto::PeerMsg::Have { hashes });
+                }
+
+                self.peers.insert(msg.uuid, peer);
+                self.sender.send(proto::PeerConnectionSuccess {
+                    peer_uuid: msg.uuid,
+                });
+                self.events.emit(FabricEvent::PeerConnected {
+                    peer_uuid: msg.uuid,
+                });
+            },
+            PeerConnMsgKind::ConnectFailed { reason } => {
+                if !self.connecting.remove(&msg.uuid) {
+                    return;
+                }
+                self.sender.send(proto::PeerConnectionFailed {
+                    peer_uuid: msg.uuid,
+                    fail_reason: reason.clone(),
+                });
+                self.events.emit(FabricEvent::PeerConnectFailed {
+                    peer_uuid: msg.uuid,
+                    reason,
+                });
+            },
+            PeerConnMsgKind::Disconnected { reason } => {
+                // The peer may already have been dropped along with a
+                // session that was not resumed, or by the orchestrator, and
+                // replaced with a new link that is still open.
+                if self.peers.get(&msg.uuid).map_or(false, |peer| !peer.is_closed()) {
+                    return;
+                }
+                self.remove_peer(msg.uuid, reason);
+            },
+            PeerConnMsgKind::RoundTrip { rtt } => {
+                self.handle_peer_rtt(msg.uuid, rtt);
+            },
+            PeerConnMsgKind::Message(peer_msg) => {
+                self.handle_peer_msg(msg.uuid, peer_msg);
+            },
+        }
+    }
+
+    pub(crate) fn handle_disconnect_peer(&mut self, msg: proto::DisconnectPeer) {
+        if self.peers.contains_key(&msg.peer_uuid) {
+            log::info!("peer {} disconnected by orchestrator", msg.peer_uuid);
+            self.remove_peer(msg.peer_uuid, "disconnected by orchestrator".to_owned());
+        } else if self.connecting.remove(&msg.peer_uuid) {
+            log::info!("cancelled connecting to peer {}", msg.peer_uuid);
+        } else {
+            log::warn!("orchestrator disconnected unknown peer {}", msg.peer_uuid);
+        }
+    }
+
+    /// Forgets a connected peer and reports it as disconnected. Its
+    /// connection task ends once the `PeerState` is dropped.
+    fn remove_peer(&mut self, peer_uuid: Uuid, reason: String) {
+        if self.peers.remove(&peer_uuid).is_none() {
+            return;
+        }
+        self.sender.send(proto::PeerConnectionDisconnected {
+            peer_uuid,
+            fail_reason: reason.clone(),
+        });
+        self.events.emit(FabricEvent::PeerDisconnected {
+            peer_uuid,
+            reason,
+        });
+
+        // Anything we were waiting for from the peer needs to be
+        // requested from someone else.
+        self.data_manager.clear_requested_from(&peer_uuid);
+        self.request_missing();
+    }
+
+    fn handle_peer_msg(&mut self, peer_uuid: Uuid, msg: proto::PeerMsg) {
+        use proto::PeerMsg as PM;
+        match msg {
+            PM::Have { hashes } => {
+                if let Some(peer) = self.peers.get_mut(&peer_uuid) {
+                    peer.add_have(&hashes);
+                }
+                self.request_missing();
+            },
+            PM::Want { hashes } => {
+                let peer = match self.peers.get(&peer_uuid) {
+                    Some(peer) => peer,
+                    None => return,
+                };
+                for hash in hashes {
+                    if let Some(data) = self.data_manager.serve_fragment(peer_uuid, &hash) {
+                        peer.send(proto::PeerMsg::FragmentData(proto::FragmentData {
+                            hash,
+                            data,
+                        }));
+                    }
+                }
+            },
+            PM::Dropped { hashes } => {
+                if let Some(peer) = self.peers.get_mut(&peer_uuid) {
+                    for hash in hashes.iter() {
+                        peer.remove_have(hash);
+                    }
+                }
+                // The peer will not answer our requests for these, so ask
+                // someone else.
+                for hash in hashes.iter() {
+                    if self.data_manager.requested_from(hash) == Some(peer_uuid) {
+                        self.data_manager.clear_requested(hash);
+                    }
+                }
+                self.request_missing();
+            },
+            PM::FragmentData(data) => {
+                match self.data_manager.handle_fragment_data(peer_uuid, data.hash, &data.data) {
+                    Ok(true) => {
+                        self.announce(&[data.hash], Some(peer_uuid));
+                    },
+                    Ok(false) => (),
+                    Err(error) => {
+                        log::warn!("rejected fragment data from peer {}: {}", peer_uuid, error);
+
+                        // Don't trust the peer with this fragment again, and
+                        // ask someone else for it.
+                        if let Some(peer) = self.peers.get_mut(&peer_uuid) {
+                            peer.remove_have(&data.hash);
+                        }
+                        if self.data_manager.requested_from(&data.hash) == Some(peer_uuid) {
+                            self.data_manager.clear_requested(&data.hash);
+                            self.request_missing();
+                        }
+                    },
+                }
+            },
+            PM::StreamData { .. } => {
+                log::warn!("received unsupported stream data from peer {}", peer_uuid);
+            },
+            PM::StreamManifest(signed) => self.handle_peer_stream_manifest(peer_uuid, signed),
+            PM::ObjectManifest(signed) => self.handle_peer_object_manifest(peer_uuid, signed),
+            PM::Auth(_) => {
+                log::warn!("received unexpected auth from peer {}", peer_uuid);
+            },
+            // Answered by the connection task.
+            PM::Ping { .. } | PM::Pong { .. } => (),
+        }
+    }
+
+    /// Sends fragments read from the store to the peers that wanted them.
+    pub(crate) fn handle_store_done(&mut self, done: StoreDone) {
+        if let Some((hash, data, peers)) = self.data_manager.handle_store_done(done) {
+            for peer_uuid in peers {
+                if let Some(peer) = self.peers.get(&peer_uuid) {
+                    peer.send(proto::PeerMsg::FragmentData(proto::FragmentData {
+                        hash,
+                        data: data.clone(),
+                    }));
+                }
+            }
+        }
+        // A fragment that could not be read is fetched from peers instead.
+        self.request_missing();
+    }
+
+    /// Announces newly present fragments to all peers, except the one that
+    /// sent them to us.
+    pub(crate) fn announce(&mut self, hashes: &[Hash], except: Option<Uuid>) {
+        let mut announced = false;
+        for peer in self.peers.values() {
+            if Some(peer.uuid()) != except {
+                peer.send(proto::PeerMsg::Have {
+                    hashes: hashes.to_owned(),
+                });
+                announced = true;
+            }
+        }
+        // Peers may want them soon, so they are kept from eviction.
+        if announced {
+            self.data_manager.mark_served(hashes);
+        }
+    }
+
+    /// Tells all peers about fragments we no longer have.
+    pub(crate) fn announce_dropped(&mut self) {
+        let hashes = self.data_manager.take_dropped();
+        if hashes.is_empty() {
+            return;
+        }
+        for peer in self.peers.values() {
+            peer.send(proto::PeerMsg::Dropped {
+                hashes: hashes.clone(),
+            });
+        }
+    }
+
+    /// Requests every missing fragment that is not already in flight from a
+    /// peer that has announced it.
+    pub(crate) fn request_missing(&mut self) {
+        let mut wants: HashMap<Uuid, Vec<Hash>> = HashMap::new();
+
+        for hash in self.data_manager.missing_fragments() {
+            let peer = self.peers.values().find(|peer| peer.has(&hash));
+            if let Some(peer) = peer {
+                wants.entry(peer.uuid()).or_insert_with(Vec::new).push(hash);
+                self.data_manager.mark_requested(&hash, peer.uuid());
+            }
+        }
+
+        for (peer_uuid, hashes) in wants {
+            self.peers[&peer_uuid].send(proto::PeerMsg::Want { hashes });
+        }
+    }
+
+}
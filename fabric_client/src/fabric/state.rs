@@ -1,24 +1,35 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
 
-use ring::signature::{self, KeyPair};
+use ring::signature;
 use ring::rand::SecureRandom;
 
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{Instant, Interval};
+use futures::channel::mpsc as futures_mpsc;
 
-use livecore_protocol as proto;
-use proto::Uuid;
+use bytes::Bytes;
 
-use crate::platform::{PeerConnectionManager, PeerTunnel};
+use livecore_protocol as proto;
+use proto::{Hash, Uuid};
+
+use crate::data::{DataError, LocalObject, ObjectReader};
+use crate::platform::PeerConnectionManager;
+use crate::peer::{PeerState, LinkCounters};
+use crate::peer::key::KeyRing;
+use super::{CompleteObject, EventSubscribers, RoundTripTimes};
+use super::heartbeat::Heartbeat;
+use super::stats::stats_tick;
 use super::packet_sender::OrchPacketSender;
 
-const HANDSHAKE_CHALLENGE_WRAP: &str = "__HANDSHAKE_CHALLENGE__";
-const CHALLENGE_RESPONSE_LEN: usize = (HANDSHAKE_CHALLENGE_WRAP.len() * 2) + (32 * 2);
-
 #[derive(Debug, PartialEq, Eq)]
 pub enum FabricProtoState {
     Handshake1,
     Handshake2,
     Normal,
+    /// The handshake failed, nothing more is processed until a reconnect.
+    Failed,
 }
 
 pub struct FabricState {
@@ -26,39 +37,123 @@ pub struct FabricState {
 
     pub(crate) sender: OrchPacketSender,
     pub(crate) receiver: mpsc::Receiver<proto::OrchServerMsg>,
+    pub(crate) cmd_receiver: mpsc::Receiver<FabricCmd>,
 
     pub(crate) peer_connector: Box<dyn PeerConnectionManager + Send>,
     pub(crate) peer_receiver: mpsc::Receiver<PeerConnMsg>,
     pub(crate) peer_receiver_sender: mpsc::Sender<PeerConnMsg>,
 
-    pub(crate) peers: HashMap<Uuid, crate::peer::PeerState>,
+    pub(crate) peers: HashMap<Uuid, PeerState>,
+    /// Peers with a `ConnectPeer` in progress. A link that connects after
+    /// being removed from here is dropped.
+    pub(crate) connecting: HashSet<Uuid>,
+    /// Keys granted capabilities by the orchestrator.
+    pub(crate) keys: KeyRing,
 
     pub(crate) data_manager: crate::data::DataManager,
+    /// Pending `get_object` calls, answered when the object completes.
+    pub(crate) object_waiters: HashMap<Hash, Vec<oneshot::Sender<Bytes>>>,
+    /// Subscriptions to completed objects, by tag.
+    pub(crate) subscriptions: HashMap<String, Vec<futures_mpsc::UnboundedSender<CompleteObject>>>,
+
+    pub(crate) events: EventSubscribers,
 
     pub(crate) node_classes: Vec<String>,
+    /// Encodings offered to the orchestrator in addition to JSON.
+    pub(crate) encodings: Vec<proto::Encoding>,
+    /// Whether encrypted peer links are offered to the orchestrator.
+    pub(crate) encrypted_links: bool,
     pub(crate) auth_token: Option<String>,
 
     pub(crate) uuid: Option<Uuid>,
+    /// Protocol version negotiated in the handshake.
+    pub(crate) protocol_version: Option<proto::ProtocolVersion>,
+    /// Token for resuming the session when reconnecting.
+    pub(crate) resumption_token: Option<String>,
     pub(crate) keypair: signature::EcdsaKeyPair,
 
     pub(crate) orch_challenge: Option<[u8; 32]>,
     pub(crate) orch_pubkey: Option<signature::UnparsedPublicKey<Vec<u8>>>,
 
+    pub(crate) orch_heartbeat: Heartbeat,
+    pub(crate) peer_heartbeat: Heartbeat,
+    /// Epoch of the timestamps in our pings to the orchestrator.
+    pub(crate) epoch: Instant,
+    pub(crate) last_orch_packet: Instant,
+    pub(crate) orch_rtt: Option<Duration>,
+    /// Set by the orchestrator with `ConfigureStats`.
+    pub(crate) stats_interval: Option<Interval>,
+
     pub(crate) rand: Box<dyn SecureRandom + Send>,
 }
 
+/// Commands from the `Fabric` handle.
+pub(crate) enum FabricCmd {
+    Publish {
+        object: LocalObject,
+        reply: oneshot::Sender<Result<proto::ObjectManifest, DataError>>,
+    },
+    Reconnect {
+        sender: OrchPacketSender,
+    },
+    GetObject {
+        hash: Hash,
+        reply: oneshot::Sender<Bytes>,
+    },
+    Subscribe {
+        tag: String,
+        sender: futures_mpsc::UnboundedSender<CompleteObject>,
+    },
+    RoundTripTimes {
+        reply: oneshot::Sender<RoundTripTimes>,
+    },
+    PinObject {
+        hash: Hash,
+        pinned: bool,
+        reply: oneshot::Sender<Result<(), DataError>>,
+    },
+    PinFragment {
+        hash: Hash,
+        pinned: bool,
+        reply: oneshot::Sender<Result<(), DataError>>,
+    },
+    CollectGarbage {
+        reply: oneshot::Sender<Vec<Hash>>,
+    },
+    OpenObject {
+        hash: Hash,
+        reply: oneshot::Sender<Result<ObjectReader, DataError>>,
+    },
+}
+
 pub(crate) struct PeerConnMsg {
     pub uuid: Uuid,
     pub kind: PeerConnMsgKind,
 }
 
 pub(crate) enum PeerConnMsgKind {
-    Connected,
+    Connected {
+        pubkey: Vec<u8>,
+        sender: mpsc::UnboundedSender<proto::PeerMsg>,
+        counters: Arc<LinkCounters>,
+    },
+    ConnectFailed {
+        reason: String,
+    },
+    Disconnected {
+        reason: String,
+    },
+    /// A round trip time was measured on the link.
+    RoundTrip {
+        rtt: Duration,
+    },
+    Message(proto::PeerMsg),
 }
 
 impl FabricState {
 
     pub async fn main_loop(mut self) {
+        let mut heartbeat = self.orch_heartbeat.ticker();
         loop {
             tokio::select! {
                 msg = self.receiver.recv() => {
@@ -67,154 +162,123 @@ impl FabricState {
                 },
                 msg = self.peer_receiver.recv() => {
                     let msg = msg.expect("can never happen, last sender always in FabricState");
-                    match msg.kind {
-                        PeerConnMsgKind::Connected => {
-                            self.sender.send(proto::PeerConnectionSuccess {
-                                peer_uuid: msg.uuid,
-                            });
-                        },
-                    }
+                    self.handle_peer_conn_msg(msg);
+                },
+                Some(cmd) = self.cmd_receiver.recv() => {
+                    self.handle_cmd(cmd);
+                },
+                _ = heartbeat.tick() => {
+                    self.handle_heartbeat_tick();
+                },
+                _ = stats_tick(&mut self.stats_interval) => {
+                    self.send_node_stats();
+                },
+                Some(done) = self.data_manager.store_done() => {
+                    self.handle_store_done(done);
                 },
             };
+            self.dispatch_completed();
+            self.enforce_memory_budget();
+            self.announce_dropped();
         }
     }
 
-    fn transition(&mut self, to: FabricProtoState) {
+    pub(crate) fn transition(&mut self, to: FabricProtoState) {
         log::debug!("fabric proto state transition: {:?} -> {:?}", self.proto_state, to);
         self.proto_state = to;
     }
 
-    pub fn start_handshake(&mut self) {
-        assert_eq!(self.proto_state, FabricProtoState::Handshake1);
-
-        log::info!("fabric: starting handshake...");
-
-        let challenge = {
-            let mut data = [0; 32];
-            self.rand.fill(&mut data).unwrap();
-            data
-        };
-
-        self.orch_challenge = Some(challenge);
-
-        self.sender.send(proto::ClientHandshake {
-            version: Default::default(),
-            node_classes: self.node_classes.clone(),
-            peer_connection_capabilities: vec![
-                proto::PeerConnectionType::WebsocketClient,
-                proto::PeerConnectionType::WebsocketServer,
-            ],
-            token: self.auth_token.clone(),
-            pubkey: self.keypair.public_key().as_ref().to_owned(),
-            challenge: proto::Challenge {
-                challenge,
+    fn handle_cmd(&mut self, cmd: FabricCmd) {
+        match cmd {
+            FabricCmd::Publish { object, reply } => {
+                let _ = reply.send(self.handle_publish(object));
             },
-        });
-        self.transition(FabricProtoState::Handshake2)
+            FabricCmd::Reconnect { sender } => self.handle_reconnect(sender),
+            FabricCmd::GetObject { hash, reply } => self.handle_get_object(hash, reply),
+            FabricCmd::PinObject { hash, pinned, reply } => {
+                let _ = reply.send(self.handle_pin_object(hash, pinned));
+            },
+            FabricCmd::PinFragment { hash, pinned, reply } => {
+                let _ = reply.send(self.handle_pin_fragment(hash, pinned));
+            },
+            FabricCmd::CollectGarbage { reply } => {
+                let _ = reply.send(self.handle_collect_garbage());
+            },
+            FabricCmd::Subscribe { tag, sender } => {
+                self.subscriptions.entry(tag).or_insert_with(Vec::new).push(sender);
+            },
+            FabricCmd::RoundTripTimes { reply } => {
+                let _ = reply.send(RoundTripTimes {
+                    orchestrator: self.orch_rtt,
+                    peers: self.peers.values()
+                        .filter_map(|peer| Some((peer.uuid(), peer.rtt()?)))
+                        .collect(),
+                });
+            },
+            FabricCmd::OpenObject { hash, reply } => {
+                let _ = reply.send(self.data_manager.open_object(&hash));
+            },
+        }
     }
 
     pub fn handle_fabric_packet(&mut self, packet: proto::OrchServerMsg) {
         use proto::OrchServerMsg as OSM;
+
+        self.last_orch_packet = Instant::now();
+
+        match (&self.proto_state, &packet) {
+            (FabricProtoState::Failed, _) => {
+                log::debug!("fabric: handshake failed, ignoring packet {:?}", packet);
+                return;
+            },
+            (FabricProtoState::Normal, OSM::ServerHandshake(_)) |
+            (FabricProtoState::Normal, OSM::HandshakeRejected(_)) => {
+                self.reject_packet("unexpected handshake packet after handshake");
+                return;
+            },
+            (FabricProtoState::Normal, _) if Some(packet.min_version()) > self.protocol_version => {
+                self.reject_packet("packet not supported by negotiated protocol version");
+                return;
+            },
+            (FabricProtoState::Normal, _) |
+            (_, OSM::ServerHandshake(_)) |
+            (_, OSM::HandshakeRejected(_)) |
+            (_, OSM::TestExit(_)) => (),
+            (_, _) => {
+                self.reject_packet("unexpected packet before handshake");
+                return;
+            },
+        }
+
         match packet {
             OSM::ServerHandshake(msg) => self.handle_server_handshake(msg),
+            OSM::HandshakeRejected(msg) => self.handle_handshake_rejected(msg),
             OSM::ConnectPeer(msg) => self.handle_connect_peer(msg),
+            OSM::DisconnectPeer(msg) => self.handle_disconnect_peer(msg),
+
+            OSM::ObjectManifest(msg) => self.handle_object_manifest(msg),
+            OSM::ExpireObject(msg) => self.handle_expire_object(msg),
+            OSM::GrantCapability(msg) => self.handle_grant_capability(msg),
+
+            OSM::Ping(msg) => self.handle_orch_ping(msg),
+            OSM::Pong(msg) => self.handle_orch_pong(msg),
 
-            OSM::ObjectManifest(msg) => self.data_manager.handle_object_manifest(msg),
+            OSM::ConfigureStats(msg) => self.handle_configure_stats(msg),
 
             OSM::TestExit(_msg) => {
                 log::info!("received test_exit packet, exitting immediately");
                 std::process::exit(0);
             },
-            _ => todo!(),
         }
     }
 
-    fn handle_server_handshake(&mut self, msg: proto::ServerHandshake) {
-        assert_eq!(self.proto_state, FabricProtoState::Handshake2);
-
-        let algo = &signature::ECDSA_P256_SHA256_FIXED;
-        self.orch_pubkey = Some(signature::UnparsedPublicKey::new(algo, msg.pubkey.clone()));
-
-        self.uuid = Some(msg.client_uuid.clone());
-
-        // Validate challenge response from the orchestrator.
-        {
-            let challenge = self.orch_challenge.take().unwrap();
-
-            assert_eq!(msg.challenge_response.challenge_response.len(), CHALLENGE_RESPONSE_LEN);
-            let challenge_range = (HANDSHAKE_CHALLENGE_WRAP.len())..(HANDSHAKE_CHALLENGE_WRAP.len() + 32);
-            assert_eq!(&msg.challenge_response.challenge_response[challenge_range], &challenge);
-
-            self.orch_pubkey.as_ref().unwrap().verify(
-                &msg.challenge_response.challenge_response,
-                &msg.challenge_response.signature,
-            ).unwrap();
-        }
-
-        // Generate challenge response for the orchestrator.
-        let challenge_response = {
-            let mut ret_challenge = vec![0; 32];
-            self.rand.fill(&mut ret_challenge[..]).unwrap();
-
-            let mut challenge_response = Vec::new();
-            challenge_response.extend(HANDSHAKE_CHALLENGE_WRAP.as_bytes());
-            assert!(msg.challenge.challenge.len() == 32);
-            challenge_response.extend(&msg.challenge.challenge);
-            challenge_response.extend(&ret_challenge);
-            challenge_response.extend(HANDSHAKE_CHALLENGE_WRAP.as_bytes());
-
-            let signature = self.keypair.sign(
-                &*self.rand,
-                &challenge_response,
-            ).unwrap();
-
-            proto::ChallengeResponse {
-                challenge_response,
-                signature: signature.as_ref().to_owned(),
-            }
-        };
-
-        log::info!(
-            "fabric: connecting to orchestrator with pubkey {:x?}",
-            msg.pubkey,
-        );
-
-        self.sender.send(proto::ClientHandshakeFinish {
-            challenge_response,
+    /// Tells the orchestrator that a packet was ignored.
+    pub(crate) fn reject_packet(&mut self, reason: &str) {
+        log::warn!("fabric: rejected packet: {}", reason);
+        self.sender.send(proto::ClientError {
+            fatal: false,
+            reason: reason.to_owned(),
         });
-
-        self.transition(FabricProtoState::Normal);
-        log::info!("fabric: connected!");
     }
 
-    fn handle_connect_peer(&mut self, msg: proto::ConnectPeer) {
-        let sender = self.peer_receiver_sender.clone();
-
-        let connect_fut = self.peer_connector.start_connect_peer(
-            PeerTunnel::new_dummy(),
-            msg.connector,
-            msg.self_nonce,
-            msg.peer_nonce,
-        );
-
-        let peer_uuid = msg.peer_uuid;
-
-        let fut = async move {
-            let conn = match connect_fut.await {
-                Ok(conn) => conn,
-                Err(err) => {
-                    log::error!("noo {:?}", err);
-                    return;
-                },
-            };
-
-            sender.send(PeerConnMsg {
-                uuid: peer_uuid.clone(),
-                kind: PeerConnMsgKind::Connected,
-            }).await.ok().unwrap();
-        };
-        tokio::spawn(fut);
-    }
-
-
 }
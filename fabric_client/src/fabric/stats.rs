@@ -0,0 +1,65 @@
+//! Periodic `NodeStats` reports to the orchestrator.
+//!
+//! Nothing is sent until the orchestrator sets an interval with
+//! `ConfigureStats`. The interval is forgotten when reconnecting, as the new
+//! orchestrator connection may not want stats at all.
+
+use std::time::Duration;
+
+use tokio::time::{Instant, Interval};
+
+use livecore_protocol as proto;
+
+use super::state::FabricState;
+
+/// Resolves on the next tick of the stats interval, or never if stats are
+/// disabled.
+pub(crate) async fn stats_tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        },
+        None => futures::future::pending().await,
+    }
+}
+
+impl FabricState {
+
+    pub(crate) fn handle_configure_stats(&mut self, msg: proto::ConfigureStats) {
+        self.stats_interval = match msg.interval_ms {
+            Some(0) => {
+                self.reject_packet("stats interval must not be zero");
+                return;
+            },
+            Some(ms) => {
+                let period = Duration::from_millis(ms.into());
+                Some(tokio::time::interval_at(Instant::now() + period, period))
+            },
+            None => None,
+        };
+        log::debug!("fabric: stats interval set to {:?}", msg.interval_ms);
+    }
+
+    pub(crate) fn node_stats(&self) -> proto::NodeStats {
+        let (complete, partial) = self.data_manager.object_counts();
+        proto::NodeStats {
+            peers: self.peers.values()
+                .map(|peer| proto::PeerStats {
+                    peer_uuid: peer.uuid(),
+                    bytes_in: peer.counters().bytes_in(),
+                    bytes_out: peer.counters().bytes_out(),
+                    rtt_us: peer.rtt().map(|rtt| rtt.as_micros() as u64),
+                })
+                .collect(),
+            memory_bytes: self.data_manager.memory_usage() as u64,
+            complete_objects: complete as u64,
+            partial_objects: partial as u64,
+        }
+    }
+
+    pub(crate) fn send_node_stats(&mut self) {
+        let stats = self.node_stats();
+        self.sender.send(stats);
+    }
+
+}
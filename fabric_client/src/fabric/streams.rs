@@ -0,0 +1,90 @@
+//! Stream handling for `FabricState`.
+//!
+//! A node originating a stream signs the manifests of the stream and of each
+//! object added to it, and sends them to its peers. Other nodes only accept
+//! the manifests if the orchestrator has granted the signing key the
+//! capability to originate the stream. Accepted manifests are passed on to
+//! all other peers.
+
+use livecore_protocol as proto;
+use proto::Uuid;
+
+use crate::data::signing;
+use crate::peer::PeerState;
+use super::FabricEvent;
+use super::state::FabricState;
+
+impl FabricState {
+
+    pub(crate) fn handle_grant_capability(&mut self, msg: proto::GrantCapability) {
+        log::info!("fabric: granted {:?} to key {:x?}", msg.capability, msg.pubkey);
+        self.keys.grant(msg.pubkey, msg.capability.into());
+    }
+
+    /// Signs and sends the manifests for an object published to a stream
+    /// originated by this node.
+    pub(crate) fn publish_to_stream(&mut self, stream_uuid: Uuid, manifest: &proto::ObjectManifest) {
+        let signed_object = signing::sign_object_manifest(
+            &self.keypair, &*self.rand, stream_uuid, manifest.clone());
+        self.data_manager.insert_signed_object_manifest(signed_object.clone());
+        self.broadcast(proto::PeerMsg::ObjectManifest(signed_object), None);
+
+        let stream_manifest = self.data_manager.next_stream_manifest(stream_uuid, manifest.hash);
+        let signed_stream = signing::sign_stream_manifest(
+            &self.keypair, &*self.rand, stream_manifest);
+        self.data_manager.insert_stream_manifest(signed_stream.clone());
+        self.emit_stream_updated(&signed_stream.manifest);
+        self.broadcast(proto::PeerMsg::StreamManifest(signed_stream), None);
+    }
+
+    pub(crate) fn handle_peer_object_manifest(&mut self, peer_uuid: Uuid, signed: proto::SignedObjectManifest) {
+        let hash = signed.manifest.hash;
+        let tags = signed.manifest.tags.clone();
+
+        match self.data_manager.handle_signed_object_manifest(&self.keys, signed.clone()) {
+            Ok(()) => {
+                self.events.emit(FabricEvent::ObjectManifest { hash, tags });
+                self.broadcast(proto::PeerMsg::ObjectManifest(signed), Some(peer_uuid));
+                self.request_missing();
+            },
+            Err(error) => log::warn!("rejected object manifest from peer {}: {}", peer_uuid, error),
+        }
+    }
+
+    pub(crate) fn handle_peer_stream_manifest(&mut self, peer_uuid: Uuid, signed: proto::SignedStreamManifest) {
+        match self.data_manager.handle_signed_stream_manifest(&self.keys, signed.clone()) {
+            Ok(true) => {
+                self.emit_stream_updated(&signed.manifest);
+                self.broadcast(proto::PeerMsg::StreamManifest(signed), Some(peer_uuid));
+            },
+            Ok(false) => (),
+            Err(error) => log::warn!("rejected stream manifest from peer {}: {}", peer_uuid, error),
+        }
+    }
+
+    /// Sends every signed manifest we know of to a newly connected peer.
+    pub(crate) fn send_manifests(&self, peer: &PeerState) {
+        for signed in self.data_manager.signed_object_manifests() {
+            peer.send(proto::PeerMsg::ObjectManifest(signed.clone()));
+        }
+        for signed in self.data_manager.stream_manifests() {
+            peer.send(proto::PeerMsg::StreamManifest(signed.clone()));
+        }
+    }
+
+    fn broadcast(&self, msg: proto::PeerMsg, except: Option<Uuid>) {
+        for peer in self.peers.values() {
+            if Some(peer.uuid()) != except {
+                peer.send(msg.clone());
+            }
+        }
+    }
+
+    fn emit_stream_updated(&self, manifest: &proto::StreamManifest) {
+        self.events.emit(FabricEvent::StreamUpdated {
+            stream_uuid: manifest.stream_uuid,
+            objects: manifest.objects.clone(),
+        });
+    }
+
+}
@@ -4,4 +4,9 @@ mod data;
 mod peer;
 mod fabric;
 
-pub use fabric::{Fabric, FabricBuilder, OrchPacketSender};
+pub use fabric::{Fabric, FabricBuilder, OrchPacketSender, CompleteObject, FabricEvent, HandshakeError, RoundTripTimes};
+pub use util::backoff::Backoff;
+pub use data::{DataError, ObjectBuilder, LocalObject, ObjectReader};
+pub use data::store::{FragmentStore, FileStore};
+pub use data::fragment_buffer::FragSize;
+pub use data::hash::{fragment_hash, object_hash, manifest_hash};
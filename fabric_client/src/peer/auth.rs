@@ -0,0 +1,122 @@
+//! Authentication of peer links.
+//!
+//! The orchestrator tells both nodes the pubkey of the other in
+//! `ConnectPeer`. Once the transport is up, each side sends a `PeerAuth` with
+//! a signature over a transcript of both nonces, both pubkeys and its own
+//! ephemeral pubkey, and checks the one it receives against the expected
+//! pubkey. The link is only handed to `FabricState` if this succeeds.
+//!
+//! For encrypted links the ephemeral pubkeys are then used for key
+//! agreement, see `peer::crypto`. Signing them binds the agreed keys to the
+//! node keys, so that nobody on the path can substitute their own.
+
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+
+use tokio::time::timeout;
+
+use anyhow::{Result, Context, bail};
+
+use livecore_protocol as proto;
+use proto::{Uuid, PEER_AUTH_PREFIX};
+
+use crate::platform::PeerConnection;
+use super::key::Key;
+
+const AUTH_TIMEOUT: Duration = Duration::from_millis(10000);
+
+/// The transcript signed by the `signer` side of a link.
+pub fn transcript(
+    signer_nonce: &Uuid,
+    verifier_nonce: &Uuid,
+    signer_pubkey: &[u8],
+    verifier_pubkey: &[u8],
+    signer_ephemeral_pubkey: &[u8],
+) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend(PEER_AUTH_PREFIX);
+    data.extend(signer_nonce.as_bytes());
+    data.extend(verifier_nonce.as_bytes());
+    for pubkey in [signer_pubkey, verifier_pubkey, signer_ephemeral_pubkey].iter() {
+        data.extend(&(pubkey.len() as u32).to_le_bytes());
+        data.extend(*pubkey);
+    }
+    data
+}
+
+/// The parts of the transcripts known from `ConnectPeer`.
+pub(crate) struct LinkInfo {
+    pub self_nonce: Uuid,
+    pub peer_nonce: Uuid,
+    pub self_pubkey: Vec<u8>,
+    pub peer_pubkey: Vec<u8>,
+}
+impl LinkInfo {
+    /// The transcript we sign.
+    pub fn self_transcript(&self, ephemeral_pubkey: &[u8]) -> Vec<u8> {
+        transcript(
+            &self.self_nonce, &self.peer_nonce,
+            &self.self_pubkey, &self.peer_pubkey,
+            ephemeral_pubkey,
+        )
+    }
+
+    /// The transcript the peer should have signed.
+    pub fn peer_transcript(&self, ephemeral_pubkey: &[u8]) -> Vec<u8> {
+        transcript(
+            &self.peer_nonce, &self.self_nonce,
+            &self.peer_pubkey, &self.self_pubkey,
+            ephemeral_pubkey,
+        )
+    }
+}
+
+/// Sends our `PeerAuth`, and verifies the one sent by the peer against the
+/// transcript it should have signed. Returns the verified peer `PeerAuth`.
+pub(crate) async fn authenticate(
+    conn: &mut PeerConnection,
+    link: &LinkInfo,
+    auth: proto::PeerAuth,
+) -> Result<proto::PeerAuth> {
+    let msg = proto::PeerMsg::Auth(auth);
+    let data = msg.serialize().expect("peer message serialization failed");
+    conn.sink.send(data).await.context("failed to send peer auth")?;
+
+    let data = match timeout(AUTH_TIMEOUT, conn.source.next()).await {
+        Ok(Some(Ok(data))) => data,
+        Ok(Some(Err(error))) => return Err(error).context("peer connection error during auth"),
+        Ok(None) => bail!("peer closed connection during auth"),
+        Err(_) => bail!("peer auth timed out"),
+    };
+
+    let auth = match proto::PeerMsg::decode(&data) {
+        Ok(proto::PeerMsg::Auth(auth)) => auth,
+        Ok(_) => bail!("peer did not start with auth"),
+        Err(error) => return Err(error).context("peer sent invalid auth"),
+    };
+
+    let peer_transcript = link.peer_transcript(&auth.ephemeral_pubkey);
+    if !Key::new(link.peer_pubkey.clone()).verify(&peer_transcript, &auth.signature) {
+        bail!("peer auth signature does not match peer pubkey");
+    }
+
+    Ok(auth)
+}
+
+#[cfg(test)]
+mod tests {
+    use livecore_protocol::Uuid;
+
+    use super::transcript;
+
+    #[test]
+    fn transcript_is_directional() {
+        let a = Uuid::from_u128(1);
+        let b = Uuid::from_u128(2);
+        assert_ne!(transcript(&a, &b, &[1], &[2], &[]), transcript(&b, &a, &[2], &[1], &[]));
+        // Length prefixes keep the pubkeys from being shifted between fields.
+        assert_ne!(transcript(&a, &b, &[1, 2], &[3], &[]), transcript(&a, &b, &[1], &[2, 3], &[]));
+        assert_ne!(transcript(&a, &b, &[1], &[2, 3], &[]), transcript(&a, &b, &[1], &[2], &[3]));
+    }
+}
@@ -0,0 +1,119 @@
+//! Per-peer connection task.
+//!
+//! Owns a `PeerConnection` for as long as it is live. Messages queued by
+//! `FabricState` are serialized and sent to the peer, and messages received
+//! from the peer are handed back to `FabricState`.
+//!
+//! The task also runs the heartbeat for the link: it pings the peer, answers
+//! its pings, and closes the link if nothing is received for too long.
+
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use futures::{SinkExt, StreamExt};
+
+use livecore_protocol as proto;
+use proto::Uuid;
+
+use crate::platform::PeerConnection;
+use super::LinkCounters;
+use crate::fabric::{PeerConnMsg, PeerConnMsgKind};
+use crate::fabric::heartbeat::{Heartbeat, timestamp, round_trip};
+
+/// Runs the connection until it fails, the peer disconnects or `FabricState`
+/// drops the outgoing sender. Returns the reason for the disconnect.
+///
+/// Every frame sent and received is counted in `counters`.
+pub(crate) async fn run(
+    uuid: Uuid,
+    conn: PeerConnection,
+    heartbeat: Heartbeat,
+    counters: Arc<LinkCounters>,
+    mut outgoing: mpsc::UnboundedReceiver<proto::PeerMsg>,
+    incoming: mpsc::Sender<PeerConnMsg>,
+) -> String {
+    let PeerConnection { mut sink, mut source } = conn;
+
+    let epoch = Instant::now();
+    let mut last_received = Instant::now();
+    let mut ticker = heartbeat.ticker();
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                if last_received.elapsed() > heartbeat.idle_timeout {
+                    let _ = sink.close().await;
+                    return "idle timeout".to_owned();
+                }
+
+                let ping = proto::PeerMsg::Ping { timestamp: timestamp(epoch) };
+                let data = ping.serialize().expect("peer message serialization failed");
+                let len = data.len();
+                if let Err(error) = sink.send(data).await {
+                    return error.to_string();
+                }
+                counters.add_out(len);
+            },
+            msg = outgoing.recv() => {
+                let msg = match msg {
+                    Some(msg) => msg,
+                    None => {
+                        let _ = sink.close().await;
+                        return "connection closed locally".to_owned();
+                    },
+                };
+
+                let data = msg.serialize().expect("peer message serialization failed");
+                let len = data.len();
+                if let Err(error) = sink.send(data).await {
+                    return error.to_string();
+                }
+                counters.add_out(len);
+            },
+            data = source.next() => {
+                let data = match data {
+                    Some(Ok(data)) => data,
+                    Some(Err(error)) => return error.to_string(),
+                    None => return "connection closed".to_owned(),
+                };
+                last_received = Instant::now();
+                counters.add_in(data.len());
+
+                let msg = match proto::PeerMsg::decode(&data) {
+                    Ok(msg) => msg,
+                    Err(error) => {
+                        log::warn!("peer {} sent invalid message: {}", uuid, error);
+                        return format!("invalid message: {}", error);
+                    },
+                };
+
+                let kind = match msg {
+                    proto::PeerMsg::Ping { timestamp } => {
+                        let pong = proto::PeerMsg::Pong { timestamp };
+                        let data = pong.serialize().expect("peer message serialization failed");
+                        let len = data.len();
+                        if let Err(error) = sink.send(data).await {
+                            return error.to_string();
+                        }
+                        counters.add_out(len);
+                        continue;
+                    },
+                    proto::PeerMsg::Pong { timestamp } => PeerConnMsgKind::RoundTrip {
+                        rtt: round_trip(epoch, timestamp),
+                    },
+                    msg => PeerConnMsgKind::Message(msg),
+                };
+
+                let res = incoming.send(PeerConnMsg {
+                    uuid,
+                    kind,
+                }).await;
+                if res.is_err() {
+                    return "fabric stopped".to_owned();
+                }
+            },
+        }
+    }
+}
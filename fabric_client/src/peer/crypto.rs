@@ -0,0 +1,222 @@
+//! Encryption of peer links.
+//!
+//! Both sides generate an ephemeral X25519 key for the link, and send the
+//! public half in their signed `PeerAuth`. The agreed secret is expanded with
+//! HKDF-SHA256 into one ChaCha20-Poly1305 key per direction. Frames are
+//! sealed with nonces from a per-direction counter, so a frame that is
+//! modified, dropped, reordered or replayed fails to open and ends the link.
+//!
+//! This wraps a `PeerConnection`, and works the same over every transport.
+
+use bytes::BytesMut;
+use futures::{future, SinkExt, StreamExt};
+
+use ring::{aead, agreement, hkdf};
+use ring::aead::BoundKey;
+use ring::error::Unspecified;
+use ring::rand::SecureRandom;
+
+use anyhow::{Result, anyhow};
+
+use livecore_protocol as proto;
+use proto::{Uuid, PEER_LINK_KEY_SALT};
+
+use crate::platform::{PeerConnection, PeerConnectionError};
+
+/// An ephemeral key pair, generated for a single link.
+pub(crate) struct EphemeralKey {
+    private_key: agreement::EphemeralPrivateKey,
+    public_key: Vec<u8>,
+}
+impl EphemeralKey {
+    pub fn generate(rand: &dyn SecureRandom) -> Self {
+        let private_key = agreement::EphemeralPrivateKey::generate(&agreement::X25519, rand)
+            .unwrap();
+        let public_key = private_key.compute_public_key().unwrap().as_ref().to_owned();
+        EphemeralKey {
+            private_key,
+            public_key,
+        }
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+}
+
+/// Nonces for one direction of a link, counting up from zero.
+struct NonceCounter(u64);
+impl aead::NonceSequence for NonceCounter {
+    fn advance(&mut self) -> Result<aead::Nonce, Unspecified> {
+        let mut nonce = [0; aead::NONCE_LEN];
+        nonce[aead::NONCE_LEN - 8..].copy_from_slice(&self.0.to_le_bytes());
+        self.0 = self.0.checked_add(1).ok_or(Unspecified)?;
+        Ok(aead::Nonce::assume_unique_for_key(nonce))
+    }
+}
+
+/// Derives the key for frames sent from `sender_nonce` to `receiver_nonce`.
+fn derive_key(secret: &[u8], sender_nonce: &Uuid, receiver_nonce: &Uuid) -> aead::UnboundKey {
+    let info = [&sender_nonce.as_bytes()[..], &receiver_nonce.as_bytes()[..]];
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, PEER_LINK_KEY_SALT).extract(secret);
+    prk.expand(&info, &aead::CHACHA20_POLY1305).unwrap().into()
+}
+
+/// Agrees on keys with the peer, and wraps the connection so that every
+/// frame is encrypted.
+///
+/// `peer_public_key` must come from a `PeerAuth` that has been verified.
+pub(crate) fn encrypt(
+    conn: PeerConnection,
+    key: EphemeralKey,
+    peer_public_key: &[u8],
+    self_nonce: &Uuid,
+    peer_nonce: &Uuid,
+) -> Result<PeerConnection> {
+    let peer_public_key = agreement::UnparsedPublicKey::new(&agreement::X25519, peer_public_key);
+    let (send_key, recv_key) = agreement::agree_ephemeral(
+        key.private_key,
+        &peer_public_key,
+        anyhow!("key agreement with peer failed"),
+        |secret| Ok((
+            derive_key(secret, self_nonce, peer_nonce),
+            derive_key(secret, peer_nonce, self_nonce),
+        )),
+    )?;
+
+    let mut sealing_key = aead::SealingKey::new(send_key, NonceCounter(0));
+    let sink = conn.sink.with(move |mut data: Vec<u8>| {
+        let res = sealing_key.seal_in_place_append_tag(aead::Aad::empty(), &mut data)
+            .map(|()| data)
+            .map_err(|_| PeerConnectionError::Protocol("link nonces exhausted"));
+        future::ready(res)
+    });
+
+    let mut opening_key = aead::OpeningKey::new(recv_key, NonceCounter(0));
+    let source = conn.source.map(move |frame| {
+        // Frames are opened in place. A frame that is not shared, as
+        // transports hand them out, is made mutable without a copy, and
+        // payloads decoded from the plaintext share its buffer.
+        let mut data = BytesMut::from(frame?);
+        let len = opening_key.open_in_place(aead::Aad::empty(), &mut data)
+            .map_err(|_| PeerConnectionError::Protocol("invalid encrypted frame"))?
+            .len();
+        data.truncate(len);
+        Ok(data.freeze())
+    });
+
+    Ok(PeerConnection {
+        sink: Box::pin(sink),
+        source: Box::pin(source),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::{SinkExt, StreamExt};
+    use futures::channel::mpsc;
+
+    use ring::rand::SystemRandom;
+
+    use livecore_protocol::Uuid;
+
+    use crate::platform::{PeerConnection, PeerConnectionError};
+    use super::{encrypt, EphemeralKey};
+
+    /// Two ends of an unencrypted link.
+    fn pair() -> (PeerConnection, PeerConnection) {
+        fn conn(sink: mpsc::UnboundedSender<Vec<u8>>, source: mpsc::UnboundedReceiver<Vec<u8>>) -> PeerConnection {
+            PeerConnection {
+                sink: Box::pin(sink.sink_map_err(|_| PeerConnectionError::Disconnected)),
+                source: Box::pin(source.map(|data| Ok(Bytes::from(data)))),
+            }
+        }
+        let (s1, r1) = mpsc::unbounded();
+        let (s2, r2) = mpsc::unbounded();
+        (conn(s1, r2), conn(s2, r1))
+    }
+
+    /// Encrypts `a` and `b` as the two sides of the same link.
+    fn encrypt_pair(a: PeerConnection, b: PeerConnection) -> (PeerConnection, PeerConnection) {
+        let rand = SystemRandom::new();
+        let (a_key, b_key) = (EphemeralKey::generate(&rand), EphemeralKey::generate(&rand));
+        let (a_pub, b_pub) = (a_key.public_key().to_owned(), b_key.public_key().to_owned());
+        let a_nonce = Uuid::from_u128(1);
+        let b_nonce = Uuid::from_u128(2);
+
+        let a = encrypt(a, a_key, &b_pub, &a_nonce, &b_nonce).unwrap();
+        let b = encrypt(b, b_key, &a_pub, &b_nonce, &a_nonce).unwrap();
+        (a, b)
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let (a, b) = pair();
+        let (mut a, mut b) = encrypt_pair(a, b);
+
+        for i in 0..3u8 {
+            a.sink.send(vec![i; 100]).await.unwrap();
+            b.sink.send(vec![i]).await.unwrap();
+            assert_eq!(b.source.next().await.unwrap().unwrap(), vec![i; 100]);
+            assert_eq!(a.source.next().await.unwrap().unwrap(), vec![i]);
+        }
+    }
+
+    /// An encrypted link where frames from `a` go through a wire, where the
+    /// test can see and modify them before they are passed on to `b`.
+    fn tapped() -> (PeerConnection, PeerConnection, PeerConnection, PeerConnection) {
+        let (a, wire_in) = pair();
+        let (wire_out, b) = pair();
+        let (a, b) = encrypt_pair(a, b);
+        (a, wire_in, wire_out, b)
+    }
+
+    #[tokio::test]
+    async fn replayed_frames_are_rejected() {
+        let (mut a, mut wire_in, mut wire_out, mut b) = tapped();
+
+        a.sink.send(vec![1, 2, 3]).await.unwrap();
+        let frame = wire_in.source.next().await.unwrap().unwrap();
+        assert_ne!(&frame[..3], &[1, 2, 3]);
+        assert_eq!(frame.len(), 3 + 16);
+
+        wire_out.sink.send(frame.to_vec()).await.unwrap();
+        assert_eq!(b.source.next().await.unwrap().unwrap(), vec![1, 2, 3]);
+
+        wire_out.sink.send(frame.to_vec()).await.unwrap();
+        assert_eq!(
+            b.source.next().await.unwrap(),
+            Err(PeerConnectionError::Protocol("invalid encrypted frame")),
+        );
+    }
+
+    #[tokio::test]
+    async fn modified_frames_are_rejected() {
+        let (mut a, mut wire_in, mut wire_out, mut b) = tapped();
+
+        a.sink.send(vec![1, 2, 3]).await.unwrap();
+        let mut frame = wire_in.source.next().await.unwrap().unwrap().to_vec();
+        frame[0] ^= 1;
+
+        wire_out.sink.send(frame).await.unwrap();
+        assert_eq!(
+            b.source.next().await.unwrap(),
+            Err(PeerConnectionError::Protocol("invalid encrypted frame")),
+        );
+    }
+
+    #[tokio::test]
+    async fn frames_are_opened_in_place() {
+        let (mut a, mut wire_in, mut wire_out, mut b) = tapped();
+
+        a.sink.send(vec![1; 100]).await.unwrap();
+        let frame = wire_in.source.next().await.unwrap().unwrap().to_vec();
+        let frame_ptr = frame.as_ptr();
+
+        wire_out.sink.send(frame).await.unwrap();
+        let data = b.source.next().await.unwrap().unwrap();
+        assert_eq!(data, vec![1; 100]);
+        assert_eq!(data.as_ptr(), frame_ptr);
+    }
+}
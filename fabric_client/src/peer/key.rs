@@ -1,9 +1,9 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use livecore_protocol as proto;
 use proto::Uuid;
 
-use ring::signature::UnparsedPublicKey;
+use ring::signature::{self, UnparsedPublicKey};
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum KeyCapability {
@@ -14,8 +14,58 @@ pub enum KeyCapability {
     /// peer connections.
     OriginateStream(Uuid),
 }
+impl From<proto::Capability> for KeyCapability {
+    fn from(capability: proto::Capability) -> Self {
+        match capability {
+            proto::Capability::OriginateStream { stream_uuid } => KeyCapability::OriginateStream(stream_uuid),
+        }
+    }
+}
 
 pub struct Key {
     key: UnparsedPublicKey<Vec<u8>>,
     capabilities: HashSet<KeyCapability>,
 }
+impl Key {
+    pub fn new(pubkey: Vec<u8>) -> Self {
+        let algo = &signature::ECDSA_P256_SHA256_FIXED;
+        Key {
+            key: UnparsedPublicKey::new(algo, pubkey),
+            capabilities: HashSet::new(),
+        }
+    }
+
+    pub fn grant(&mut self, capability: KeyCapability) {
+        self.capabilities.insert(capability);
+    }
+
+    pub fn has_capability(&self, capability: &KeyCapability) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    pub fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        self.key.verify(data, signature).is_ok()
+    }
+}
+
+/// The keys the orchestrator has granted capabilities to, by pubkey.
+pub struct KeyRing {
+    keys: HashMap<Vec<u8>, Key>,
+}
+impl KeyRing {
+    pub fn new() -> Self {
+        KeyRing {
+            keys: HashMap::new(),
+        }
+    }
+
+    pub fn grant(&mut self, pubkey: Vec<u8>, capability: KeyCapability) {
+        self.keys.entry(pubkey.clone())
+            .or_insert_with(|| Key::new(pubkey))
+            .grant(capability);
+    }
+
+    pub fn get(&self, pubkey: &[u8]) -> Option<&Key> {
+        self.keys.get(pubkey)
+    }
+}
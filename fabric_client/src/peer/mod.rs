@@ -1,9 +1,106 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+
 use livecore_protocol as proto;
-use proto::Uuid;
+use proto::{Hash, Uuid};
+
+pub(crate) mod key;
+pub(crate) mod connection;
+pub(crate) mod auth;
+pub(crate) mod crypto;
+
+/// Traffic on a peer link, counted by the connection task.
+#[derive(Default)]
+pub(crate) struct LinkCounters {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+impl LinkCounters {
+    pub fn add_in(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+    pub fn add_out(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
 
-mod key;
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+}
 
 pub(crate) struct PeerState {
     uuid: Uuid,
     key: key::Key,
+
+    sender: mpsc::UnboundedSender<proto::PeerMsg>,
+    counters: Arc<LinkCounters>,
+
+    /// Fragments the peer has announced that it has.
+    has: HashSet<Hash>,
+
+    /// Latest round trip time measured on the link.
+    rtt: Option<Duration>,
+}
+impl PeerState {
+    pub fn new(
+        uuid: Uuid,
+        pubkey: Vec<u8>,
+        sender: mpsc::UnboundedSender<proto::PeerMsg>,
+        counters: Arc<LinkCounters>,
+    ) -> Self {
+        PeerState {
+            uuid,
+            key: key::Key::new(pubkey),
+            sender,
+            counters,
+            has: HashSet::new(),
+            rtt: None,
+        }
+    }
+
+    pub fn uuid(&self) -> Uuid {
+        self.uuid
+    }
+
+    /// Queues a message to be sent to the peer.
+    /// If the connection task has already exited, the message is dropped.
+    pub fn send(&self, msg: proto::PeerMsg) {
+        let _ = self.sender.send(msg);
+    }
+
+    /// Whether the connection task has exited.
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    pub fn counters(&self) -> &LinkCounters {
+        &self.counters
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    pub fn set_rtt(&mut self, rtt: Duration) {
+        self.rtt = Some(rtt);
+    }
+
+    pub fn has(&self, hash: &Hash) -> bool {
+        self.has.contains(hash)
+    }
+
+    pub fn add_have(&mut self, hashes: &[Hash]) {
+        self.has.extend(hashes.iter().cloned());
+    }
+
+    pub fn remove_have(&mut self, hash: &Hash) {
+        self.has.remove(hash);
+    }
 }
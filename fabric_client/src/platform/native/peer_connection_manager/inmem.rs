@@ -1,84 +1,399 @@
-use std::sync::Mutex;
+//! In-memory peer connector.
+//!
+//! Connects peers within the same process over bounded channels. All managers
+//! created from the same `InMemNetwork` can reach each other, which makes it
+//! possible to run many fabric nodes in a single process without opening any
+//! sockets.
+//!
+//! The connector in the `ConnectPeer` message is ignored, every connection is
+//! routed through the network and matched on the orchestrator nonces.
+
+use std::sync::{Arc, Weak, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::collections::HashMap;
+use std::future::Future;
 use std::pin::Pin;
 use std::task::{Poll, Context};
+use std::time::Duration;
 
-use tokio::sync::{mpsc, Notify};
+use tokio::time::Instant;
 
+use futures::channel::mpsc;
+use futures::task::AtomicWaker;
 use futures::{Stream, Sink};
 
+use bytes::Bytes;
+
+use anyhow::{Result, Context as _, bail};
+
 use livecore_protocol as proto;
 use proto::Uuid;
 
-use crate::platform::{PeerConnection, PeerConnectionError};
+use crate::util::matcher::Matcher;
+use crate::platform::{PeerConnection, PeerConnectionManager, PeerTunnel, PeerConnectionError};
 
-lazy_static::lazy_static! {
-    static ref MATCHER: Mutex<HashMap<(Uuid, Uuid), PeerConnection>> = {
-        Mutex::new(HashMap::new())
-    };
+const DEFAULT_CAPACITY: usize = 16;
+
+type InMemMatcher = Matcher<Uuid, PeerConnection>;
+
+/// Shared state of a single in-memory link, used to simulate a disconnect.
+///
+/// Each sink and source of the link has its own waker slot, so that every
+/// end is woken up when the link is severed.
+struct LinkState {
+    severed: AtomicBool,
+    /// Messages are silently dropped, like on a half-open connection.
+    stalled: AtomicBool,
+    wakers: [AtomicWaker; 4],
 }
+impl LinkState {
+    fn new() -> Self {
+        Self {
+            severed: AtomicBool::new(false),
+            stalled: AtomicBool::new(false),
+            wakers: [
+                AtomicWaker::new(),
+                AtomicWaker::new(),
+                AtomicWaker::new(),
+                AtomicWaker::new(),
+            ],
+        }
+    }
+
+    fn sever(&self) {
+        self.severed.store(true, Ordering::SeqCst);
+        for waker in self.wakers.iter() {
+            waker.wake();
+        }
+    }
 
-struct MpscSink(mpsc::UnboundedSender<Vec<u8>>);
+    fn is_severed(&self, slot: usize, cx: &mut Context) -> bool {
+        self.wakers[slot].register(cx.waker());
+        self.severed.load(Ordering::SeqCst)
+    }
+}
+
+struct MpscSink {
+    inner: mpsc::Sender<Vec<u8>>,
+    link: Arc<LinkState>,
+    slot: usize,
+}
 impl Sink<Vec<u8>> for MpscSink {
     type Error = PeerConnectionError;
     fn poll_ready(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut Context,
     ) -> Poll<Result<(), Self::Error>> {
-        todo!()
+        if self.link.is_severed(self.slot, cx) {
+            return Poll::Ready(Err(PeerConnectionError::Disconnected));
+        }
+        Pin::new(&mut self.inner).poll_ready(cx)
+            .map_err(|_| PeerConnectionError::Disconnected)
     }
     fn start_send(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         item: Vec<u8>,
     ) -> Result<(), Self::Error> {
-        todo!()
+        if self.link.severed.load(Ordering::SeqCst) {
+            return Err(PeerConnectionError::Disconnected);
+        }
+        if self.link.stalled.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        Pin::new(&mut self.inner).start_send(item)
+            .map_err(|_| PeerConnectionError::Disconnected)
     }
     fn poll_flush(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut Context,
     ) -> Poll<Result<(), Self::Error>> {
-        todo!()
+        Pin::new(&mut self.inner).poll_flush(cx)
+            .map_err(|_| PeerConnectionError::Disconnected)
     }
     fn poll_close(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut Context,
     ) -> Poll<Result<(), Self::Error>> {
-        todo!()
+        Pin::new(&mut self.inner).poll_close(cx)
+            .map_err(|_| PeerConnectionError::Disconnected)
     }
 }
 
-struct MpscSource(mpsc::UnboundedReceiver<Vec<u8>>);
+struct MpscSource {
+    inner: mpsc::Receiver<Vec<u8>>,
+    link: Arc<LinkState>,
+    slot: usize,
+    done: bool,
+}
 impl Stream for MpscSource {
-    type Item = Result<Vec<u8>, PeerConnectionError>;
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Vec<u8>, PeerConnectionError>>> {
-        match self.0.poll_recv(cx) {
+    type Item = Result<Bytes, PeerConnectionError>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Bytes, PeerConnectionError>>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        if self.link.is_severed(self.slot, cx) {
+            // A severed link yields a single error, then ends.
+            self.done = true;
+            return Poll::Ready(Some(Err(PeerConnectionError::Disconnected)));
+        }
+        if self.link.stalled.load(Ordering::SeqCst) {
+            // Not even the other end closing is noticed.
+            return Poll::Pending;
+        }
+        match Pin::new(&mut self.inner).poll_next(cx) {
             Poll::Pending => Poll::Pending,
-            Poll::Ready(Some(inner)) => Poll::Ready(Some(Ok(inner))),
-            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Ready(Some(inner)) => Poll::Ready(Some(Ok(inner.into()))),
+            Poll::Ready(None) => {
+                self.done = true;
+                Poll::Ready(None)
+            },
         }
     }
 }
 
-pub (super) fn do_connect(self_nonce: Uuid, other_nonce: Uuid) -> PeerConnection {
-    let mut lock = (*MATCHER).lock().unwrap();
+/// Creates both ends of a new link.
+fn new_link(capacity: usize) -> (Arc<LinkState>, PeerConnection, PeerConnection) {
+    let link = Arc::new(LinkState::new());
 
-    if let Some(conn) = lock.remove(&(other_nonce, self_nonce)) {
-        conn
-    } else {
-        let (s1, r1) = mpsc::unbounded_channel();
-        let (s2, r2) = mpsc::unbounded_channel();
+    let (s1, r1) = mpsc::channel(capacity);
+    let (s2, r2) = mpsc::channel(capacity);
 
-        lock.insert(
-            (self_nonce, other_nonce),
-            PeerConnection {
-                sink: Box::pin(MpscSink(s1)),
-                source: Box::pin(MpscSource(r2)),
-            },
-        );
+    let a = PeerConnection {
+        sink: Box::pin(MpscSink { inner: s1, link: link.clone(), slot: 0 }),
+        source: Box::pin(MpscSource { inner: r2, link: link.clone(), slot: 1, done: false }),
+    };
+    let b = PeerConnection {
+        sink: Box::pin(MpscSink { inner: s2, link: link.clone(), slot: 2 }),
+        source: Box::pin(MpscSource { inner: r1, link: link.clone(), slot: 3, done: false }),
+    };
+
+    (link, a, b)
+}
+
+struct InMemNetworkInner {
+    capacity: usize,
+    matcher: Arc<InMemMatcher>,
+    links: Mutex<HashMap<Uuid, Weak<LinkState>>>,
+}
+
+/// A set of peers that are able to connect to each other in memory.
+#[derive(Clone)]
+pub struct InMemNetwork {
+    inner: Arc<InMemNetworkInner>,
+}
+impl InMemNetwork {
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    /// Creates a network where every link direction buffers at most
+    /// `capacity` messages before applying backpressure to the sender.
+    pub fn with_capacity(capacity: usize) -> Self {
+        InMemNetwork {
+            inner: Arc::new(InMemNetworkInner {
+                capacity,
+                matcher: Matcher::new(),
+                links: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    pub fn manager(&self) -> InMemPeerConnectionManager {
+        InMemPeerConnectionManager {
+            network: self.clone(),
+        }
+    }
+
+    /// Simulates a disconnect of the link established with the given nonce.
+    /// Either of the two nonces of the link may be used.
+    ///
+    /// Both ends of the link will fail on their next send, and their sources
+    /// will yield an error before ending.
+    ///
+    /// Returns `false` if there was no live link for the nonce.
+    pub fn disconnect(&self, nonce: Uuid) -> bool {
+        let link = {
+            let mut links = self.inner.links.lock().unwrap();
+            let link = links.remove(&nonce);
+            if let Some(link) = &link {
+                links.retain(|_, other| !Weak::ptr_eq(link, other));
+            }
+            link.and_then(|link| link.upgrade())
+        };
+
+        if let Some(link) = link {
+            link.sever();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Simulates a half-open link: nothing sent in either direction after
+    /// this arrives, and neither end sees an error or the other end closing.
+    ///
+    /// Returns `false` if there was no live link for the nonce.
+    pub fn stall(&self, nonce: Uuid) -> bool {
+        let link = self.inner.links.lock().unwrap()
+            .get(&nonce)
+            .and_then(|link| link.upgrade());
 
-        PeerConnection {
-            sink: Box::pin(MpscSink(s2)),
-            source: Box::pin(MpscSource(r1)),
+        if let Some(link) = link {
+            link.stalled.store(true, Ordering::SeqCst);
+            true
+        } else {
+            false
         }
     }
+
+    fn register_link(&self, link: &Arc<LinkState>, self_nonce: Uuid, peer_nonce: Uuid) {
+        let mut links = self.inner.links.lock().unwrap();
+        links.retain(|_, link| link.strong_count() > 0);
+        links.insert(self_nonce, Arc::downgrade(link));
+        links.insert(peer_nonce, Arc::downgrade(link));
+    }
+
+    async fn connect(self, self_nonce: Uuid, peer_nonce: Uuid) -> Result<PeerConnection> {
+        let timeout_time = Instant::now() + Duration::from_millis(10000);
+
+        // Both sides of a link use the lowest nonce as the matcher key. The
+        // side owning that nonce creates the link and holds the other end
+        // until the peer shows up.
+        if self_nonce < peer_nonce {
+            let (link, conn, peer_conn) = new_link(self.inner.capacity);
+            self.register_link(&link, self_nonce, peer_nonce);
+
+            log::info!("holding in-memory peer connection");
+            self.inner.matcher.send(&self_nonce, timeout_time, peer_conn)
+                .await
+                .context("in-memory peer never claimed connection")?;
+
+            Ok(conn)
+        } else if self_nonce > peer_nonce {
+            log::info!("waiting for in-memory peer connection");
+            let conn = self.inner.matcher.receive(&peer_nonce, timeout_time)
+                .await
+                .context("failed to get connection from matcher")?;
+
+            Ok(conn)
+        } else {
+            bail!("in-memory peer connection with identical nonces");
+        }
+    }
+}
+
+pub struct InMemPeerConnectionManager {
+    network: InMemNetwork,
+}
+
+impl PeerConnectionManager for InMemPeerConnectionManager {
+    fn start_connect_peer(
+        &self,
+        _peer_tunnel: PeerTunnel,
+        _conn_type: proto::Connector,
+        self_nonce: Uuid,
+        peer_nonce: Uuid,
+    ) -> Pin<Box<dyn Future<Output = Result<PeerConnection>> + Send>> {
+        Box::pin(self.network.clone().connect(self_nonce, peer_nonce))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{StreamExt, SinkExt};
+    use futures::task::Poll;
+
+    use livecore_protocol as proto;
+    use proto::Uuid;
+
+    use crate::platform::{PeerConnection, PeerTunnel, PeerConnectionManager};
+    use super::InMemNetwork;
+
+    async fn connect_pair(network: &InMemNetwork) -> (PeerConnection, PeerConnection) {
+        let a_nonce = Uuid::from_bytes([1u8; 16]);
+        let b_nonce = Uuid::from_bytes([2u8; 16]);
+
+        let a = network.manager().start_connect_peer(
+            PeerTunnel::new_dummy(),
+            proto::Connector::WebsocketServer,
+            a_nonce,
+            b_nonce,
+        );
+        let b = network.manager().start_connect_peer(
+            PeerTunnel::new_dummy(),
+            proto::Connector::WebsocketServer,
+            b_nonce,
+            a_nonce,
+        );
+
+        let (a, b) = futures::join!(a, b);
+        (a.unwrap(), b.unwrap())
+    }
+
+    #[tokio::test]
+    async fn bidirectional() {
+        let network = InMemNetwork::new();
+        let (mut a, mut b) = connect_pair(&network).await;
+
+        a.sink.send(vec![1, 2, 3]).await.map_err(|_| ()).unwrap();
+        b.sink.send(vec![4, 5, 6]).await.map_err(|_| ()).unwrap();
+
+        let recv = b.source.next().await.unwrap().map_err(|_| ()).unwrap();
+        assert!(recv == vec![1, 2, 3]);
+        let recv = a.source.next().await.unwrap().map_err(|_| ()).unwrap();
+        assert!(recv == vec![4, 5, 6]);
+
+        // Dropping one end closes the link cleanly.
+        std::mem::drop(a);
+        assert!(b.source.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn backpressure() {
+        let network = InMemNetwork::with_capacity(1);
+        let (mut a, mut b) = connect_pair(&network).await;
+
+        a.sink.send(vec![1]).await.map_err(|_| ()).unwrap();
+
+        // The buffer is full, the send does not finish until the peer
+        // receives.
+        let send = a.sink.send(vec![2]);
+        tokio::pin!(send);
+        assert!(futures::poll!(&mut send).is_pending());
+
+        let recv = b.source.next().await.unwrap().map_err(|_| ()).unwrap();
+        assert!(recv == vec![1]);
+
+        assert!(matches!(futures::poll!(&mut send), Poll::Ready(Ok(()))));
+    }
+
+    #[tokio::test]
+    async fn disconnect() {
+        let network = InMemNetwork::new();
+        let (mut a, mut b) = connect_pair(&network).await;
+
+        assert!(network.disconnect(Uuid::from_bytes([2u8; 16])));
+        assert!(!network.disconnect(Uuid::from_bytes([1u8; 16])));
+
+        assert!(a.sink.send(vec![1]).await.is_err());
+        assert!(b.source.next().await.unwrap().is_err());
+        assert!(b.source.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn stall() {
+        let network = InMemNetwork::new();
+        let (mut a, mut b) = connect_pair(&network).await;
+
+        assert!(network.stall(Uuid::from_bytes([1u8; 16])));
+
+        a.sink.send(vec![1]).await.map_err(|_| ()).unwrap();
+        b.sink.send(vec![2]).await.map_err(|_| ()).unwrap();
+        assert!(futures::poll!(b.source.next()).is_pending());
+        assert!(futures::poll!(a.source.next()).is_pending());
+
+        std::mem::drop(a);
+        assert!(futures::poll!(b.source.next()).is_pending());
+    }
+
 }
@@ -86,13 +86,13 @@ pub async fn connect(matcher: Arc<IPCMatcher>, self_nonce: Uuid, other_nonce: Uu
 
     let sink = sink
         .with(|bin: Vec<u8>| async { Ok(bin.into()) })
-        .sink_map_err(|_err: std::io::Error| PeerConnectionError::Wat);
+        .sink_map_err(|err: std::io::Error| PeerConnectionError::Transport(err.to_string()));
 
     let source = source
         .map(|val| {
             match val {
-                Ok(bin) => Ok(bin.as_ref().to_owned()),
-                Err(_) => Err(PeerConnectionError::Wat),
+                Ok(bin) => Ok(bin.freeze()),
+                Err(err) => Err(PeerConnectionError::Transport(err.to_string())),
             }
         });
 
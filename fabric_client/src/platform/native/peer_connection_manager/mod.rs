@@ -7,7 +7,7 @@ use std::future::Future;
 use std::sync::Arc;
 use std::pin::Pin;
 
-use anyhow::Result;
+use anyhow::{Result, bail};
 
 use tokio::task::spawn;
 use tokio::net::{TcpListener, UnixListener};
@@ -22,6 +22,8 @@ mod ipc;
 
 #[cfg(feature = "inmem_peer")]
 mod inmem;
+#[cfg(feature = "inmem_peer")]
+pub use inmem::{InMemNetwork, InMemPeerConnectionManager};
 
 pub struct NativePeerConnectionManagerBuilder {
     ws_listener: Option<TcpListener>,
@@ -103,23 +105,23 @@ impl PeerConnectionManager for NativePeerConnectionManager {
                     return Ok(ipc::server::connect(ipc_matcher, self_nonce, peer_nonce).await?);
 
                     #[cfg(not(feature = "ipc_peer"))]
-                    panic!("ipc server not supported");
+                    bail!("ipc server not supported");
                 },
                 proto::Connector::IpcClient(data) => {
                     #[cfg(feature = "ipc_peer")]
                     return Ok(ipc::client::connect(&data.socket_path, self_nonce, peer_nonce).await?);
 
                     #[cfg(not(feature = "ipc_peer"))]
-                    panic!("ipc client not supported");
+                    bail!("ipc client not supported");
                 },
                 proto::Connector::WebsocketServer => {
-                    todo!()
+                    ws::server::do_start_connect_incoming(ws_matcher, self_nonce, peer_nonce).await
                 },
                 proto::Connector::WebsocketClient(data) => {
-                    todo!()
+                    ws::client::do_start_connect_outgoing(data.url, self_nonce, peer_nonce).await
                 },
                 proto::Connector::WebRTC => {
-                    todo!()
+                    bail!("webrtc not supported");
                 },
             }
         }
@@ -140,46 +142,52 @@ impl PeerConnectionManager for NativePeerConnectionManager {
 mod tests {
     use futures::{StreamExt, SinkExt};
 
+    use tokio::net::TcpListener;
+
+    use livecore_protocol as proto;
+    use proto::Uuid;
+
     use crate::platform::{PeerTunnel, PeerConnectionManager};
-    use super::NativePeerConnectionManager;
+    use super::NativePeerConnectionManagerBuilder;
 
     #[tokio::test]
     async fn test_connect() {
-        let conn_manager_1 = NativePeerConnectionManager::new();
-        let conn_manager_2 = conn_manager_1.clone();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let conn_manager_1 = NativePeerConnectionManagerBuilder::new()
+            .with_ws_listener(listener)
+            .build();
+        let conn_manager_2 = NativePeerConnectionManagerBuilder::new()
+            .build();
 
-        let a_nonce_1 = vec![1u8; 32];
+        let a_nonce_1 = Uuid::from_bytes([1u8; 16]);
         let a_nonce_2 = a_nonce_1.clone();
-        let b_nonce_1 = vec![2u8; 32];
+        let b_nonce_1 = Uuid::from_bytes([2u8; 16]);
         let b_nonce_2 = b_nonce_1.clone();
 
         let h1 = tokio::spawn(async move {
-            let dummy_tunnel = PeerTunnel::new_dummy();
             let incoming_fut = conn_manager_1.start_connect_peer(
-                dummy_tunnel,
-                serde_json::json!({
-                    "kind": "websocket_in",
-                    "self_nonce": a_nonce_1,
-                    "other_nonce": b_nonce_1,
-                }),
+                PeerTunnel::new_dummy(),
+                proto::Connector::WebsocketServer,
+                a_nonce_1,
+                b_nonce_1,
             );
-            let mut peer = incoming_fut.await;
+            let mut peer = incoming_fut.await.unwrap();
 
             peer.sink.send(vec![1, 2, 3]).await.map_err(|_| ()).unwrap();
         });
 
         let h2 = tokio::spawn(async move {
-            let dummy_tunnel = PeerTunnel::new_dummy();
             let outgoing_fut = conn_manager_2.start_connect_peer(
-                dummy_tunnel,
-                serde_json::json!({
-                    "kind": "websocket_out",
-                    "url": "ws://localhost:6789",
-                    "self_nonce": b_nonce_2,
-                    "other_nonce": a_nonce_2,
+                PeerTunnel::new_dummy(),
+                proto::Connector::WebsocketClient(proto::WebsocketClient {
+                    url: format!("ws://{}", addr),
                 }),
+                b_nonce_2,
+                a_nonce_2,
             );
-            let mut peer = outgoing_fut.await;
+            let mut peer = outgoing_fut.await.unwrap();
 
             let recv = peer.source.next().await.unwrap().map_err(|_| ()).unwrap();
             assert!(recv == vec![1, 2, 3]);
@@ -190,4 +198,40 @@ mod tests {
         r2.unwrap();
     }
 
+    #[tokio::test]
+    async fn test_connect_wrong_nonce() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let conn_manager_1 = NativePeerConnectionManagerBuilder::new()
+            .with_ws_listener(listener)
+            .build();
+        let conn_manager_2 = NativePeerConnectionManagerBuilder::new()
+            .build();
+
+        let a_nonce = Uuid::from_bytes([1u8; 16]);
+        let b_nonce = Uuid::from_bytes([2u8; 16]);
+        let c_nonce = Uuid::from_bytes([3u8; 16]);
+
+        let h1 = tokio::spawn(conn_manager_1.start_connect_peer(
+            PeerTunnel::new_dummy(),
+            proto::Connector::WebsocketServer,
+            a_nonce,
+            b_nonce,
+        ));
+
+        let h2 = tokio::spawn(conn_manager_2.start_connect_peer(
+            PeerTunnel::new_dummy(),
+            proto::Connector::WebsocketClient(proto::WebsocketClient {
+                url: format!("ws://{}", addr),
+            }),
+            b_nonce,
+            c_nonce,
+        ));
+
+        // The outgoing side expects the nonce `c`, but receives `a`.
+        assert!(h2.await.unwrap().is_err());
+        assert!(h1.await.unwrap().is_ok());
+    }
+
 }
@@ -1,3 +1,4 @@
+use std::io::Write;
 use std::time::Duration;
 
 use tokio::time::timeout_at;
@@ -6,78 +7,69 @@ use futures::sink::SinkExt;
 use futures::stream::StreamExt;
 
 use tokio_tungstenite::tungstenite::Message as TMessage;
-use tokio_tungstenite::tungstenite;
 
-use crate::platform::{PeerConnection, PeerConnectionManager, PeerTunnel, PeerConnectionError};
+use anyhow::{Result, Context, ensure, bail};
 
-pub async fn do_start_connect_outgoing(url: String, self_nonce: Vec<u8>, other_nonce: Vec<u8>) -> PeerConnection {
+use livecore_protocol::Uuid;
+
+use crate::platform::PeerConnection;
+
+pub async fn do_start_connect_outgoing(url: String, self_nonce: Uuid, other_nonce: Uuid) -> Result<PeerConnection> {
     let timeout_time = tokio::time::Instant::now() + Duration::from_millis(10000);
 
     let res = timeout_at(timeout_time, tokio_tungstenite::connect_async(url)).await;
     let (mut ws_stream, _response) = match res {
         Ok(Ok((ws_stream, response))) => (ws_stream, response),
-        Ok(Err(_)) => todo!(),
+        Ok(Err(error)) => {
+            return Err(error).context("failed to open WS peer connection");
+        }
         Err(_) => {
-            // TODO Timeout
-            todo!()
+            log::warn!("WS peer connect timed out");
+            bail!("WS peer connection timed out");
         },
     };
 
-    // TODO error
-    ws_stream.send(TMessage::Binary(self_nonce)).await.unwrap();
+    let mut buf = Vec::new();
+    write!(buf, "{}", self_nonce).unwrap();
+    ws_stream.send(TMessage::Binary(buf)).await.context("failed to send nonce to peer")?;
 
-    // Expect a single message with a 32 byte nonce from the peer.
+    // Expect a single message with the nonce of the peer.
     let nonce = loop {
-        match tokio::time::timeout_at(timeout_time, ws_stream.next()).await {
-            Ok(Some(Ok(TMessage::Binary(nonce)))) if nonce.len() == 32 => {
-                log::info!("received nonce for outgoing WS connection");
-                break nonce;
+        match timeout_at(timeout_time, ws_stream.next()).await {
+            Ok(Some(Ok(TMessage::Binary(inner)))) => {
+                if let Some(uuid) = crate::util::uuid::parse_uuid(&inner) {
+                    log::info!("received nonce for outgoing WS connection");
+                    break uuid;
+                } else {
+                    log::warn!("WS peer sent invalid handshake nonce");
+                    bail!("WS peer sent invalid handshake nonce");
+                }
             }
 
             // No item in stream, socket closed immediately.
-            Ok(None) => {
+            Ok(None) | Ok(Some(Ok(TMessage::Close(_)))) => {
                 log::warn!("outgoing WS closed without nonce");
-                todo!()
+                bail!("WS peer connection closed without handshake nonce");
             }
             Ok(Some(Err(ws_error))) => {
                 log::warn!("failed to receive handhake from outgoing WS peer connection ({})", ws_error);
-                todo!()
+                return Err(ws_error).context("WS peer connection error");
             }
-            Ok(Some(Ok(TMessage::Close(_)))) => unreachable!(),
-            Ok(Some(Ok(TMessage::Binary(_)))) | Ok(Some(Ok(TMessage::Text(_)))) => {
+            Ok(Some(Ok(TMessage::Text(_)))) => {
                 log::warn!("received invalid handshake for outgoing peer WS");
-                todo!()
+                bail!("received invalid handshake for outgoing peer WS");
             }
             Ok(_) => continue,
             Err(_) => {
                 log::warn!("WS peer handshake timed out");
-                todo!()
+                bail!("WS peer connection handshake timed out");
             }
         }
     };
 
-    if nonce != other_nonce {
-        // nonce check failed, connection failed
-        todo!()
-    }
+    ensure!(nonce == other_nonce, "received non-matching nonce on peer handshake");
 
-    let (sink, source) = ws_stream.split();
-
-    let sink = sink
-        .with(|bin| async { Ok(TMessage::Binary(bin)) })
-        .sink_map_err(|_err: tungstenite::Error| PeerConnectionError::Wat);
-
-    let source = source
-        .map(|val| {
-            match val {
-                Ok(TMessage::Binary(bin)) => Ok(bin),
-                Ok(_) => todo!(),
-                Err(_) => Err(PeerConnectionError::Wat),
-            }
-        });
+    log::info!("handshaked to peer WS");
 
-    PeerConnection {
-        sink: Box::pin(sink),
-        source: Box::pin(source),
-    }
+    Ok(super::into_peer_connection(ws_stream))
 }
@@ -1,2 +1,46 @@
+use futures::future;
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use tokio_tungstenite::{tungstenite::Message as TMessage, WebSocketStream};
+use tokio_tungstenite::tungstenite;
+
+use crate::platform::{PeerConnection, PeerConnectionError};
+
 pub mod client;
 pub mod server;
+
+/// Wraps a handshaked WS stream into a `PeerConnection`.
+///
+/// Only binary messages carry peer data. Control frames are handled by
+/// tungstenite and skipped, a text message is a protocol error.
+fn into_peer_connection<S>(ws_stream: WebSocketStream<S>) -> PeerConnection
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sink, source) = ws_stream.split();
+
+    let sink = sink
+        .with(|bin| async { Ok(TMessage::Binary(bin)) })
+        .sink_map_err(|err: tungstenite::Error| PeerConnectionError::Transport(err.to_string()));
+
+    let source = source
+        .filter_map(|val| {
+            future::ready(match val {
+                Ok(TMessage::Binary(bin)) => Some(Ok(bin.into())),
+                Ok(TMessage::Text(_)) => {
+                    log::warn!("received text message on WS peer connection");
+                    Some(Err(PeerConnectionError::Protocol("unexpected text message")))
+                }
+                Ok(_) => None,
+                Err(err) => Some(Err(PeerConnectionError::Transport(err.to_string()))),
+            })
+        });
+
+    PeerConnection {
+        sink: Box::pin(sink),
+        source: Box::pin(source),
+    }
+}
@@ -3,6 +3,7 @@
 //! Uses a `Matcher` to match incoming WS connections to connection requests
 //! from the orchestrator. A timeout is used to prevent DOS attacks.
 
+use std::io::Write;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
@@ -15,12 +16,15 @@ use futures::sink::SinkExt;
 use futures::stream::StreamExt;
 
 use tokio_tungstenite::{tungstenite::Message as TMessage, WebSocketStream};
-use tokio_tungstenite::tungstenite;
 
-use crate::util::matcher::{HoldSuccess, HoldError, PermitError, Matcher};
-use crate::platform::{PeerConnection, PeerConnectionManager, PeerTunnel, PeerConnectionError};
+use anyhow::{Result, Context};
 
-pub type WSMatcher = Matcher<Vec<u8>, WebSocketStream<TcpStream>>;
+use livecore_protocol::Uuid;
+
+use crate::util::matcher::Matcher;
+use crate::platform::PeerConnection;
+
+pub type WSMatcher = Matcher<Uuid, WebSocketStream<TcpStream>>;
 
 async fn accept_ws(
     matcher: Arc<WSMatcher>,
@@ -42,19 +46,24 @@ async fn accept_ws(
             }
         };
 
-    // Expect a single message with a 32 byte nonce from the peer.
+    // Expect a single message with the nonce of the peer.
     let (nonce, ws_stream) = loop {
         match tokio::time::timeout_at(timeout_time, ws_stream.next()).await {
-            Ok(Some(Ok(TMessage::Binary(nonce)))) if nonce.len() == 32 => {
-                log::info!(
-                    "received handshake noce for incoming peer WS, posting to matcher ({})",
-                    addr
-                );
-                break (nonce, ws_stream);
+            Ok(Some(Ok(TMessage::Binary(inner)))) => {
+                if let Some(uuid) = crate::util::uuid::parse_uuid(&inner) {
+                    log::info!(
+                        "received handshake noce for incoming peer WS, posting to matcher ({})",
+                        addr
+                    );
+                    break (uuid, ws_stream);
+                } else {
+                    log::warn!("WS peer sent invalid handshake nonce ({})", addr);
+                    return;
+                }
             }
 
             // No item in stream, socket closed immediately.
-            Ok(None) => {
+            Ok(None) | Ok(Some(Ok(TMessage::Close(_)))) => {
                 log::warn!(
                     "incoming WS peer connection closed without handshake message ({})",
                     addr
@@ -69,8 +78,7 @@ async fn accept_ws(
                 );
                 return;
             }
-            Ok(Some(Ok(TMessage::Close(_)))) => unreachable!(),
-            Ok(Some(Ok(TMessage::Binary(_)))) | Ok(Some(Ok(TMessage::Text(_)))) => {
+            Ok(Some(Ok(TMessage::Text(_)))) => {
                 log::warn!("received invalid handshake for incoming peer WS");
                 return;
             }
@@ -100,33 +108,17 @@ pub async fn ws_server(listener: TcpListener, matcher: Arc<WSMatcher>) {
     }
 }
 
-pub async fn do_start_connect_incoming(matcher: Arc<WSMatcher>, self_nonce: Vec<u8>, other_nonce: Vec<u8>) -> PeerConnection {
+pub async fn do_start_connect_incoming(matcher: Arc<WSMatcher>, self_nonce: Uuid, other_nonce: Uuid) -> Result<PeerConnection> {
     let timeout_time = tokio::time::Instant::now() + Duration::from_millis(10000);
 
-    match matcher.receive(&other_nonce, timeout_time).await {
-        Ok(conn) => {
-            let (mut sink, source) = conn.split();
-
-            sink.send(TMessage::Binary(self_nonce)).await.unwrap();
+    log::info!("waiting for incoming peer WS connection");
+    let mut conn = matcher.receive(&other_nonce, timeout_time)
+        .await
+        .context("failed to get connection from matcher")?;
 
-            let sink = sink
-                .with(|bin| async { Ok(TMessage::Binary(bin)) })
-                .sink_map_err(|_err: tungstenite::Error| PeerConnectionError::Wat);
+    let mut buf = Vec::new();
+    write!(buf, "{}", self_nonce).unwrap();
+    conn.send(TMessage::Binary(buf)).await.context("failed to send nonce to peer")?;
 
-            let source = source
-                .map(|val| {
-                    match val {
-                        Ok(TMessage::Binary(bin)) => Ok(bin),
-                        Ok(_) => todo!(),
-                        Err(_) => Err(PeerConnectionError::Wat),
-                    }
-                });
-
-            PeerConnection {
-                sink: Box::pin(sink),
-                source: Box::pin(source),
-            }
-        },
-        Err(error) => todo!(),
-    }
+    Ok(super::into_peer_connection(conn))
 }
@@ -1,6 +1,8 @@
 use futures::sink::{Sink, SinkExt};
 use futures::stream::Stream;
 
+use bytes::Bytes;
+
 use std::future::Future;
 use std::pin::Pin;
 
@@ -32,14 +34,34 @@ impl PeerTunnel {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PeerConnectionError {
-    Wat,
+    /// The link to the peer was closed or severed.
+    Disconnected,
+    /// The underlying transport failed.
+    Transport(String),
+    /// The peer sent something the transport does not accept.
+    Protocol(&'static str),
+}
+impl std::fmt::Display for PeerConnectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Disconnected => write!(f, "disconnected"),
+            Self::Transport(error) => write!(f, "transport error: {}", error),
+            Self::Protocol(error) => write!(f, "protocol error: {}", error),
+        }
+    }
 }
+impl std::error::Error for PeerConnectionError {}
 
+/// A message based link to a peer.
+///
+/// Received frames are `Bytes`, so that decoded payloads can share the
+/// transport buffer instead of being copied out of it.
 pub struct PeerConnection {
     //meta: Box<dyn PeerConnectionMeta>,
     pub sink: Pin<Box<dyn Sink<Vec<u8>, Error = PeerConnectionError> + Send>>,
-    pub source: Pin<Box<dyn Stream<Item = Result<Vec<u8>, PeerConnectionError>> + Send>>,
+    pub source: Pin<Box<dyn Stream<Item = Result<Bytes, PeerConnectionError>> + Send>>,
 }
 
 pub struct PeerData {
@@ -0,0 +1,73 @@
+//! Exponential backoff with jitter, for retrying connections.
+
+use std::time::Duration;
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    attempt: u32,
+    rand: SystemRandom,
+}
+
+impl Backoff {
+
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            attempt: 0,
+            rand: SystemRandom::new(),
+        }
+    }
+
+    /// Returns the delay before the next attempt.
+    ///
+    /// The delay doubles with every attempt, up to `max`. Up to half of it is
+    /// randomly taken off, so that nodes that lost their connection at the
+    /// same time don't all retry at the same time.
+    pub fn next_delay(&mut self) -> Duration {
+        let base = 2u32.checked_pow(self.attempt)
+            .and_then(|factor| self.min.checked_mul(factor))
+            .map(|delay| delay.min(self.max))
+            .unwrap_or(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+
+        let mut bytes = [0; 4];
+        self.rand.fill(&mut bytes).unwrap();
+        let jitter = u32::from_le_bytes(bytes) as f64 / u32::MAX as f64;
+
+        base - base.mul_f64(jitter / 2.0)
+    }
+
+    /// Starts over from `min`, should be called once a connection succeeds.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Backoff;
+
+    #[test]
+    fn bounded_delays() {
+        let min = Duration::from_millis(100);
+        let max = Duration::from_secs(10);
+        let mut backoff = Backoff::new(min, max);
+
+        for attempt in 0..100 {
+            let base = if attempt < 7 { min * 2u32.pow(attempt) } else { max };
+            let delay = backoff.next_delay();
+            assert!(delay <= base);
+            assert!(delay >= base / 2);
+        }
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= min);
+    }
+}
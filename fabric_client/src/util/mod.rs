@@ -1,2 +1,3 @@
+pub mod backoff;
 pub mod matcher;
 pub mod uuid;
@@ -0,0 +1,162 @@
+//! Wire encodings for orchestrator messages.
+//!
+//! JSON is always supported, and is used for the handshake. A client lists
+//! the other encodings it supports in the `ClientHandshake`, and the server
+//! picks the one used for the rest of the session in the `ServerHandshake`.
+//! JSON stays available for debugging.
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "jsonschema")]
+use schemars::JsonSchema;
+
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Cbor,
+}
+
+/// UTF-8 byte order mark, which some JSON encoders put before the text.
+const BOM: &[u8] = b"\xef\xbb\xbf";
+
+fn skip_bom(data: &[u8]) -> &[u8] {
+    if data.starts_with(BOM) {
+        &data[BOM.len()..]
+    } else {
+        data
+    }
+}
+
+impl Encoding {
+
+    /// Detects the encoding of a message.
+    ///
+    /// Messages are maps, a JSON message always starts with `{` after any
+    /// byte order mark and whitespace. Neither `{`, whitespace nor the byte
+    /// order mark is ever the first byte of a CBOR map.
+    pub fn detect(data: &[u8]) -> Encoding {
+        match skip_bom(data).iter().find(|byte| !byte.is_ascii_whitespace()) {
+            Some(b'{') => Encoding::Json,
+            _ => Encoding::Cbor,
+        }
+    }
+
+    pub(crate) fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, CodecError> {
+        match self {
+            Encoding::Json => serde_json::to_vec(value).map_err(CodecError::Json),
+            Encoding::Cbor => serde_cbor::to_vec(value).map_err(CodecError::Cbor),
+        }
+    }
+
+    pub(crate) fn decode<'de, T: Deserialize<'de>>(self, data: &'de [u8]) -> Result<T, CodecError> {
+        match self {
+            Encoding::Json => serde_json::from_slice(skip_bom(data)).map_err(CodecError::Json),
+            Encoding::Cbor => serde_cbor::from_slice(data).map_err(CodecError::Cbor),
+        }
+    }
+
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    Json(serde_json::Error),
+    Cbor(serde_cbor::Error),
+}
+impl std::fmt::Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Json(error) => write!(f, "json: {}", error),
+            Self::Cbor(error) => write!(f, "cbor: {}", error),
+        }
+    }
+}
+impl std::error::Error for CodecError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Json(error) => Some(error),
+            Self::Cbor(error) => Some(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{OrchClientMsg, OrchServerMsg, ClientHandshakeFinish, ChallengeResponse, ObjectManifest, FragmentManifest, Hash};
+    use super::Encoding;
+
+    fn finish() -> OrchClientMsg {
+        ClientHandshakeFinish {
+            challenge_response: ChallengeResponse {
+                challenge_response: vec![0xaa; 110],
+                signature: vec![0xbb; 64],
+            },
+        }.into()
+    }
+
+    #[test]
+    fn byte_fields() {
+        let msg = finish();
+
+        // JSON keeps byte fields as number arrays.
+        let json = msg.serialize_with(Encoding::Json).unwrap();
+        let text = std::str::from_utf8(&json).unwrap();
+        assert!(text.contains("\"signature\":[187,187,"));
+
+        // CBOR stores them as byte strings, one byte per byte.
+        let cbor = msg.serialize_with(Encoding::Cbor).unwrap();
+        assert!(cbor.windows(64).any(|window| window == &[0xbb; 64][..]));
+        assert!(json.len() > 2 * cbor.len());
+
+        for data in [json, cbor].iter() {
+            match OrchClientMsg::deserialize(data).unwrap() {
+                OrchClientMsg::ClientHandshakeFinish(msg) => {
+                    assert_eq!(msg.challenge_response.challenge_response, vec![0xaa; 110]);
+                    assert_eq!(msg.challenge_response.signature, vec![0xbb; 64]);
+                },
+                msg => panic!("unexpected message {:?}", msg),
+            }
+        }
+    }
+
+    #[test]
+    fn detect() {
+        let msg: OrchServerMsg = ObjectManifest {
+            hash: Hash([1; 32]),
+            tags: vec!["segment".to_owned()],
+            size: 10,
+            fragment_size: 8,
+            fragments: vec![FragmentManifest { hash: Hash([2; 32]) }],
+        }.into();
+
+        for &encoding in [Encoding::Json, Encoding::Cbor].iter() {
+            let data = msg.serialize_with(encoding).unwrap();
+            assert_eq!(Encoding::detect(&data), encoding);
+
+            match OrchServerMsg::deserialize(&data).unwrap() {
+                OrchServerMsg::ObjectManifest(manifest) => {
+                    assert_eq!(manifest.hash, Hash([1; 32]));
+                    assert_eq!(manifest.fragments[0].hash, Hash([2; 32]));
+                },
+                msg => panic!("unexpected message {:?}", msg),
+            }
+        }
+    }
+
+    #[test]
+    fn detect_padded_json() {
+        let json = finish().serialize_with(Encoding::Json).unwrap();
+        let padded = [&b" \r\n\t"[..], &json].concat();
+        let with_bom = [&b"\xef\xbb\xbf\n"[..], &json].concat();
+
+        for data in [padded, with_bom].iter() {
+            assert_eq!(Encoding::detect(data), Encoding::Json);
+            assert!(matches!(
+                OrchClientMsg::deserialize(data).unwrap(),
+                OrchClientMsg::ClientHandshakeFinish(_),
+            ));
+        }
+    }
+}
@@ -5,10 +5,26 @@ pub use types::uuid::Uuid;
 pub use types::hash::Hash;
 pub use types::protocol_version::ProtocolVersion;
 
+mod encoding;
+pub use encoding::{Encoding, CodecError};
+
 #[cfg(feature = "jsonschema")]
 use schemars::JsonSchema;
 
-pub const VERSION: u32 = 0;
+/// Newest protocol version implemented by this crate.
+///
+/// * `0`: Initial version.
+/// * `1`: Adds `ObjectPublished`, `ClientError` and `HandshakeRejected`.
+/// * `2`: Adds `GrantCapability`, and signed manifests between peers.
+/// * `3`: Adds encrypted peer links.
+/// * `4`: Adds `Ping`, `Pong` and `PeerRtt`.
+/// * `5`: Adds `NodeStats` and `ConfigureStats`.
+/// * `6`: Adds `DisconnectPeer` and `ExpireObject`.
+/// * `7`: Adds `ObjectsEvicted`.
+/// * `8`: Adds `StoredFragments`.
+pub const VERSION: u32 = 8;
+/// Oldest protocol version this crate can still speak.
+pub const MIN_VERSION: u32 = 0;
 
 #[macro_export]
 macro_rules! impl_from {
@@ -38,6 +54,11 @@ pub use orch::server::*;
 mod peer;
 pub use peer::*;
 
+/// Wraps both ends of a signed challenge response.
+pub const HANDSHAKE_CHALLENGE_WRAP: &str = "__HANDSHAKE_CHALLENGE__";
+/// Length of a challenge response, the wrapped challenge and nonce.
+pub const CHALLENGE_RESPONSE_LEN: usize = (HANDSHAKE_CHALLENGE_WRAP.len() * 2) + (32 * 2);
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
 pub struct Challenge {
@@ -45,6 +66,8 @@ pub struct Challenge {
     /// A value should be formatted as
     /// `__HANDSHAKE_CHALLENGE__{challenge}{nonce}__HANDSHAKE_CHALLENGE__`
     /// signed, and returned as a `ChallengeResponse`.
+    #[serde(with = "crate::types::bytes::array32")]
+    #[cfg_attr(feature = "jsonschema", schemars(with = "[u8; 32]"))]
     pub challenge: [u8; 32],
 }
 
@@ -52,7 +75,11 @@ pub struct Challenge {
 #[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
 pub struct ChallengeResponse {
     /// The response for the challenge from the server.
+    #[serde(with = "crate::types::bytes")]
+    #[cfg_attr(feature = "jsonschema", schemars(with = "Vec<u8>"))]
     pub challenge_response: Vec<u8>,
+    #[serde(with = "crate::types::bytes")]
+    #[cfg_attr(feature = "jsonschema", schemars(with = "Vec<u8>"))]
     pub signature: Vec<u8>,
 }
 
@@ -65,7 +92,7 @@ pub struct IpcClient {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
 pub struct WebsocketClient {
-    url: String,
+    pub url: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -77,6 +104,9 @@ pub enum PeerConnectionType {
     WebsocketClient,
     WebsocketServer,
     WebRTC,
+    /// Not a transport. The node can encrypt peer links over any transport,
+    /// see `ConnectPeer::encrypted`.
+    Encrypted,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -1,16 +1,30 @@
 use serde::{Deserialize, Serialize};
 
-use crate::{ProtocolVersion, PeerConnectionType, Challenge, ChallengeResponse, Uuid, impl_from};
+#[cfg(feature = "jsonschema")]
+use schemars::JsonSchema;
+
+use crate::{ProtocolVersion, Encoding, CodecError, PeerConnectionType, Challenge, ChallengeResponse, ObjectManifest, Hash, Uuid, impl_from};
 
 /// When establishing a fabric connection, this message must be sent initially
 /// by the client.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
 pub struct ClientHandshake {
-    /// Protocol version in use.
-    /// Right now the fabric can only operate if the client and server
-    /// versions match, so this functions as a validation.
+    /// Oldest protocol version supported by the client.
+    /// Servers predating version negotiation require this to match their
+    /// own version exactly.
     pub version: ProtocolVersion,
+    /// Newest protocol version supported by the client. The server picks a
+    /// version in the range `version..=max_version`, and returns it in the
+    /// `ServerHandshake`.
+    #[serde(default)]
+    pub max_version: Option<ProtocolVersion>,
+
+    /// Encodings the client supports in addition to JSON, in order of
+    /// preference. The server picks the encoding for the session, and
+    /// returns it in the `ServerHandshake`.
+    #[serde(default)]
+    pub encodings: Vec<Encoding>,
 
     /// The set of classes for a node.
     /// Which values are supported here depends on the orchestrator.
@@ -26,9 +40,17 @@ pub struct ClientHandshake {
 
     /// When a fabric client starts up, it should generate a
     /// `ECDSA_P256_SHA256_FIXED` keypair, and send its public key.
+    #[serde(with = "crate::types::bytes")]
+    #[cfg_attr(feature = "jsonschema", schemars(with = "Vec<u8>"))]
     pub pubkey: Vec<u8>,
 
     pub challenge: Challenge,
+
+    /// When reconnecting, the `resumption_token` from the last
+    /// `ServerHandshake`. If the orchestrator still knows of the session, it
+    /// should give the client back its previous `client_uuid`.
+    #[serde(default)]
+    pub resumption_token: Option<String>,
 }
 
 /// Sent by the client after it has received a `ServerHandshake`.
@@ -64,6 +86,103 @@ pub struct PeerConnectionDisconnected {
     pub fail_reason: String,
 }
 
+/// Sent by the client when it has published a new object.
+/// The client has every fragment of the object, and will serve them to its
+/// peers. It is up to the orchestrator to hand the manifest to the nodes that
+/// should fetch the object.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
+pub struct ObjectPublished {
+    pub manifest: ObjectManifest,
+}
+
+/// Sent by the client when it could not handle a message from the
+/// orchestrator.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
+pub struct ClientError {
+    /// If set, the client has given up on the connection, and will not
+    /// process any more messages until it reconnects.
+    pub fatal: bool,
+    /// A human readable description of the error.
+    /// Mainly used for debugging.
+    pub reason: String,
+}
+
+/// Sent periodically by either side to check that the connection is alive.
+/// The other side answers with a `Pong` carrying the same timestamp.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
+pub struct Ping {
+    /// Chosen by the sender, usually the time the ping was sent. Opaque to
+    /// the receiver.
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
+pub struct Pong {
+    /// The timestamp of the `Ping` being answered.
+    pub timestamp: u64,
+}
+
+/// Sent by the client whenever it has measured the round trip time of a peer
+/// link.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
+pub struct PeerRtt {
+    pub peer_uuid: Uuid,
+    /// Round trip time in microseconds.
+    pub rtt_us: u64,
+}
+
+/// Traffic and latency of a single peer link.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
+pub struct PeerStats {
+    pub peer_uuid: Uuid,
+    /// Bytes received from the peer since the link was established.
+    pub bytes_in: u64,
+    /// Bytes sent to the peer since the link was established.
+    pub bytes_out: u64,
+    /// Latest round trip time in microseconds, if one has been measured.
+    #[serde(default)]
+    pub rtt_us: Option<u64>,
+}
+
+/// Sent periodically by the client once the orchestrator has asked for it
+/// with `ConfigureStats`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
+pub struct NodeStats {
+    /// Every connected peer.
+    pub peers: Vec<PeerStats>,
+    /// Bytes of object and fragment data held by the client.
+    pub memory_bytes: u64,
+    /// Objects with all their data present.
+    pub complete_objects: u64,
+    /// Objects still waiting for fragments.
+    pub partial_objects: u64,
+}
+
+/// Sent when the client frees objects on its own, to stay within its memory
+/// budget or when garbage collecting. Requests for them should no longer be
+/// routed to the client.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
+pub struct ObjectsEvicted {
+    pub hashes: Vec<Hash>,
+}
+
+/// Sent after the handshake by a client with a persistent fragment store,
+/// listing every fragment in it. The client can serve these to peers without
+/// fetching them first.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
+pub struct StoredFragments {
+    pub hashes: Vec<Hash>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
 #[serde(tag = "ty", rename_all = "snake_case")]
@@ -74,13 +193,42 @@ pub enum OrchClientMsg {
     PeerConnectionFailed(PeerConnectionFailed),
     PeerConnectionSuccess(PeerConnectionSuccess),
     PeerConnectionDisconnected(PeerConnectionDisconnected),
+
+    ObjectPublished(ObjectPublished),
+    ObjectsEvicted(ObjectsEvicted),
+    StoredFragments(StoredFragments),
+
+    Error(ClientError),
+
+    Ping(Ping),
+    Pong(Pong),
+    PeerRtt(PeerRtt),
+
+    NodeStats(NodeStats),
 }
 impl OrchClientMsg {
-    pub fn serialize(&self) -> serde_json::Result<Vec<u8>> {
-        serde_json::to_vec(self)
+    /// The protocol version the message was introduced in. It must not be
+    /// sent in a session with an older negotiated version.
+    pub fn min_version(&self) -> ProtocolVersion {
+        match self {
+            Self::ObjectPublished(_) | Self::Error(_) => ProtocolVersion(1),
+            Self::Ping(_) | Self::Pong(_) | Self::PeerRtt(_) => ProtocolVersion(4),
+            Self::NodeStats(_) => ProtocolVersion(5),
+            Self::ObjectsEvicted(_) => ProtocolVersion(7),
+            Self::StoredFragments(_) => ProtocolVersion(8),
+            _ => ProtocolVersion(0),
+        }
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>, CodecError> {
+        self.serialize_with(Encoding::Json)
+    }
+    pub fn serialize_with(&self, encoding: Encoding) -> Result<Vec<u8>, CodecError> {
+        encoding.encode(self)
     }
-    pub fn deserialize(string: &[u8]) -> serde_json::Result<Self> {
-        serde_json::from_slice(string)
+    /// Deserializes a message in any supported encoding.
+    pub fn deserialize(data: &[u8]) -> Result<Self, CodecError> {
+        Encoding::detect(data).decode(data)
     }
 }
 impl_from!(OrchClientMsg, ClientHandshake, ClientHandshake);
@@ -88,3 +236,11 @@ impl_from!(OrchClientMsg, ClientHandshakeFinish, ClientHandshakeFinish);
 impl_from!(OrchClientMsg, PeerConnectionFailed, PeerConnectionFailed);
 impl_from!(OrchClientMsg, PeerConnectionSuccess, PeerConnectionSuccess);
 impl_from!(OrchClientMsg, PeerConnectionDisconnected, PeerConnectionDisconnected);
+impl_from!(OrchClientMsg, ObjectPublished, ObjectPublished);
+impl_from!(OrchClientMsg, ObjectsEvicted, ObjectsEvicted);
+impl_from!(OrchClientMsg, StoredFragments, StoredFragments);
+impl_from!(OrchClientMsg, Error, ClientError);
+impl_from!(OrchClientMsg, Ping, Ping);
+impl_from!(OrchClientMsg, Pong, Pong);
+impl_from!(OrchClientMsg, PeerRtt, PeerRtt);
+impl_from!(OrchClientMsg, NodeStats, NodeStats);
@@ -1,6 +1,9 @@
 use serde::{Deserialize, Serialize};
 
-use crate::{Connector, Challenge, ChallengeResponse, Uuid, Hash, impl_from};
+#[cfg(feature = "jsonschema")]
+use schemars::JsonSchema;
+
+use crate::{ProtocolVersion, Encoding, CodecError, Connector, Challenge, ChallengeResponse, Ping, Pong, Uuid, Hash, impl_from};
 
 /// Sent by the server after it has received a `ClientHandshake`.
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -9,12 +12,63 @@ pub struct ServerHandshake {
     /// UUID assigned to the client for the fabric session.
     pub client_uuid: Uuid,
 
+    /// Protocol version picked by the server for the session, within the
+    /// range advertised in the `ClientHandshake`.
+    #[serde(default)]
+    pub version: ProtocolVersion,
+
+    /// Encoding used for all following messages in both directions, picked
+    /// from the `encodings` in the `ClientHandshake`.
+    #[serde(default)]
+    pub encoding: Encoding,
+
     /// For the duration of the fabric session, the orchestrator will
     /// have a single keypair. This is the public key for that pair.
+    #[serde(with = "crate::types::bytes")]
+    #[cfg_attr(feature = "jsonschema", schemars(with = "Vec<u8>"))]
     pub pubkey: Vec<u8>,
 
     pub challenge: Challenge,
     pub challenge_response: ChallengeResponse,
+
+    /// Opaque token the client may present in a later `ClientHandshake` to
+    /// resume the session after losing the connection to the orchestrator.
+    /// If not set, the session can not be resumed.
+    #[serde(default)]
+    pub resumption_token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum HandshakeRejectReason {
+    /// The `token` is missing or not valid.
+    InvalidToken,
+    /// One of the `node_classes` is not supported by the orchestrator.
+    UnsupportedNodeClass,
+    /// The protocol version of the client is not supported.
+    VersionMismatch,
+    /// The orchestrator can not accept the node right now, for example
+    /// because it is overloaded.
+    Unavailable,
+}
+
+/// Sent by the server instead of a `ServerHandshake` when it refuses a
+/// node. The server closes the connection after sending this.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
+pub struct HandshakeRejected {
+    pub reason: HandshakeRejectReason,
+
+    /// If set, the client may try again after this many seconds.
+    /// If not set, the client should not try again with the same handshake.
+    #[serde(default)]
+    pub retry_after: Option<u32>,
+
+    /// A human readable description of the rejection.
+    /// Mainly used for debugging.
+    #[serde(default)]
+    pub message: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -25,6 +79,8 @@ pub struct ConnectPeer {
     /// The UUID of the peer we should connect to in the fabric.
     pub peer_uuid: Uuid,
     /// The pubkey of the peer node.
+    #[serde(with = "crate::types::bytes")]
+    #[cfg_attr(feature = "jsonschema", schemars(with = "Vec<u8>"))]
     pub peer_pubkey: Vec<u8>,
 
     /// `self_nonce` and `peer_nonce` are UUIDs generated by the orchestrator
@@ -34,6 +90,29 @@ pub struct ConnectPeer {
     /// used for any purpose depending on the transport used.
     pub self_nonce: Uuid,
     pub peer_nonce: Uuid,
+
+    /// Whether the link should be encrypted. Only set if both nodes have
+    /// the `Encrypted` peer connection capability, and always set to the
+    /// same value for both sides of a link.
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+/// Tells the client to close its link to a peer, or to stop connecting to
+/// it. The client reports the link as closed with a
+/// `PeerConnectionDisconnected`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
+pub struct DisconnectPeer {
+    pub peer_uuid: Uuid,
+}
+
+/// Tells the client to forget an object, and release its data. Fragments
+/// shared with other objects are kept.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
+pub struct ExpireObject {
+    pub hash: Hash,
 }
 
 /// Used in tests, makes the client process disconnect and exit.
@@ -41,6 +120,36 @@ pub struct ConnectPeer {
 #[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
 pub struct TestExit {}
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
+#[serde(tag = "ty", rename_all = "snake_case")]
+pub enum Capability {
+    /// The key may originate the stream with the given UUID. Stream and
+    /// object manifests for the stream signed by the key are accepted from
+    /// peers.
+    OriginateStream { stream_uuid: Uuid },
+}
+
+/// Grants a capability to the node owning a pubkey.
+/// Grants are kept for the duration of the session.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
+pub struct GrantCapability {
+    #[serde(with = "crate::types::bytes")]
+    #[cfg_attr(feature = "jsonschema", schemars(with = "Vec<u8>"))]
+    pub pubkey: Vec<u8>,
+    pub capability: Capability,
+}
+
+/// Sets how often the client sends `NodeStats`. No stats are sent until this
+/// is received, and the setting is reset when the client reconnects.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
+pub struct ConfigureStats {
+    /// Interval in milliseconds, or `None` to stop sending stats.
+    pub interval_ms: Option<u32>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
 pub struct FragmentManifest {
@@ -57,27 +166,61 @@ pub struct ObjectManifest {
     pub fragments: Vec<FragmentManifest>,
 }
 
-// TODO: Fix copying on deserializing Cow
-// https://play.rust-lang.org/?version=nightly&mode=debug&edition=2018&gist=418dd6b98dfa62d43c4cc7fa8b7ea0d6
-
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
 #[serde(tag = "ty", rename_all = "snake_case")]
 pub enum OrchServerMsg {
     ServerHandshake(ServerHandshake),
+    HandshakeRejected(HandshakeRejected),
 
     TestExit(TestExit),
 
     ObjectManifest(ObjectManifest),
+    ExpireObject(ExpireObject),
 
     ConnectPeer(ConnectPeer),
+    DisconnectPeer(DisconnectPeer),
+
+    GrantCapability(GrantCapability),
+
+    Ping(Ping),
+    Pong(Pong),
+
+    ConfigureStats(ConfigureStats),
 }
 impl OrchServerMsg {
-    pub fn serialize(&self) -> serde_json::Result<Vec<u8>> {
-        serde_json::to_vec(self)
+    /// The protocol version the message was introduced in. It must not be
+    /// sent in a session with an older negotiated version.
+    pub fn min_version(&self) -> ProtocolVersion {
+        match self {
+            Self::HandshakeRejected(_) => ProtocolVersion(1),
+            Self::GrantCapability(_) => ProtocolVersion(2),
+            Self::Ping(_) | Self::Pong(_) => ProtocolVersion(4),
+            Self::ConfigureStats(_) => ProtocolVersion(5),
+            Self::DisconnectPeer(_) | Self::ExpireObject(_) => ProtocolVersion(6),
+            _ => ProtocolVersion(0),
+        }
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>, CodecError> {
+        self.serialize_with(Encoding::Json)
+    }
+    pub fn serialize_with(&self, encoding: Encoding) -> Result<Vec<u8>, CodecError> {
+        encoding.encode(self)
     }
-    pub fn deserialize(string: &[u8]) -> serde_json::Result<Self> {
-        serde_json::from_slice(string)
+    /// Deserializes a message in any supported encoding.
+    pub fn deserialize(data: &[u8]) -> Result<Self, CodecError> {
+        Encoding::detect(data).decode(data)
     }
 }
 impl_from!(OrchServerMsg, ServerHandshake, ServerHandshake);
+impl_from!(OrchServerMsg, HandshakeRejected, HandshakeRejected);
+impl_from!(OrchServerMsg, TestExit, TestExit);
+impl_from!(OrchServerMsg, ObjectManifest, ObjectManifest);
+impl_from!(OrchServerMsg, ConnectPeer, ConnectPeer);
+impl_from!(OrchServerMsg, DisconnectPeer, DisconnectPeer);
+impl_from!(OrchServerMsg, ExpireObject, ExpireObject);
+impl_from!(OrchServerMsg, GrantCapability, GrantCapability);
+impl_from!(OrchServerMsg, Ping, Ping);
+impl_from!(OrchServerMsg, Pong, Pong);
+impl_from!(OrchServerMsg, ConfigureStats, ConfigureStats);
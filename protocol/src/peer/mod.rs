@@ -1,23 +1,278 @@
+use bytes::Bytes;
+
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "jsonschema")]
+use schemars::JsonSchema;
+
+use crate::{Hash, Uuid, ObjectManifest};
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
 pub struct FragmentData {
-    data: Vec<u8>,
+    pub hash: Hash,
+    #[serde(with = "crate::types::bytes::shared")]
+    #[cfg_attr(feature = "jsonschema", schemars(with = "Vec<u8>"))]
+    pub data: Bytes,
+}
+
+/// First message on every peer link, proves that the sender owns the pubkey
+/// the orchestrator gave for it in `ConnectPeer`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
+pub struct PeerAuth {
+    /// Signature over `PEER_AUTH_PREFIX`, the sender nonce, the receiver
+    /// nonce, the sender pubkey, the receiver pubkey and the sender
+    /// ephemeral pubkey, made with the sender keypair.
+    #[serde(with = "crate::types::bytes")]
+    #[cfg_attr(feature = "jsonschema", schemars(with = "Vec<u8>"))]
+    pub signature: Vec<u8>,
+    /// X25519 public key of the sender for an encrypted link, empty if the
+    /// link is not encrypted.
+    #[serde(with = "crate::types::bytes")]
+    #[cfg_attr(feature = "jsonschema", schemars(with = "Vec<u8>"))]
+    pub ephemeral_pubkey: Vec<u8>,
+}
+
+/// Prefix of the transcript signed in `PeerAuth`.
+pub const PEER_AUTH_PREFIX: &[u8] = b"__LIVECORE_PEER_AUTH__";
+
+/// HKDF salt used when deriving the keys of an encrypted link from the
+/// agreed secret. The key for each direction is expanded with the sender
+/// nonce followed by the receiver nonce as info.
+pub const PEER_LINK_KEY_SALT: &[u8] = b"__LIVECORE_PEER_LINK_KEY__";
+
+/// The objects making up a stream, as published by the node originating it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
+pub struct StreamManifest {
+    pub stream_uuid: Uuid,
+    /// Increased by the origin with every update. Updates older than the
+    /// latest one seen are ignored.
+    pub sequence: u64,
+    /// Objects in the stream, oldest first.
+    pub objects: Vec<Hash>,
+}
+
+/// A `StreamManifest` signed by the originating node.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
+pub struct SignedStreamManifest {
+    pub manifest: StreamManifest,
+    #[serde(with = "crate::types::bytes")]
+    #[cfg_attr(feature = "jsonschema", schemars(with = "Vec<u8>"))]
+    pub pubkey: Vec<u8>,
+    #[serde(with = "crate::types::bytes")]
+    #[cfg_attr(feature = "jsonschema", schemars(with = "Vec<u8>"))]
+    pub signature: Vec<u8>,
+}
+
+/// An `ObjectManifest` for an object in a stream, signed by the node
+/// originating the stream.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
+pub struct SignedObjectManifest {
+    pub stream_uuid: Uuid,
+    pub manifest: ObjectManifest,
+    #[serde(with = "crate::types::bytes")]
+    #[cfg_attr(feature = "jsonschema", schemars(with = "Vec<u8>"))]
+    pub pubkey: Vec<u8>,
+    #[serde(with = "crate::types::bytes")]
+    #[cfg_attr(feature = "jsonschema", schemars(with = "Vec<u8>"))]
+    pub signature: Vec<u8>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
 pub enum PeerMsg {
-    StreamData { data: Vec<u8> },
+    StreamData {
+        #[serde(with = "crate::types::bytes::shared")]
+        #[cfg_attr(feature = "jsonschema", schemars(with = "Vec<u8>"))]
+        data: Bytes,
+    },
+
+    /// Announces fragments the sender has available.
+    /// Sent to every peer when the connection is established, and whenever a
+    /// new fragment becomes available.
+    Have { hashes: Vec<Hash> },
+    /// Requests fragments previously announced by the receiver.
+    /// The receiver responds with a `FragmentData` for every fragment it has.
+    Want { hashes: Vec<Hash> },
 
     FragmentData(FragmentData),
+
+    /// Sent when a stream is updated, and for every known stream when the
+    /// connection is established. Only accepted if the signing key may
+    /// originate the stream.
+    StreamManifest(SignedStreamManifest),
+    /// Sent when an object is added to a stream, and for every known object
+    /// in a stream when the connection is established. Only accepted if the
+    /// signing key may originate the stream.
+    ObjectManifest(SignedObjectManifest),
+
+    Auth(PeerAuth),
+
+    /// Sent periodically on an idle link. Answered with a `Pong` carrying the
+    /// same timestamp, which is only meaningful to the sender.
+    Ping { timestamp: u64 },
+    Pong { timestamp: u64 },
+
+    /// Announces fragments the sender no longer has. A `Want` for them will
+    /// not be answered, they need to be requested from another peer.
+    Dropped { hashes: Vec<Hash> },
 }
 impl PeerMsg {
     pub fn serialize(&self) -> bincode::Result<Vec<u8>> {
         bincode::serialize(self)
     }
-    pub fn deserialize(string: &[u8]) -> bincode::Result<Self> {
-        bincode::deserialize(string)
+    /// Deserializes a message, copying payloads out of `data`.
+    pub fn deserialize(data: &[u8]) -> bincode::Result<Self> {
+        bincode::deserialize(data)
+    }
+    /// Deserializes a message received in `frame`. Payloads are slices of
+    /// the frame, and are not copied.
+    pub fn decode(frame: &Bytes) -> bincode::Result<Self> {
+        let msg: PeerMsgRef = bincode::deserialize(frame)?;
+        Ok(msg.into_owned(frame))
+    }
+}
+
+/// `PeerMsg` with payloads borrowed from the frame being decoded.
+///
+/// Must match the encoding of `PeerMsg` variant for variant, which the
+/// `decode_matches_deserialize` test checks.
+#[derive(Deserialize)]
+enum PeerMsgRef<'a> {
+    StreamData { data: &'a [u8] },
+    Have { hashes: Vec<Hash> },
+    Want { hashes: Vec<Hash> },
+    FragmentData { hash: Hash, data: &'a [u8] },
+    StreamManifest(SignedStreamManifest),
+    ObjectManifest(SignedObjectManifest),
+    Auth(PeerAuth),
+    Ping { timestamp: u64 },
+    Pong { timestamp: u64 },
+    Dropped { hashes: Vec<Hash> },
+}
+impl<'a> PeerMsgRef<'a> {
+    fn into_owned(self, frame: &Bytes) -> PeerMsg {
+        match self {
+            Self::StreamData { data } => PeerMsg::StreamData { data: frame.slice_ref(data) },
+            Self::Have { hashes } => PeerMsg::Have { hashes },
+            Self::Want { hashes } => PeerMsg::Want { hashes },
+            Self::FragmentData { hash, data } => PeerMsg::FragmentData(FragmentData {
+                hash,
+                data: frame.slice_ref(data),
+            }),
+            Self::StreamManifest(signed) => PeerMsg::StreamManifest(signed),
+            Self::ObjectManifest(signed) => PeerMsg::ObjectManifest(signed),
+            Self::Auth(auth) => PeerMsg::Auth(auth),
+            Self::Ping { timestamp } => PeerMsg::Ping { timestamp },
+            Self::Pong { timestamp } => PeerMsg::Pong { timestamp },
+            Self::Dropped { hashes } => PeerMsg::Dropped { hashes },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::{Hash, Uuid, ObjectManifest, FragmentManifest};
+    use super::{PeerMsg, FragmentData, PeerAuth, StreamManifest, SignedStreamManifest, SignedObjectManifest};
+
+    #[test]
+    fn decode_borrows_frame() {
+        let msg = PeerMsg::FragmentData(FragmentData {
+            hash: Hash([3; 32]),
+            data: Bytes::from_static(&[1, 2, 3, 4]),
+        });
+        let frame = Bytes::from(msg.serialize().unwrap());
+
+        let data = match PeerMsg::decode(&frame).unwrap() {
+            PeerMsg::FragmentData(FragmentData { hash, data }) => {
+                assert_eq!(hash, Hash([3; 32]));
+                data
+            },
+            msg => panic!("unexpected message {:?}", msg),
+        };
+        assert_eq!(&data[..], &[1, 2, 3, 4]);
+
+        // The payload points into the frame.
+        let frame_range = frame.as_ptr_range();
+        assert!(frame_range.contains(&data.as_ptr()));
+    }
+
+    /// Index of the variant of a message. Adding a variant to `PeerMsg` fails
+    /// to compile here, as a reminder to add it to `PeerMsgRef` and to
+    /// `decode_matches_deserialize`.
+    fn variant(msg: &PeerMsg) -> usize {
+        match msg {
+            PeerMsg::StreamData { .. } => 0,
+            PeerMsg::Have { .. } => 1,
+            PeerMsg::Want { .. } => 2,
+            PeerMsg::FragmentData(_) => 3,
+            PeerMsg::StreamManifest(_) => 4,
+            PeerMsg::ObjectManifest(_) => 5,
+            PeerMsg::Auth(_) => 6,
+            PeerMsg::Ping { .. } => 7,
+            PeerMsg::Pong { .. } => 8,
+            PeerMsg::Dropped { .. } => 9,
+        }
+    }
+    const VARIANTS: usize = 10;
+
+    #[test]
+    fn decode_matches_deserialize() {
+        let msgs = vec![
+            PeerMsg::StreamData { data: Bytes::from_static(b"stream") },
+            PeerMsg::Have { hashes: vec![Hash([1; 32])] },
+            PeerMsg::Want { hashes: vec![Hash([2; 32]), Hash([3; 32])] },
+            PeerMsg::StreamManifest(SignedStreamManifest {
+                manifest: StreamManifest {
+                    stream_uuid: Uuid::from_bytes([1; 16]),
+                    sequence: 2,
+                    objects: vec![Hash([5; 32])],
+                },
+                pubkey: vec![1, 2, 3],
+                signature: vec![4, 5, 6],
+            }),
+            PeerMsg::FragmentData(FragmentData {
+                hash: Hash([4; 32]),
+                data: Bytes::from_static(b"fragment"),
+            }),
+            PeerMsg::ObjectManifest(SignedObjectManifest {
+                stream_uuid: Uuid::from_bytes([3; 16]),
+                manifest: ObjectManifest {
+                    hash: Hash([7; 32]),
+                    tags: vec!["segment".to_owned()],
+                    size: 10,
+                    fragment_size: 8,
+                    fragments: vec![FragmentManifest { hash: Hash([8; 32]) }],
+                },
+                pubkey: vec![7],
+                signature: vec![8],
+            }),
+            PeerMsg::Auth(PeerAuth {
+                signature: vec![9, 10],
+                ephemeral_pubkey: vec![11],
+            }),
+            PeerMsg::Ping { timestamp: 12 },
+            PeerMsg::Pong { timestamp: 13 },
+            PeerMsg::Dropped { hashes: vec![Hash([6; 32])] },
+        ];
+
+        let mut covered: Vec<_> = msgs.iter().map(variant).collect();
+        covered.sort();
+        covered.dedup();
+        assert_eq!(covered.len(), VARIANTS);
+
+        for msg in msgs {
+            let frame = Bytes::from(msg.serialize().unwrap());
+            let decoded = PeerMsg::decode(&frame).unwrap();
+            let deserialized = PeerMsg::deserialize(&frame).unwrap();
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", msg));
+            assert_eq!(format!("{:?}", deserialized), format!("{:?}", msg));
+        }
     }
 }
@@ -0,0 +1,81 @@
+//! Serde helpers for byte fields.
+//!
+//! Plain `Vec<u8>` and `[u8; N]` fields serialize as sequences of numbers,
+//! which is what we want for JSON, but wastes space in binary encodings. Fields
+//! using these helpers serialize as byte strings instead. Both forms are
+//! accepted when deserializing.
+
+use std::fmt;
+
+use serde::{Serializer, Deserializer};
+use serde::de::{self, Visitor, SeqAccess};
+
+pub(crate) struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a byte string")
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+        Ok(v.to_owned())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+        Ok(v)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+        let mut data = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            data.push(byte);
+        }
+        Ok(data)
+    }
+}
+
+/// For `Vec<u8>` fields, use with `#[serde(with = "crate::types::bytes")]`.
+pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_bytes(data)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    deserializer.deserialize_bytes(BytesVisitor)
+}
+
+/// For `bytes::Bytes` fields, use with
+/// `#[serde(with = "crate::types::bytes::shared")]`.
+pub mod shared {
+    use bytes::Bytes;
+
+    use serde::{Serializer, Deserializer};
+
+    pub fn serialize<S: Serializer>(data: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(data)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+        deserializer.deserialize_bytes(super::BytesVisitor).map(Bytes::from)
+    }
+}
+
+/// For `[u8; 32]` fields, use with
+/// `#[serde(with = "crate::types::bytes::array32")]`.
+pub mod array32 {
+    use std::convert::TryInto;
+
+    use serde::{Serializer, Deserializer};
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(data: &[u8; 32], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(data)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 32], D::Error> {
+        let data = deserializer.deserialize_bytes(super::BytesVisitor)?;
+        data[..].try_into()
+            .map_err(|_| D::Error::invalid_length(data.len(), &"32 bytes"))
+    }
+}
@@ -1,8 +1,12 @@
+use std::convert::TryInto;
 use std::fmt;
 use std::fmt::Write;
 
 use serde::{Deserialize, Serialize};
 use serde::{Serializer, Deserializer};
+use serde::de::{self, Visitor, SeqAccess};
+
+use super::bytes::BytesVisitor;
 
 #[cfg(feature = "jsonschema")]
 use schemars::JsonSchema;
@@ -92,28 +96,67 @@ impl Serialize for Hash {
     }
 }
 
+/// Accepts a hex string, or the raw bytes of the hash.
+///
+/// Internally tagged enums buffer their content before deserializing it, and
+/// claim to be human readable even when the format is not. Raw bytes are
+/// therefore accepted in both modes.
+struct HashVisitor;
+
+impl<'de> Visitor<'de> for HashVisitor {
+    type Value = Hash;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a hex string or 32 bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Hash, E> {
+        Hash::parse_str(v).ok_or_else(|| E::custom("invalid hash"))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Hash, E> {
+        v.try_into()
+            .map(Hash)
+            .map_err(|_| E::invalid_length(v.len(), &self))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Hash, A::Error> {
+        let data = BytesVisitor.visit_seq(seq)?;
+        self.visit_bytes(&data)
+    }
+}
+
 impl<'de> Deserialize<'de> for Hash {
     fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
-        use serde::de::Error;
-
         if deserializer.is_human_readable() {
-            let string = String::deserialize(deserializer)?;
-            Hash::parse_str(&string).ok_or_else(|| {
-                D::Error::custom("invalid hash")
-            })
+            deserializer.deserialize_str(HashVisitor)
         } else {
-            let data = Vec::deserialize(deserializer)?;
-            let mut buf = [0u8; 32];
-            if data.len() == 32 {
-                (&mut buf).copy_from_slice(&data);
-                Ok(Hash(buf))
-            } else {
-                Err(D::Error::custom("invalid hash"))
-            }
+            deserializer.deserialize_bytes(HashVisitor)
         }
     }
 }
 
+#[cfg(feature = "jsonschema")]
+use schemars::{gen::SchemaGenerator, schema::{Schema, SchemaObject, InstanceType, StringValidation}};
+
+#[cfg(feature = "jsonschema")]
+impl JsonSchema for Hash {
+    fn schema_name() -> String {
+        "hash".to_owned()
+    }
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            string: Some(Box::new(StringValidation {
+                max_length: Some(64),
+                min_length: Some(64),
+                pattern: Some("^[0-9a-f]{64}$".to_owned()),
+            })),
+            ..Default::default()
+        }.into()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use serde_json::{to_value, from_value};
@@ -139,5 +182,11 @@ mod tests {
         let hash_back: Hash = from_value(value).unwrap();
 
         assert_eq!(hash, hash_back);
+
+        let data = serde_cbor::to_vec(&hash).unwrap();
+        assert_eq!(data.len(), 34);
+        let hash_back: Hash = serde_cbor::from_slice(&data).unwrap();
+
+        assert_eq!(hash, hash_back);
     }
 }
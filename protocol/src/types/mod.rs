@@ -1,3 +1,4 @@
+pub mod bytes;
 pub mod hash;
 pub mod protocol_version;
 pub mod uuid;
@@ -1,45 +1,60 @@
-use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error};
+use serde::{Deserialize, Serialize};
 
-#[derive(Default, Copy, Clone)]
-pub struct ProtocolVersion;
-
-impl std::fmt::Debug for ProtocolVersion {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", crate::VERSION)
-    }
-}
-
-impl Serialize for ProtocolVersion {
-    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        serializer.serialize_u32(crate::VERSION)
+#[cfg(feature = "jsonschema")]
+use schemars::JsonSchema;
+
+/// A version of the fabric protocol.
+///
+/// A client advertises the range of versions it supports, and the server
+/// picks the one used for the session with `negotiate`.
+#[derive(Serialize, Deserialize, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "jsonschema", derive(JsonSchema))]
+#[serde(transparent)]
+pub struct ProtocolVersion(pub u32);
+
+impl ProtocolVersion {
+    /// Newest version implemented by this crate.
+    pub const CURRENT: ProtocolVersion = ProtocolVersion(crate::VERSION);
+    /// Oldest version this crate can still speak.
+    pub const MIN_SUPPORTED: ProtocolVersion = ProtocolVersion(crate::MIN_VERSION);
+
+    pub fn is_supported(&self) -> bool {
+        *self >= Self::MIN_SUPPORTED && *self <= Self::CURRENT
     }
-}
-impl<'de> Deserialize<'de> for ProtocolVersion {
-    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
-        let ver: u32 = Deserialize::deserialize(deserializer)?;
 
-        if ver == crate::VERSION {
-            Ok(ProtocolVersion)
+    /// Picks the newest version supported by both sides, given the range
+    /// supported by the peer.
+    pub fn negotiate(min: ProtocolVersion, max: ProtocolVersion) -> Option<ProtocolVersion> {
+        let version = std::cmp::min(max, Self::CURRENT);
+        if version >= min && version.is_supported() {
+            Some(version)
         } else {
-            Err(D::Error::custom(format!("invalid protocol version (expected {}, got {})", crate::VERSION, ver)))
+            None
         }
     }
 }
 
-#[cfg(feature = "jsonschema")]
-use schemars::{JsonSchema, gen::SchemaGenerator, schema::{Schema, SchemaObject}};
-
-#[cfg(feature = "jsonschema")]
-impl JsonSchema for ProtocolVersion {
-    fn schema_name() -> String {
-        "protocol_version".to_owned()
+impl std::fmt::Debug for ProtocolVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
     }
-    fn json_schema(_: &mut SchemaGenerator) -> Schema {
-        let mut schema = SchemaObject::default();
-
-        let ver = crate::VERSION;
-        schema.const_value = Some(serde_json::json!(ver));
+}
+impl std::fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
 
-        schema.into()
+#[cfg(test)]
+mod tests {
+    use super::ProtocolVersion as V;
+
+    #[test]
+    fn negotiate() {
+        assert_eq!(V::negotiate(V::MIN_SUPPORTED, V::CURRENT), Some(V::CURRENT));
+        assert_eq!(V::negotiate(V(0), V(0)), Some(V(0)));
+        assert_eq!(V::negotiate(V(0), V(u32::MAX)), Some(V::CURRENT));
+        assert_eq!(V::negotiate(V(V::CURRENT.0 + 1), V(u32::MAX)), None);
+        assert_eq!(V::negotiate(V(1), V(0)), None);
     }
 }
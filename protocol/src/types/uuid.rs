@@ -14,15 +14,22 @@ mod shim {
     #[repr(transparent)]
     pub struct Uuid(OUuid);
 
+    impl Uuid {
+        pub const fn from_bytes(bytes: uuid::Bytes) -> Self {
+            Uuid(OUuid::from_bytes(bytes))
+        }
+    }
+
     impl JsonSchema for Uuid {
         fn schema_name() -> String {
             "uuid".to_owned()
         }
         fn json_schema(_: &mut SchemaGenerator) -> Schema {
-            let mut schema = SchemaObject::default();
-            schema.instance_type = Some(InstanceType::String.into());
-            schema.format = Some("uuid".into());
-            schema.into()
+            SchemaObject {
+                instance_type: Some(InstanceType::String.into()),
+                format: Some("uuid".into()),
+                ..Default::default()
+            }.into()
         }
     }
 }
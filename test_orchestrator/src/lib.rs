@@ -0,0 +1,510 @@
+//! Mock orchestrator for driving `Fabric` instances from tests.
+//!
+//! Talks to each node directly through its `OrchPacketSender` and
+//! `Fabric::handle_fabric_packet`, with no transport in between. Every message
+//! is still round-tripped through the protocol serialization, so the nodes see
+//! exactly what a real orchestrator would send.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{self, KeyPair};
+
+use futures::{Stream, StreamExt};
+
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+use anyhow::{Result, Context, anyhow, bail, ensure};
+
+use livecore_protocol as proto;
+use proto::{Uuid, ProtocolVersion, Encoding, HANDSHAKE_CHALLENGE_WRAP, CHALLENGE_RESPONSE_LEN};
+
+use fabric_client::{Fabric, FabricBuilder, FabricEvent, OrchPacketSender};
+use fabric_client::platform::PeerConnectionManager;
+use fabric_client::platform::peer_connection_manager_impl::InMemNetwork;
+
+const RECV_TIMEOUT: Duration = Duration::from_millis(10000);
+
+pub struct TestOrchestrator {
+    rand: SystemRandom,
+    keypair: signature::EcdsaKeyPair,
+    network: InMemNetwork,
+
+    /// Newest protocol version the orchestrator is willing to negotiate.
+    max_version: ProtocolVersion,
+    /// Encoding used with nodes that offer it, JSON otherwise.
+    encoding: Encoding,
+    /// Whether links between nodes that offer encryption are encrypted.
+    encrypt_links: bool,
+
+    /// Resumable sessions, by resumption token.
+    sessions: Mutex<HashMap<String, Uuid>>,
+}
+
+/// The nonces handed out for a peer connection between two nodes.
+#[derive(Debug, Copy, Clone)]
+pub struct PeerLink {
+    pub a_nonce: Uuid,
+    pub b_nonce: Uuid,
+}
+
+struct Handshake {
+    uuid: Uuid,
+    pubkey: Vec<u8>,
+    version: ProtocolVersion,
+    encoding: Encoding,
+    encrypted_links: bool,
+    resumed: bool,
+}
+
+/// A fabric node that has completed the handshake with the orchestrator.
+pub struct TestNode {
+    pub uuid: Uuid,
+    pub pubkey: Vec<u8>,
+    /// Protocol version negotiated in the last handshake.
+    pub version: ProtocolVersion,
+    /// Encoding negotiated in the last handshake.
+    pub encoding: Encoding,
+    /// Whether the node offered encrypted peer links in the last handshake.
+    pub encrypted_links: bool,
+    pub fabric: Fabric,
+    receiver: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+impl TestNode {
+
+    /// Sends a message to the node.
+    pub async fn send<P: Into<proto::OrchServerMsg>>(&self, packet: P) {
+        send_msg(&self.fabric, self.encoding, packet).await
+    }
+
+    /// Receives the next message sent by the node.
+    ///
+    /// Heartbeat messages are handled like a real orchestrator would, and
+    /// are not returned: pings are answered, and pongs and peer round trip
+    /// times are skipped. Use `recv_raw` to see them.
+    pub async fn recv(&mut self) -> Result<proto::OrchClientMsg> {
+        loop {
+            match self.recv_raw().await? {
+                proto::OrchClientMsg::Ping(ping) => {
+                    self.send(proto::Pong { timestamp: ping.timestamp }).await;
+                },
+                proto::OrchClientMsg::Pong(_) | proto::OrchClientMsg::PeerRtt(_) => (),
+                msg => return Ok(msg),
+            }
+        }
+    }
+
+    /// Receives the next message sent by the node, including heartbeat
+    /// messages.
+    pub async fn recv_raw(&mut self) -> Result<proto::OrchClientMsg> {
+        recv_msg(&mut self.receiver, self.encoding).await
+    }
+
+    /// Receives the next message sent by the node, failing if it is not of
+    /// the expected type. Heartbeat messages are skipped like in `recv`.
+    pub async fn expect<T: TryFrom<proto::OrchClientMsg>>(&mut self) -> Result<T> {
+        let msg = self.recv().await?;
+        T::try_from(msg.clone())
+            .map_err(|_| anyhow!("received unexpected message from node: {:?}", msg))
+    }
+
+    /// Waits until the node reports the result of connecting to the given
+    /// peer. Unrelated messages are skipped.
+    pub async fn expect_peer_connected(&mut self, peer_uuid: Uuid) -> Result<()> {
+        loop {
+            match self.recv().await? {
+                proto::OrchClientMsg::PeerConnectionSuccess(msg) if msg.peer_uuid == peer_uuid => {
+                    return Ok(());
+                }
+                proto::OrchClientMsg::PeerConnectionFailed(msg) if msg.peer_uuid == peer_uuid => {
+                    bail!("peer connection failed: {}", msg.fail_reason);
+                }
+                msg => {
+                    log::debug!("skipping message while waiting for peer connection: {:?}", msg);
+                }
+            }
+        }
+    }
+
+    /// Waits until the node reports that the given peer disconnected, and
+    /// returns the reason. Unrelated messages are skipped.
+    pub async fn expect_peer_disconnected(&mut self, peer_uuid: Uuid) -> Result<String> {
+        loop {
+            match self.recv().await? {
+                proto::OrchClientMsg::PeerConnectionDisconnected(msg) if msg.peer_uuid == peer_uuid => {
+                    return Ok(msg.fail_reason);
+                }
+                msg => {
+                    log::debug!("skipping message while waiting for peer disconnect: {:?}", msg);
+                }
+            }
+        }
+    }
+
+}
+
+async fn send_msg<P: Into<proto::OrchServerMsg>>(fabric: &Fabric, encoding: Encoding, packet: P) {
+    let msg: proto::OrchServerMsg = packet.into();
+    let serialized = msg.serialize_with(encoding).unwrap();
+    let msg = proto::OrchServerMsg::deserialize(&serialized).unwrap();
+    fabric.handle_fabric_packet(msg).await;
+}
+
+/// Receives a message from a node, failing if it is not in the expected
+/// encoding.
+async fn recv_msg(
+    receiver: &mut mpsc::UnboundedReceiver<Vec<u8>>,
+    encoding: Encoding,
+) -> Result<proto::OrchClientMsg> {
+    let data = timeout(RECV_TIMEOUT, receiver.recv())
+        .await
+        .context("timed out waiting for message from node")?
+        .ok_or_else(|| anyhow!("node stopped sending messages"))?;
+    ensure!(
+        Encoding::detect(&data) == encoding,
+        "node sent message in wrong encoding, expected {:?}", encoding,
+    );
+    proto::OrchClientMsg::deserialize(&data).context("node sent invalid message")
+}
+
+async fn expect_msg<T: TryFrom<proto::OrchClientMsg>>(
+    receiver: &mut mpsc::UnboundedReceiver<Vec<u8>>,
+    encoding: Encoding,
+) -> Result<T> {
+    let msg = recv_msg(receiver, encoding).await?;
+    T::try_from(msg.clone())
+        .map_err(|_| anyhow!("received unexpected message from node: {:?}", msg))
+}
+
+impl TestOrchestrator {
+
+    pub fn new() -> Self {
+        let rand = SystemRandom::new();
+
+        let algo = &signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+        let pkcs8_bytes = signature::EcdsaKeyPair::generate_pkcs8(
+            algo, &rand).unwrap();
+        let keypair = signature::EcdsaKeyPair::from_pkcs8(
+            algo, pkcs8_bytes.as_ref()).unwrap();
+
+        Self {
+            rand,
+            keypair,
+            network: InMemNetwork::new(),
+            max_version: ProtocolVersion::CURRENT,
+            encoding: Encoding::Cbor,
+            encrypt_links: true,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Limits the protocol versions negotiated with nodes, to act like an
+    /// older orchestrator.
+    pub fn with_max_version(mut self, version: ProtocolVersion) -> Self {
+        self.max_version = version;
+        self
+    }
+
+    /// Sets the encoding picked for nodes that offer it.
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Sets whether links between nodes that offer encryption are encrypted.
+    pub fn with_encrypted_links(mut self, encrypt_links: bool) -> Self {
+        self.encrypt_links = encrypt_links;
+        self
+    }
+
+    pub fn pubkey(&self) -> &[u8] {
+        self.keypair.public_key().as_ref()
+    }
+
+    /// The in-memory network used by nodes started with `start_inmem_node`.
+    pub fn network(&self) -> &InMemNetwork {
+        &self.network
+    }
+
+    pub fn gen_uuid(&self) -> Uuid {
+        let mut bytes = [0; 16];
+        self.rand.fill(&mut bytes).unwrap();
+        uuid::Builder::from_bytes(bytes)
+            .set_variant(uuid::Variant::RFC4122)
+            .set_version(uuid::Version::Random)
+            .build()
+    }
+
+    /// Starts a node connected to the in-memory network of the orchestrator,
+    /// and performs the handshake with it.
+    pub async fn start_inmem_node(&self, builder: FabricBuilder) -> Result<TestNode> {
+        self.start_node(builder, Box::new(self.network.manager())).await
+    }
+
+    /// Starts a node with the given peer connection manager, and performs the
+    /// handshake with it.
+    pub async fn start_node(
+        &self,
+        builder: FabricBuilder,
+        peer_connector: Box<dyn PeerConnectionManager + Send>,
+    ) -> Result<TestNode> {
+        let (sender, mut receiver) = OrchPacketSender::new();
+        let fabric = builder.start(sender, peer_connector);
+        let mut events = fabric.events();
+
+        let handshake = self.handshake(&fabric, &mut receiver, &mut events).await?;
+        ensure!(!handshake.resumed, "new node resumed a session");
+
+        Ok(TestNode {
+            uuid: handshake.uuid,
+            pubkey: handshake.pubkey,
+            version: handshake.version,
+            encoding: handshake.encoding,
+            encrypted_links: handshake.encrypted_links,
+            fabric,
+            receiver,
+        })
+    }
+
+    /// Starts a node without performing the handshake, for testing the
+    /// handshake itself. The node has no UUID or pubkey until it is set by
+    /// the test.
+    pub fn start_raw_node(
+        &self,
+        builder: FabricBuilder,
+        peer_connector: Box<dyn PeerConnectionManager + Send>,
+    ) -> TestNode {
+        let (sender, receiver) = OrchPacketSender::new();
+        let fabric = builder.start(sender, peer_connector);
+
+        TestNode {
+            uuid: Uuid::nil(),
+            pubkey: Vec::new(),
+            version: ProtocolVersion::default(),
+            encoding: Encoding::Json,
+            encrypted_links: false,
+            fabric,
+            receiver,
+        }
+    }
+
+    /// Gives a node a new connection to the orchestrator, as if the old one
+    /// was lost, and performs the handshake again.
+    ///
+    /// Returns whether the session was resumed.
+    pub async fn reconnect_node(&self, node: &mut TestNode) -> Result<bool> {
+        let (sender, receiver) = OrchPacketSender::new();
+        let mut events = node.fabric.events();
+
+        node.fabric.reconnect(sender).await;
+        node.receiver = receiver;
+
+        let handshake = self.handshake(&node.fabric, &mut node.receiver, &mut events).await?;
+        ensure!(handshake.pubkey == node.pubkey, "node changed pubkey when reconnecting");
+        node.uuid = handshake.uuid;
+        node.version = handshake.version;
+        node.encoding = handshake.encoding;
+        node.encrypted_links = handshake.encrypted_links;
+
+        Ok(handshake.resumed)
+    }
+
+    /// Forgets all sessions, nodes reconnecting after this get a new session.
+    pub fn forget_sessions(&self) {
+        self.sessions.lock().unwrap().clear();
+    }
+
+    async fn handshake(
+        &self,
+        fabric: &Fabric,
+        receiver: &mut mpsc::UnboundedReceiver<Vec<u8>>,
+        events: &mut (impl Stream<Item = FabricEvent> + Unpin),
+    ) -> Result<Handshake> {
+        let handshake: proto::ClientHandshake = expect_msg(receiver, Encoding::Json).await?;
+
+        let max_version = std::cmp::min(
+            handshake.max_version.unwrap_or(handshake.version),
+            self.max_version,
+        );
+        let version = match ProtocolVersion::negotiate(handshake.version, max_version) {
+            Some(version) => version,
+            None => {
+                send_msg(fabric, Encoding::Json, proto::HandshakeRejected {
+                    reason: proto::HandshakeRejectReason::VersionMismatch,
+                    retry_after: None,
+                    message: None,
+                }).await;
+                bail!("no common protocol version with node");
+            }
+        };
+
+        let resumed_uuid = handshake.resumption_token.as_ref()
+            .and_then(|token| self.sessions.lock().unwrap().remove(token));
+        let resumed = resumed_uuid.is_some();
+        let client_uuid = resumed_uuid.unwrap_or_else(|| self.gen_uuid());
+
+        let resumption_token = self.gen_uuid().to_string();
+        self.sessions.lock().unwrap().insert(resumption_token.clone(), client_uuid);
+
+        let challenge = self.gen_challenge();
+
+        let encoding = if handshake.encodings.contains(&self.encoding) {
+            self.encoding
+        } else {
+            Encoding::Json
+        };
+
+        send_msg(fabric, Encoding::Json, proto::ServerHandshake {
+            client_uuid,
+            version,
+            encoding,
+            pubkey: self.pubkey().to_owned(),
+            challenge: proto::Challenge {
+                challenge,
+            },
+            challenge_response: self.sign_challenge(&handshake.challenge.challenge),
+            resumption_token: Some(resumption_token),
+        }).await;
+
+        let finish: proto::ClientHandshakeFinish = expect_msg(receiver, encoding).await?;
+        verify_challenge_response(&handshake.pubkey, &challenge, &finish.challenge_response)?;
+
+        // Other events, like peers dropped with a session that was not
+        // resumed, may come before the handshake completes.
+        let event = loop {
+            let event = timeout(RECV_TIMEOUT, events.next())
+                .await
+                .context("timed out waiting for handshake event")?
+                .ok_or_else(|| anyhow!("fabric stopped"))?;
+            if let FabricEvent::HandshakeComplete { .. } = event {
+                break event;
+            }
+        };
+        ensure!(
+            event == FabricEvent::HandshakeComplete {
+                uuid: client_uuid,
+                orch_pubkey: self.pubkey().to_owned(),
+                version,
+                resumed,
+            },
+            "unexpected handshake event: {:?}", event,
+        );
+
+        log::info!(
+            "test orchestrator: node {} handshaked (version: {}, resumed: {})",
+            client_uuid, version, resumed,
+        );
+
+        Ok(Handshake {
+            uuid: client_uuid,
+            pubkey: handshake.pubkey,
+            version,
+            encoding,
+            encrypted_links: handshake.peer_connection_capabilities.iter()
+                .any(|ty| matches!(ty, proto::PeerConnectionType::Encrypted)),
+            resumed,
+        })
+    }
+
+    /// Instructs two nodes to connect to each other, and waits for both of
+    /// them to report success. The link is encrypted if both nodes offer it,
+    /// unless disabled with `with_encrypted_links`.
+    pub async fn connect_peers(
+        &self,
+        a: &mut TestNode,
+        a_connector: proto::Connector,
+        b: &mut TestNode,
+        b_connector: proto::Connector,
+    ) -> Result<PeerLink> {
+        let a_nonce = self.gen_uuid();
+        let b_nonce = self.gen_uuid();
+        let encrypted = self.encrypt_links && a.encrypted_links && b.encrypted_links;
+
+        a.send(proto::ConnectPeer {
+            connector: a_connector,
+            peer_uuid: b.uuid,
+            peer_pubkey: b.pubkey.clone(),
+            self_nonce: a_nonce,
+            peer_nonce: b_nonce,
+            encrypted,
+        }).await;
+        b.send(proto::ConnectPeer {
+            connector: b_connector,
+            peer_uuid: a.uuid,
+            peer_pubkey: a.pubkey.clone(),
+            self_nonce: b_nonce,
+            peer_nonce: a_nonce,
+            encrypted,
+        }).await;
+
+        a.expect_peer_connected(b.uuid).await?;
+        b.expect_peer_connected(a.uuid).await?;
+
+        Ok(PeerLink {
+            a_nonce,
+            b_nonce,
+        })
+    }
+
+    /// Connects two nodes started with `start_inmem_node`.
+    pub async fn connect_inmem_peers(&self, a: &mut TestNode, b: &mut TestNode) -> Result<PeerLink> {
+        self.connect_peers(
+            a,
+            proto::Connector::WebsocketServer,
+            b,
+            proto::Connector::WebsocketServer,
+        ).await
+    }
+
+    pub async fn send_object_manifest(&self, node: &TestNode, manifest: proto::ObjectManifest) {
+        node.send(manifest).await;
+    }
+
+    fn gen_challenge(&self) -> [u8; 32] {
+        let mut challenge = [0; 32];
+        self.rand.fill(&mut challenge).unwrap();
+        challenge
+    }
+
+    fn sign_challenge(&self, challenge: &[u8; 32]) -> proto::ChallengeResponse {
+        let nonce = self.gen_challenge();
+
+        let mut challenge_response = Vec::new();
+        challenge_response.extend(HANDSHAKE_CHALLENGE_WRAP.as_bytes());
+        challenge_response.extend(challenge);
+        challenge_response.extend(&nonce);
+        challenge_response.extend(HANDSHAKE_CHALLENGE_WRAP.as_bytes());
+
+        let signature = self.keypair.sign(&self.rand, &challenge_response).unwrap();
+
+        proto::ChallengeResponse {
+            challenge_response,
+            signature: signature.as_ref().to_owned(),
+        }
+    }
+
+}
+
+fn verify_challenge_response(
+    pubkey: &[u8],
+    challenge: &[u8; 32],
+    response: &proto::ChallengeResponse,
+) -> Result<()> {
+    let data = &response.challenge_response;
+
+    ensure!(data.len() == CHALLENGE_RESPONSE_LEN, "invalid challenge response length");
+    let challenge_range = (HANDSHAKE_CHALLENGE_WRAP.len())..(HANDSHAKE_CHALLENGE_WRAP.len() + 32);
+    ensure!(&data[challenge_range] == &challenge[..], "challenge response does not match challenge");
+
+    let algo = &signature::ECDSA_P256_SHA256_FIXED;
+    signature::UnparsedPublicKey::new(algo, pubkey)
+        .verify(data, &response.signature)
+        .map_err(|_| anyhow!("invalid challenge response signature"))?;
+
+    Ok(())
+}
@@ -0,0 +1,836 @@
+use std::time::Duration;
+
+use futures::{Stream, StreamExt};
+use tokio::time::timeout;
+
+use fabric_client::{DataError, FabricBuilder, FabricEvent, FileStore, FragSize, HandshakeError, ObjectBuilder};
+use fabric_client::platform::peer_connection_manager_impl::NativePeerConnectionManagerBuilder;
+
+use livecore_protocol as proto;
+use proto::Hash;
+
+use test_orchestrator::TestOrchestrator;
+
+#[tokio::test]
+async fn handshake() {
+    let orch = TestOrchestrator::new();
+
+    let a = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let b = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+
+    assert!(a.uuid != b.uuid);
+    assert!(a.pubkey != b.pubkey);
+}
+
+#[tokio::test]
+async fn connect_peers() {
+    let orch = TestOrchestrator::new();
+
+    let mut a = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let mut b = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let mut c = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+
+    orch.connect_inmem_peers(&mut a, &mut b).await.unwrap();
+    orch.connect_inmem_peers(&mut b, &mut c).await.unwrap();
+    orch.connect_inmem_peers(&mut c, &mut a).await.unwrap();
+}
+
+#[tokio::test]
+async fn peer_disconnect() {
+    let orch = TestOrchestrator::new();
+
+    let mut a = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let mut b = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+
+    let link = orch.connect_inmem_peers(&mut a, &mut b).await.unwrap();
+    assert!(orch.network().disconnect(link.a_nonce));
+
+    let reason = a.expect_peer_disconnected(b.uuid).await.unwrap();
+    assert_eq!(reason, "disconnected");
+    let reason = b.expect_peer_disconnected(a.uuid).await.unwrap();
+    assert_eq!(reason, "disconnected");
+}
+
+#[tokio::test]
+async fn peer_connect_failed() {
+    let orch = TestOrchestrator::new();
+
+    let peer_connector = NativePeerConnectionManagerBuilder::new().build();
+    let mut a = orch.start_node(FabricBuilder::new(), Box::new(peer_connector)).await.unwrap();
+
+    let peer_uuid = orch.gen_uuid();
+    a.send(proto::ConnectPeer {
+        connector: proto::Connector::WebRTC,
+        peer_uuid,
+        peer_pubkey: vec![],
+        self_nonce: orch.gen_uuid(),
+        peer_nonce: orch.gen_uuid(),
+        encrypted: false,
+    }).await;
+
+    let failed: proto::PeerConnectionFailed = a.expect().await.unwrap();
+    assert_eq!(failed.peer_uuid, peer_uuid);
+    assert_eq!(failed.fail_reason, "webrtc not supported");
+}
+
+#[tokio::test]
+async fn object_manifest() {
+    let orch = TestOrchestrator::new();
+
+    let a = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let mut events = a.fabric.events();
+
+    let mut manifest = proto::ObjectManifest {
+        hash: Hash([0; 32]),
+        tags: vec!["segment".to_owned()],
+        size: 1000,
+        fragment_size: 8,
+        fragments: (0..4)
+            .map(|n| proto::FragmentManifest { hash: fabric_client::fragment_hash(&[n]) })
+            .collect(),
+    };
+    manifest.hash = fabric_client::manifest_hash(&manifest);
+
+    orch.send_object_manifest(&a, manifest.clone()).await;
+    assert_eq!(
+        next(&mut events).await,
+        FabricEvent::ObjectManifest { hash: manifest.hash, tags: manifest.tags },
+    );
+
+    // The object is known, even though none of its data is there yet.
+    let reader = a.fabric.open_object(manifest.hash).await.unwrap();
+    assert_eq!(reader.size(), 1000);
+}
+
+#[tokio::test]
+async fn publish_object() {
+    let orch = TestOrchestrator::new();
+
+    let mut a = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+
+    let data: Vec<u8> = (0..1000).map(|n| n as u8).collect();
+    let manifest = a.fabric.publish(&data, vec!["segment".to_owned()], FragSize(8)).await.unwrap();
+    assert_eq!(manifest.size, 1000);
+    assert_eq!(manifest.fragments.len(), 4);
+    assert_eq!(manifest.hash, fabric_client::manifest_hash(&manifest));
+
+    let published: proto::ObjectPublished = a.expect().await.unwrap();
+    assert_eq!(published.manifest.hash, manifest.hash);
+    assert_eq!(published.manifest.tags, vec!["segment".to_owned()]);
+
+    // Publishing the same data twice is rejected.
+    let res = a.fabric.publish(&data, vec![], FragSize(8)).await;
+    assert_eq!(res.err(), Some(DataError::DuplicateObject(manifest.hash)));
+}
+
+#[tokio::test]
+async fn transfer_object() {
+    let orch = TestOrchestrator::new();
+
+    let mut a = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let mut b = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let mut c = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+
+    // c is only connected to a through b.
+    orch.connect_inmem_peers(&mut a, &mut b).await.unwrap();
+    orch.connect_inmem_peers(&mut b, &mut c).await.unwrap();
+
+    let mut segments = c.fabric.subscribe("segment".to_owned()).await;
+
+    let data: Vec<u8> = (0..5000).map(|n| (n % 251) as u8).collect();
+    let manifest = a.fabric.publish(&data, vec!["segment".to_owned()], FragSize(10)).await.unwrap();
+
+    let b_object = b.fabric.get_object(manifest.hash);
+
+    orch.send_object_manifest(&b, manifest.clone()).await;
+    orch.send_object_manifest(&c, manifest.clone()).await;
+
+    let b_data = timeout(Duration::from_secs(10), b_object).await.unwrap();
+    assert_eq!(&b_data[..], &data[..]);
+
+    let c_object = timeout(Duration::from_secs(10), segments.next()).await.unwrap().unwrap();
+    assert_eq!(c_object.hash, manifest.hash);
+    assert_eq!(&c_object.data[..], &data[..]);
+
+    // Once complete, the object is available immediately.
+    assert_eq!(&c.fabric.get_object(manifest.hash).await[..], &data[..]);
+}
+
+#[tokio::test]
+async fn events() {
+    let orch = TestOrchestrator::new();
+
+    let mut a = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let mut b = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+
+    let mut a_events = a.fabric.events();
+    let mut b_events = b.fabric.events();
+
+    let link = orch.connect_inmem_peers(&mut a, &mut b).await.unwrap();
+    assert_eq!(next(&mut a_events).await, FabricEvent::PeerConnected { peer_uuid: b.uuid });
+    assert_eq!(next(&mut b_events).await, FabricEvent::PeerConnected { peer_uuid: a.uuid });
+
+    let manifest = a.fabric.publish(&[1; 100], vec!["segment".to_owned()], FragSize(4)).await.unwrap();
+    let tags = vec!["segment".to_owned()];
+    assert_eq!(next(&mut a_events).await, FabricEvent::ObjectComplete { hash: manifest.hash, tags: tags.clone() });
+
+    orch.send_object_manifest(&b, manifest.clone()).await;
+    assert_eq!(next(&mut b_events).await, FabricEvent::ObjectManifest { hash: manifest.hash, tags: tags.clone() });
+    assert_eq!(next(&mut b_events).await, FabricEvent::ObjectComplete { hash: manifest.hash, tags });
+
+    orch.network().disconnect(link.a_nonce);
+    assert_eq!(
+        next(&mut a_events).await,
+        FabricEvent::PeerDisconnected { peer_uuid: b.uuid, reason: "disconnected".to_owned() },
+    );
+}
+
+async fn next(events: &mut (impl Stream<Item = FabricEvent> + Unpin)) -> FabricEvent {
+    timeout(Duration::from_secs(10), events.next()).await.unwrap().unwrap()
+}
+
+#[tokio::test]
+async fn reconnect_resume() {
+    let orch = TestOrchestrator::new();
+
+    let mut a = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let mut b = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    orch.connect_inmem_peers(&mut a, &mut b).await.unwrap();
+
+    let a_uuid = a.uuid;
+    assert!(orch.reconnect_node(&mut a).await.unwrap());
+    assert_eq!(a.uuid, a_uuid);
+
+    // The peer link survives the reconnect.
+    let manifest = a.fabric.publish(&[1; 100], vec![], FragSize(4)).await.unwrap();
+    let b_object = b.fabric.get_object(manifest.hash);
+    orch.send_object_manifest(&b, manifest).await;
+    let data = timeout(Duration::from_secs(10), b_object).await.unwrap();
+    assert_eq!(&data[..], &[1; 100][..]);
+}
+
+#[tokio::test]
+async fn reconnect_new_session() {
+    let orch = TestOrchestrator::new();
+
+    let mut a = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let mut b = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    orch.connect_inmem_peers(&mut a, &mut b).await.unwrap();
+
+    let mut a_events = a.fabric.events();
+
+    let a_uuid = a.uuid;
+    orch.forget_sessions();
+    assert!(!orch.reconnect_node(&mut a).await.unwrap());
+    assert!(a.uuid != a_uuid);
+
+    // Peer links from the old session are dropped.
+    assert_eq!(next(&mut a_events).await, FabricEvent::PeerDisconnected {
+        peer_uuid: b.uuid,
+        reason: "session not resumed".to_owned(),
+    });
+    b.expect_peer_disconnected(a_uuid).await.unwrap();
+}
+
+#[tokio::test]
+async fn handshake_failure() {
+    let orch = TestOrchestrator::new();
+
+    let mut a = orch.start_raw_node(FabricBuilder::new(), Box::new(orch.network().manager()));
+    let mut events = a.fabric.events();
+
+    let handshake: proto::ClientHandshake = a.expect().await.unwrap();
+    a.pubkey = handshake.pubkey;
+
+    // Anything but the handshake is rejected until the handshake completes.
+    a.send(proto::ObjectManifest {
+        hash: Hash([0; 32]),
+        tags: vec![],
+        size: 1,
+        fragment_size: 8,
+        fragments: vec![],
+    }).await;
+    let error: proto::ClientError = a.expect().await.unwrap();
+    assert!(!error.fatal);
+
+    a.send(proto::ServerHandshake {
+        client_uuid: orch.gen_uuid(),
+        version: proto::ProtocolVersion::CURRENT,
+        encoding: proto::Encoding::Json,
+        pubkey: orch.pubkey().to_owned(),
+        challenge: proto::Challenge {
+            challenge: [0; 32],
+        },
+        challenge_response: proto::ChallengeResponse {
+            challenge_response: vec![0; 10],
+            signature: vec![],
+        },
+        resumption_token: None,
+    }).await;
+
+    let error: proto::ClientError = a.expect().await.unwrap();
+    assert!(error.fatal);
+    assert_eq!(next(&mut events).await, FabricEvent::HandshakeFailed {
+        error: HandshakeError::InvalidResponseLength {
+            expected: proto::CHALLENGE_RESPONSE_LEN,
+            actual: 10,
+        },
+    });
+
+    // The node recovers when given a new connection.
+    assert!(!orch.reconnect_node(&mut a).await.unwrap());
+}
+
+#[tokio::test]
+async fn handshake_rejected() {
+    let orch = TestOrchestrator::new();
+
+    let mut a = orch.start_raw_node(FabricBuilder::new(), Box::new(orch.network().manager()));
+    let mut events = a.fabric.events();
+
+    let handshake: proto::ClientHandshake = a.expect().await.unwrap();
+    a.pubkey = handshake.pubkey;
+
+    a.send(proto::HandshakeRejected {
+        reason: proto::HandshakeRejectReason::Unavailable,
+        retry_after: Some(5),
+        message: Some("overloaded".to_owned()),
+    }).await;
+
+    assert_eq!(next(&mut events).await, FabricEvent::HandshakeFailed {
+        error: HandshakeError::Rejected {
+            reason: proto::HandshakeRejectReason::Unavailable,
+            message: Some("overloaded".to_owned()),
+            retry_after: Some(Duration::from_secs(5)),
+        },
+    });
+
+    assert!(!orch.reconnect_node(&mut a).await.unwrap());
+}
+
+#[tokio::test]
+async fn version_negotiation() {
+    let orch = TestOrchestrator::new();
+    let a = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    assert_eq!(a.version, proto::ProtocolVersion::CURRENT);
+
+    let mut a = orch.start_raw_node(FabricBuilder::new(), Box::new(orch.network().manager()));
+    let mut events = a.fabric.events();
+
+    let handshake: proto::ClientHandshake = a.expect().await.unwrap();
+    assert_eq!(handshake.version, proto::ProtocolVersion::MIN_SUPPORTED);
+    assert_eq!(handshake.max_version, Some(proto::ProtocolVersion::CURRENT));
+
+    // A version outside the advertised range fails the handshake.
+    let unsupported = proto::ProtocolVersion(proto::ProtocolVersion::CURRENT.0 + 1);
+    a.send(proto::ServerHandshake {
+        client_uuid: orch.gen_uuid(),
+        version: unsupported,
+        encoding: proto::Encoding::Json,
+        pubkey: orch.pubkey().to_owned(),
+        challenge: proto::Challenge {
+            challenge: [0; 32],
+        },
+        challenge_response: proto::ChallengeResponse {
+            challenge_response: vec![],
+            signature: vec![],
+        },
+        resumption_token: None,
+    }).await;
+
+    let error: proto::ClientError = a.expect().await.unwrap();
+    assert!(error.fatal);
+    assert_eq!(next(&mut events).await, FabricEvent::HandshakeFailed {
+        error: HandshakeError::UnsupportedVersion(unsupported),
+    });
+}
+
+#[tokio::test]
+async fn version_downgrade() {
+    let orch = TestOrchestrator::new().with_max_version(proto::ProtocolVersion(0));
+
+    let peer_connector = NativePeerConnectionManagerBuilder::new().build();
+    let mut a = orch.start_node(FabricBuilder::new(), Box::new(peer_connector)).await.unwrap();
+    assert_eq!(a.version, proto::ProtocolVersion(0));
+
+    // `ObjectPublished` does not exist in version 0, and is not sent.
+    a.fabric.publish(b"hello", vec![], FragSize(8)).await.unwrap();
+
+    let peer_uuid = orch.gen_uuid();
+    a.send(proto::ConnectPeer {
+        connector: proto::Connector::WebRTC,
+        peer_uuid,
+        peer_pubkey: vec![],
+        self_nonce: orch.gen_uuid(),
+        peer_nonce: orch.gen_uuid(),
+        encrypted: false,
+    }).await;
+
+    let failed: proto::PeerConnectionFailed = a.expect().await.unwrap();
+    assert_eq!(failed.peer_uuid, peer_uuid);
+}
+
+#[tokio::test]
+async fn encoding_negotiation() {
+    let orch = TestOrchestrator::new();
+
+    let mut a = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let mut b = orch.start_inmem_node(FabricBuilder::new().with_encodings(vec![])).await.unwrap();
+    assert_eq!(a.encoding, proto::Encoding::Cbor);
+    assert_eq!(b.encoding, proto::Encoding::Json);
+
+    orch.connect_inmem_peers(&mut a, &mut b).await.unwrap();
+
+    let manifest = a.fabric.publish(b"hello", vec![], FragSize(8)).await.unwrap();
+    let published: proto::ObjectPublished = a.expect().await.unwrap();
+    assert_eq!(published.manifest.hash, manifest.hash);
+
+    b.send(manifest.clone()).await;
+    assert_eq!(&b.fabric.get_object(manifest.hash).await[..], b"hello");
+
+    // The encoding is negotiated again when reconnecting, here to an
+    // orchestrator that only speaks JSON.
+    let orch_json = TestOrchestrator::new().with_encoding(proto::Encoding::Json);
+    assert!(!orch_json.reconnect_node(&mut a).await.unwrap());
+    assert_eq!(a.encoding, proto::Encoding::Json);
+
+    let manifest = a.fabric.publish(b"again", vec![], FragSize(8)).await.unwrap();
+    let published: proto::ObjectPublished = a.expect().await.unwrap();
+    assert_eq!(published.manifest.hash, manifest.hash);
+}
+
+#[tokio::test]
+async fn stream_manifests() {
+    let orch = TestOrchestrator::new();
+
+    let mut a = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let mut b = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let mut c = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+
+    let stream_uuid = orch.gen_uuid();
+    for node in [&b, &c].iter() {
+        node.send(proto::GrantCapability {
+            pubkey: a.pubkey.clone(),
+            capability: proto::Capability::OriginateStream { stream_uuid },
+        }).await;
+    }
+
+    orch.connect_inmem_peers(&mut a, &mut b).await.unwrap();
+    orch.connect_inmem_peers(&mut b, &mut c).await.unwrap();
+
+    let mut c_events = c.fabric.events();
+
+    let object = ObjectBuilder::new(FragSize(8))
+        .with_stream(stream_uuid)
+        .build(b"stream segment")
+        .unwrap();
+    let hash = object.hash();
+    a.fabric.publish_object(object).await.unwrap();
+
+    // The manifests reach c through b, and the object follows.
+    assert_eq!(&c.fabric.get_object(hash).await[..], b"stream segment");
+    loop {
+        if let FabricEvent::StreamUpdated { stream_uuid: updated, objects } = next(&mut c_events).await {
+            assert_eq!(updated, stream_uuid);
+            assert_eq!(objects, vec![hash]);
+            break;
+        }
+    }
+}
+
+#[tokio::test]
+async fn peer_auth() {
+    let orch = TestOrchestrator::new();
+
+    let mut a = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let mut b = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let c = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+
+    // a is told to expect c's key on the link to b.
+    let a_nonce = orch.gen_uuid();
+    let b_nonce = orch.gen_uuid();
+    a.send(proto::ConnectPeer {
+        connector: proto::Connector::WebsocketServer,
+        peer_uuid: b.uuid,
+        peer_pubkey: c.pubkey.clone(),
+        self_nonce: a_nonce,
+        peer_nonce: b_nonce,
+        encrypted: false,
+    }).await;
+    b.send(proto::ConnectPeer {
+        connector: proto::Connector::WebsocketServer,
+        peer_uuid: a.uuid,
+        peer_pubkey: a.pubkey.clone(),
+        self_nonce: b_nonce,
+        peer_nonce: a_nonce,
+        encrypted: false,
+    }).await;
+
+    let error = a.expect_peer_connected(b.uuid).await.unwrap_err();
+    assert!(error.to_string().contains("does not match peer pubkey"), "{}", error);
+
+    // b may see the link come up before a drops it, but never keeps it.
+    if b.expect_peer_connected(a.uuid).await.is_ok() {
+        b.expect_peer_disconnected(a.uuid).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn encrypted_links() {
+    let orch = TestOrchestrator::new();
+
+    let mut a = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let mut b = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let mut c = orch.start_inmem_node(FabricBuilder::new().with_encrypted_links(false)).await.unwrap();
+    assert!(a.encrypted_links && b.encrypted_links);
+    assert!(!c.encrypted_links);
+
+    // a-b is encrypted, b-c is not as c does not offer it.
+    orch.connect_inmem_peers(&mut a, &mut b).await.unwrap();
+    orch.connect_inmem_peers(&mut b, &mut c).await.unwrap();
+
+    let data: Vec<u8> = (0..5000).map(|n| (n % 251) as u8).collect();
+    let manifest = a.fabric.publish(&data, vec![], FragSize(10)).await.unwrap();
+
+    let c_object = c.fabric.get_object(manifest.hash);
+    orch.send_object_manifest(&b, manifest.clone()).await;
+    orch.send_object_manifest(&c, manifest.clone()).await;
+
+    let c_object = timeout(Duration::from_secs(10), c_object).await.unwrap();
+    assert_eq!(&c_object[..], &data[..]);
+}
+
+#[tokio::test]
+async fn encrypted_link_mismatch() {
+    let orch = TestOrchestrator::new();
+
+    let mut a = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let mut b = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+
+    // Only a is told to encrypt the link.
+    let a_nonce = orch.gen_uuid();
+    let b_nonce = orch.gen_uuid();
+    a.send(proto::ConnectPeer {
+        connector: proto::Connector::WebsocketServer,
+        peer_uuid: b.uuid,
+        peer_pubkey: b.pubkey.clone(),
+        self_nonce: a_nonce,
+        peer_nonce: b_nonce,
+        encrypted: true,
+    }).await;
+    b.send(proto::ConnectPeer {
+        connector: proto::Connector::WebsocketServer,
+        peer_uuid: a.uuid,
+        peer_pubkey: a.pubkey.clone(),
+        self_nonce: b_nonce,
+        peer_nonce: a_nonce,
+        encrypted: false,
+    }).await;
+
+    let error = a.expect_peer_connected(b.uuid).await.unwrap_err();
+    assert!(error.to_string().contains("key agreement"), "{}", error);
+
+    if b.expect_peer_connected(a.uuid).await.is_ok() {
+        b.expect_peer_disconnected(a.uuid).await.unwrap();
+    }
+}
+
+fn heartbeat_builder() -> FabricBuilder {
+    FabricBuilder::new()
+        .with_orchestrator_heartbeat(Duration::from_millis(50), Duration::from_millis(300))
+        .with_peer_heartbeat(Duration::from_millis(50), Duration::from_millis(300))
+}
+
+#[tokio::test]
+async fn orchestrator_heartbeat() {
+    let orch = TestOrchestrator::new();
+
+    let mut a = orch.start_inmem_node(heartbeat_builder()).await.unwrap();
+    let mut events = a.fabric.events();
+
+    let ping = match a.recv_raw().await.unwrap() {
+        proto::OrchClientMsg::Ping(ping) => ping,
+        msg => panic!("unexpected message {:?}", msg),
+    };
+    a.send(proto::Pong { timestamp: ping.timestamp }).await;
+    // The pong and the query are not ordered, so it may take a moment to
+    // show up.
+    timeout(Duration::from_secs(10), async {
+        while a.fabric.round_trip_times().await.orchestrator.is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }).await.unwrap();
+
+    // The node answers our pings.
+    a.send(proto::Ping { timestamp: 42 }).await;
+    loop {
+        match a.recv_raw().await.unwrap() {
+            proto::OrchClientMsg::Pong(pong) => {
+                assert_eq!(pong, proto::Pong { timestamp: 42 });
+                break;
+            },
+            proto::OrchClientMsg::Ping(_) => (),
+            msg => panic!("unexpected message {:?}", msg),
+        }
+    }
+
+    // Without answers, the orchestrator is reported as gone.
+    assert_eq!(next(&mut events).await, FabricEvent::OrchestratorTimeout);
+}
+
+#[tokio::test]
+async fn peer_heartbeat() {
+    let orch = TestOrchestrator::new();
+
+    let mut a = orch.start_inmem_node(heartbeat_builder()).await.unwrap();
+    let mut b = orch.start_inmem_node(heartbeat_builder()).await.unwrap();
+    let link = orch.connect_inmem_peers(&mut a, &mut b).await.unwrap();
+
+    // Round trip times are reported to the orchestrator as they are measured.
+    let rtt = loop {
+        if let proto::OrchClientMsg::PeerRtt(rtt) = a.recv_raw().await.unwrap() {
+            break rtt;
+        }
+    };
+    assert_eq!(rtt.peer_uuid, b.uuid);
+    let rtts = a.fabric.round_trip_times().await;
+    assert!(rtts.peers.contains_key(&b.uuid));
+
+    // A link that silently stops delivering is closed by both sides.
+    assert!(orch.network().stall(link.a_nonce));
+    assert_eq!(a.expect_peer_disconnected(b.uuid).await.unwrap(), "idle timeout");
+    assert_eq!(b.expect_peer_disconnected(a.uuid).await.unwrap(), "idle timeout");
+}
+
+#[tokio::test]
+async fn node_stats() {
+    let orch = TestOrchestrator::new();
+
+    let mut a = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let mut b = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    orch.connect_inmem_peers(&mut a, &mut b).await.unwrap();
+
+    let manifest = a.fabric.publish(&[1; 100], vec![], FragSize(4)).await.unwrap();
+    let _: proto::ObjectPublished = a.expect().await.unwrap();
+
+    let b_object = b.fabric.get_object(manifest.hash);
+    orch.send_object_manifest(&b, manifest.clone()).await;
+    timeout(Duration::from_secs(10), b_object).await.unwrap();
+
+    a.send(proto::ConfigureStats { interval_ms: Some(50) }).await;
+    let stats: proto::NodeStats = a.expect().await.unwrap();
+
+    assert_eq!(stats.complete_objects, 1);
+    assert_eq!(stats.partial_objects, 0);
+    assert!(stats.memory_bytes >= 100);
+    assert_eq!(stats.peers.len(), 1);
+    let peer = &stats.peers[0];
+    assert_eq!(peer.peer_uuid, b.uuid);
+    // At least the fragment data was sent to b.
+    assert!(peer.bytes_out >= 100, "{:?}", peer);
+    assert!(peer.bytes_in > 0, "{:?}", peer);
+
+    // Zero is not a valid interval.
+    a.send(proto::ConfigureStats { interval_ms: Some(0) }).await;
+    loop {
+        match a.recv().await.unwrap() {
+            proto::OrchClientMsg::NodeStats(_) => (),
+            proto::OrchClientMsg::Error(error) => {
+                assert!(!error.fatal);
+                break;
+            },
+            msg => panic!("unexpected message {:?}", msg),
+        }
+    }
+}
+
+#[tokio::test]
+async fn disconnect_peer() {
+    let orch = TestOrchestrator::new();
+
+    let mut a = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let mut b = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    orch.connect_inmem_peers(&mut a, &mut b).await.unwrap();
+
+    a.send(proto::DisconnectPeer { peer_uuid: b.uuid }).await;
+    let reason = a.expect_peer_disconnected(b.uuid).await.unwrap();
+    assert_eq!(reason, "disconnected by orchestrator");
+    // b sees the link close.
+    b.expect_peer_disconnected(a.uuid).await.unwrap();
+
+    // The peers can be connected again.
+    orch.connect_inmem_peers(&mut a, &mut b).await.unwrap();
+}
+
+#[tokio::test]
+async fn expire_object() {
+    let orch = TestOrchestrator::new();
+
+    let mut a = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let mut b = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    orch.connect_inmem_peers(&mut a, &mut b).await.unwrap();
+
+    let data = vec![7; 100];
+    let manifest = a.fabric.publish(&data, vec![], FragSize(4)).await.unwrap();
+    let tags = manifest.tags.clone();
+    orch.send_object_manifest(&b, manifest.clone()).await;
+    timeout(Duration::from_secs(10), b.fabric.get_object(manifest.hash)).await.unwrap();
+
+    let mut b_events = b.fabric.events();
+    b.send(proto::ExpireObject { hash: manifest.hash }).await;
+    assert_eq!(next(&mut b_events).await, FabricEvent::ObjectExpired { hash: manifest.hash });
+
+    // The object is forgotten, so the same manifest is accepted again and
+    // the data fetched from a.
+    let b_object = b.fabric.get_object(manifest.hash);
+    orch.send_object_manifest(&b, manifest.clone()).await;
+    assert_eq!(next(&mut b_events).await, FabricEvent::ObjectManifest { hash: manifest.hash, tags });
+    let b_data = timeout(Duration::from_secs(10), b_object).await.unwrap();
+    assert_eq!(&b_data[..], &data[..]);
+}
+
+#[tokio::test]
+async fn collect_garbage() {
+    let orch = TestOrchestrator::new();
+
+    let mut a = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let mut b = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    orch.connect_inmem_peers(&mut a, &mut b).await.unwrap();
+
+    let manifest = a.fabric.publish(&[5; 100], vec![], FragSize(4)).await.unwrap();
+    let b_object = b.fabric.get_object(manifest.hash);
+    orch.send_object_manifest(&b, manifest.clone()).await;
+    timeout(Duration::from_secs(10), b_object).await.unwrap();
+
+    b.fabric.pin_object(manifest.hash).await.unwrap();
+    assert!(b.fabric.collect_garbage().await.is_empty());
+
+    // A pinned fragment outlives its object.
+    let fragment = manifest.fragments[0].hash;
+    b.fabric.pin_fragment(fragment).await.unwrap();
+    b.fabric.unpin_object(manifest.hash).await.unwrap();
+    assert_eq!(b.fabric.collect_garbage().await, vec![manifest.hash]);
+    assert_eq!(
+        b.fabric.pin_object(manifest.hash).await,
+        Err(DataError::UnknownObject(manifest.hash)),
+    );
+
+    b.fabric.unpin_fragment(fragment).await.unwrap();
+    assert!(b.fabric.collect_garbage().await.is_empty());
+    assert_eq!(
+        b.fabric.pin_fragment(fragment).await,
+        Err(DataError::UnknownFragment(fragment)),
+    );
+}
+
+#[tokio::test]
+async fn memory_budget() {
+    let orch = TestOrchestrator::new();
+
+    let mut a = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let mut b = orch.start_inmem_node(FabricBuilder::new().with_memory_budget(150)).await.unwrap();
+    orch.connect_inmem_peers(&mut a, &mut b).await.unwrap();
+
+    let mut b_events = b.fabric.events();
+
+    let mut hashes = Vec::new();
+    for n in 0..2 {
+        let manifest = a.fabric.publish(&[n; 100], vec![], FragSize(4)).await.unwrap();
+        let b_object = b.fabric.get_object(manifest.hash);
+        orch.send_object_manifest(&b, manifest.clone()).await;
+        timeout(Duration::from_secs(10), b_object).await.unwrap();
+        hashes.push(manifest.hash);
+    }
+
+    // The first object no longer fits, and is reported as evicted.
+    loop {
+        match next(&mut b_events).await {
+            FabricEvent::ObjectEvicted { hash } => {
+                assert_eq!(hash, hashes[0]);
+                break;
+            },
+            FabricEvent::ObjectManifest { .. } | FabricEvent::ObjectComplete { .. } => (),
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+    let evicted: proto::ObjectsEvicted = b.expect().await.unwrap();
+    assert_eq!(evicted.hashes, vec![hashes[0]]);
+
+    // Pinned objects are kept even over budget.
+    b.fabric.pin_object(hashes[1]).await.unwrap();
+    let manifest = a.fabric.publish(&[2; 100], vec![], FragSize(4)).await.unwrap();
+    let b_object = b.fabric.get_object(manifest.hash);
+    orch.send_object_manifest(&b, manifest.clone()).await;
+    timeout(Duration::from_secs(10), b_object).await.unwrap();
+    let evicted: proto::ObjectsEvicted = b.expect().await.unwrap();
+    assert_eq!(evicted.hashes, vec![manifest.hash]);
+}
+
+#[tokio::test]
+async fn fragment_store() {
+    let dir = std::env::temp_dir().join(format!("livecore_fragment_store_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let orch = TestOrchestrator::new();
+
+    let mut a = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let store = FileStore::open(&dir).unwrap();
+    let mut b = orch.start_inmem_node(FabricBuilder::new().with_fragment_store(Box::new(store))).await.unwrap();
+    orch.connect_inmem_peers(&mut a, &mut b).await.unwrap();
+
+    let data: Vec<u8> = (0..1000).map(|n| n as u8).collect();
+    let manifest = a.fabric.publish(&data, vec![], FragSize(8)).await.unwrap();
+    let b_object = b.fabric.get_object(manifest.hash);
+    orch.send_object_manifest(&b, manifest.clone()).await;
+    timeout(Duration::from_secs(10), b_object).await.unwrap();
+
+    // A node started on the same directory, as if b was restarted,
+    // advertises the fragments and has the object as soon as it gets the
+    // manifest, without any peers.
+    let store = FileStore::open(&dir).unwrap();
+    let mut c = orch.start_inmem_node(FabricBuilder::new().with_fragment_store(Box::new(store))).await.unwrap();
+    let stored: proto::StoredFragments = c.expect().await.unwrap();
+    let mut hashes = stored.hashes;
+    hashes.sort();
+    let mut expected: Vec<_> = manifest.fragments.iter().map(|f| f.hash).collect();
+    expected.sort();
+    expected.dedup();
+    assert_eq!(hashes, expected);
+
+    let c_object = c.fabric.get_object(manifest.hash);
+    orch.send_object_manifest(&c, manifest.clone()).await;
+    let c_data = timeout(Duration::from_secs(10), c_object).await.unwrap();
+    assert_eq!(&c_data[..], &data[..]);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn streaming_reads() {
+    let orch = TestOrchestrator::new();
+
+    let mut a = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    let mut b = orch.start_inmem_node(FabricBuilder::new()).await.unwrap();
+    orch.connect_inmem_peers(&mut a, &mut b).await.unwrap();
+
+    let data: Vec<u8> = (0..5000).map(|n| (n % 251) as u8).collect();
+    let manifest = a.fabric.publish(&data, vec![], FragSize(8)).await.unwrap();
+    assert!(matches!(
+        b.fabric.open_object(manifest.hash).await,
+        Err(DataError::UnknownObject(_)),
+    ));
+
+    let mut b_events = b.fabric.events();
+    orch.send_object_manifest(&b, manifest.clone()).await;
+    next(&mut b_events).await;
+
+    let mut reader = b.fabric.open_object(manifest.hash).await.unwrap();
+    let stream = b.fabric.open_object(manifest.hash).await.unwrap().into_stream();
+    assert_eq!(reader.size(), data.len());
+
+    let range = timeout(Duration::from_secs(10), reader.read_range(3000..4100)).await.unwrap().unwrap();
+    assert_eq!(&range[..], &data[3000..4100]);
+
+    let chunks: Vec<_> = timeout(Duration::from_secs(10), stream.collect()).await.unwrap();
+    assert_eq!(&chunks.concat()[..], &data[..]);
+}